@@ -0,0 +1,79 @@
+#!/bin/sh
+# 二つの版のsoilcが生成したsedの大きさと実行時間を比べる (フレームの値の指定方法などの変更の前後で使う)
+# それぞれの版をgit worktreeでビルドし、同じプログラムと入力でsedを繰り返し実行する
+#
+# 使い方: scripts/bench_frame_encoding.sh [変更前の版] [変更後の版] [繰り返す回数]
+# 版はgitのrevisionで指定する 省略した場合はHEAD~1とHEADを比べる
+set -eu
+
+root=$(git rev-parse --show-toplevel)
+before=${1:-HEAD~1}
+after=${2:-HEAD}
+runs=${3:-3}
+
+work=$(mktemp -d)
+cleanup() {
+    for rev in before after; do
+        git -C "$root" worktree remove --force "$work/$rev" >/dev/null 2>&1 || true
+    done
+    rm -rf "$work"
+}
+trap cleanup EXIT
+
+build() {
+    git -C "$root" worktree add --detach --quiet "$work/$1" "$2"
+    cargo build --quiet -p soilc \
+        --manifest-path "$work/$1/Cargo.toml" --target-dir "$work/target-$1"
+}
+build before "$before"
+build after "$after"
+
+# 引数と返り値の多い関数が再帰して、ホールドスペースにフレームが積み重なる
+cat > "$work/deep.soil" <<'EOF'
+fn deep n:bit32, a:bit32, b:bit32, c:bit32, d:bit32, e:bit32, f:bit32, g:bit32 -> bit32 {
+    if n == 0 {
+        return a + (b + (c + (d + (e + (f + g)))));
+    }
+    return deep(n - 1, b, c, d, e, f, g, a) + 1;
+}
+
+pub fn entry n:bit32 -> bit32 {
+    return deep(n, 1, 2, 3, 4, 5, 6, 7);
+}
+EOF
+
+# 名前 ソース 入力 soilcの追加の引数
+cases="basic_operations|$root/sed-compiler/soil/basic_operations.soil|~00000111010110111100110100010101~00111010110111100110100010110001|
+deep|$work/deep.soil|20|--io-format decimal"
+
+elapsed_ms() {
+    start=$(date +%s%N)
+    i=0
+    while [ "$i" -lt "$runs" ]; do
+        printf '%s\n' "$2" | sed -f "$1" >/dev/null
+        i=$((i + 1))
+    done
+    end=$(date +%s%N)
+    echo $(((end - start) / 1000000))
+}
+
+printf '%-18s %-4s %14s %14s %14s %14s\n' program opt "bytes before" "bytes after" "ms before" "ms after"
+echo "$cases" | while IFS='|' read -r name source input flags; do
+    for opt in 0 2; do
+        for rev in before after; do
+            # 警告は表に混ざるので捨てる (エラーの場合はset -eで止まる)
+            # shellcheck disable=SC2086
+            "$work/target-$rev/debug/soilc" -i "$source" -o "$work/$name-$opt-$rev.sed" -O "$opt" $flags 2>/dev/null
+        done
+        out_before=$(printf '%s\n' "$input" | sed -f "$work/$name-$opt-before.sed")
+        out_after=$(printf '%s\n' "$input" | sed -f "$work/$name-$opt-after.sed")
+        if [ "$out_before" != "$out_after" ]; then
+            echo "$name -O $opt: outputs differ ($out_before / $out_after)" >&2
+            exit 1
+        fi
+        printf '%-18s %-4s %14s %14s %14s %14s\n' "$name" "-O $opt" \
+            "$(wc -c < "$work/$name-$opt-before.sed")" "$(wc -c < "$work/$name-$opt-after.sed")" \
+            "$(elapsed_ms "$work/$name-$opt-before.sed" "$input")" \
+            "$(elapsed_ms "$work/$name-$opt-after.sed" "$input")"
+    done
+done
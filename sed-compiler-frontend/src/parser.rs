@@ -1,3 +1,5 @@
+use std::fmt;
+
 use chumsky::{input::ValueInput, prelude::*};
 
 
@@ -33,6 +35,10 @@ pub enum BinaryOp {
     Mod,
     Eq,
     NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
 }
 
 
@@ -48,6 +54,8 @@ pub enum Value<'src> {
     Int32(i32),
     Int64(i64),
     Str(String),
+    /// 正規表現リテラル`/re/`の中身 エスケープはそのまま残す
+    Regex(&'src str),
     Func(&'src str),
 }
 
@@ -60,6 +68,10 @@ pub struct Arg<'src>{
 #[derive(Clone, Debug, PartialEq)]
 pub struct Func<'src> {
     pub public: bool,
+    /// `#[inline]`が付いているかどうか
+    pub inline: bool,
+    /// `const fn`かどうか
+    pub constant: bool,
     pub name: &'src str,
     pub args: Vec<(Arg<'src>, Span)>,
     pub rtype: Vec<(&'src str, Span)>,
@@ -91,6 +103,55 @@ pub enum Token<'src>{
     Ctrl(char),
     Ident(&'src str),
     Str(String),
+    Regex(&'src str),
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// エラーメッセージに表示するためのソース上の表記
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Pub => write!(f, "pub"),
+            Token::Fn => write!(f, "fn"),
+            Token::Let => write!(f, "let"),
+            Token::If => write!(f, "if"),
+            Token::Else => write!(f, "else"),
+            Token::Sed => write!(f, "sed"),
+            Token::Return => write!(f, "return"),
+            Token::Arrow => write!(f, "->"),
+            Token::Colon => write!(f, ":"),
+            Token::SemiColon => write!(f, ";"),
+            Token::Comma => write!(f, ","),
+            Token::Assign => write!(f, "="),
+            Token::MbOpen => write!(f, "${{"),
+            Token::MbClose => write!(f, "}}$"),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::Bool(b) => write!(f, "{}", b),
+            Token::I32(i) => write!(f, "{}", i),
+            Token::Ctrl(c) => write!(f, "{}", c),
+            Token::Ident(ident) => write!(f, "{}", ident),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Regex(re) => write!(f, "/{}/", re),
+        }
+    }
 }
 
 fn lexer<'src>() 
@@ -111,9 +172,7 @@ fn lexer<'src>()
         .or(escape)
         .repeated()
         .collect::<String>()
-        .map(|tok|
-           Token::Str(tok)
-        );
+        .map(Token::Str);
 
     let string = 
         string_contents
@@ -137,8 +196,20 @@ fn lexer<'src>()
         .to_slice()
         .map(|op| if op == "==" { Token::Op(BinaryOp::Eq)} else {Token::Assign});
 
+    let less_or_greater = 
+        one_of("<>")
+        .then(just('=').or_not())
+        .to_slice()
+        .map(|op| match op {
+            "<" => Token::Op(BinaryOp::Lt),
+            "<=" => Token::Op(BinaryOp::LtEq),
+            ">" => Token::Op(BinaryOp::Gt),
+            _ => Token::Op(BinaryOp::GtEq),
+        });
+
     let op = 
         choice((
+            less_or_greater,
             just('+').to(Token::Op(BinaryOp::Add)),
             just('*').to(Token::Op(BinaryOp::Mul)),
             just('/').to(Token::Op(BinaryOp::Div)),
@@ -163,7 +234,7 @@ fn lexer<'src>()
         choice((
                 brace_open,
                 brace_close,
-                one_of("[]()").map(Token::Ctrl),
+                one_of("[]()#").map(Token::Ctrl),
         ));
 
     let ident = 
@@ -195,17 +266,42 @@ fn lexer<'src>()
         )
         .or(ident);
 
+    // 正規表現リテラルは除算が現れない`(`と`,`の直後でだけ認識する
+    let regex_body = 
+        none_of("/\\\n")
+        .or(just('\\').then(none_of("\n")).to('\\'))
+        .repeated()
+        .at_least(1)
+        .to_slice();
+
+    let regex = 
+        choice((
+            just('(').to(Token::Ctrl('(')),
+            just(',').to(Token::Comma),
+        ))
+        .then(text::whitespace().to_slice())
+        .then(regex_body.delimited_by(just('/'), just('/')))
+        .map_with(|((tok, ws), re): ((Token<'src>, &'src str), &'src str), e| {
+            let span: Span = e.span();
+            let start = span.start + 1 + ws.len();
+            vec![
+                (tok, Span::from(span.start..span.start + 1)),
+                (Token::Regex(re), Span::from(start..span.end)),
+            ]
+        });
+
     let comment = just("//")
         .then(any().and_is(just('\n').not()).repeated())
         .padded();
 
-    token
-        .map_with(|tok, e| (tok, e.span()))
+    regex
+        .or(token.map_with(|tok, e| vec![(tok, e.span())]))
         .padded_by(comment.repeated())
         .padded()
         .recover_with(skip_then_retry_until(any().ignored(), end()))
         .repeated()
-        .collect()
+        .collect::<Vec<_>>()
+        .map(|toks| toks.into_iter().flatten().collect())
 }
 
 fn args_parser<'tokens, 'src: 'tokens, I>() 
@@ -213,7 +309,7 @@ fn args_parser<'tokens, 'src: 'tokens, I>()
     where I: ValueInput<'tokens, Token = Token<'src>, Span = Span>
 {
     let ident = select!{ Token::Ident(ident) => ident };
-    let arg = group(
+    group(
         (
             ident,
             just(Token::Colon).labelled("Colon"),
@@ -228,8 +324,7 @@ fn args_parser<'tokens, 'src: 'tokens, I>()
             .labelled("Comma")
             .map_with(|tok, e| (tok, e.span())))
         .allow_trailing()
-        .collect::<Vec<_>>();
-    arg
+        .collect::<Vec<_>>()
 }
 
 pub fn func_parser<'tokens, 'src: 'tokens, I>() 
@@ -249,9 +344,19 @@ pub fn func_parser<'tokens, 'src: 'tokens, I>()
                 .collect::<Vec<_>>()
             );
 
-    let func_def = 
-        just(Token::Pub)
+    let inline = 
+        just(Token::Ctrl('#'))
+        .ignore_then(
+            just(Token::Ident("inline"))
+            .delimited_by(just(Token::Ctrl('[')), just(Token::Ctrl(']')))
+        )
         .or_not()
+        .map(|attr| attr.is_some());
+
+    let func_def = 
+        inline
+        .then(just(Token::Pub).or_not())
+        .then(just(Token::Ident("const")).or_not())
         .then(fn_header)
         .then(
             decl_parser()
@@ -267,12 +372,10 @@ pub fn func_parser<'tokens, 'src: 'tokens, I>()
             )))
         )
         .map_with(
-            |((public, (((_, name), args), rtype)), body), e|{
-            let public = match public {
-                Some(_) => true,
-                None => false, 
-            };
-            (Func { public, name, args, rtype, body }, e.span())
+            |((((inline, public), constant), (((_, name), args), rtype)), body), e|{
+            let public = public.is_some();
+            let constant = constant.is_some();
+            (Func { public, inline, constant, name, args, rtype, body }, e.span())
         }).labelled("function");
 
     func_def
@@ -286,6 +389,8 @@ fn expr_parser<'tokens, 'src: 'tokens, I>()
 {
     recursive(|expr|{
         let int = select! { Token::I32(i) => Expr::Value(Value::Int32(i))};
+        let string = select! { Token::Str(s) => Expr::Value(Value::Str(s))};
+        let regex = select! { Token::Regex(re) => Expr::Value(Value::Regex(re))};
         let ident = select! { Token::Ident(i) => Expr::Local(i) };
 
         let items = expr
@@ -296,6 +401,8 @@ fn expr_parser<'tokens, 'src: 'tokens, I>()
 
         let atom = 
             int.map_with(|tok, e| (tok, e.span()))
+            .or(string.map_with(|tok, e| (tok, e.span())))
+            .or(regex.map_with(|tok, e| (tok, e.span())))
             .or(
                 expr.delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
             )
@@ -314,15 +421,18 @@ fn expr_parser<'tokens, 'src: 'tokens, I>()
 
         let unary = 
             just(Token::Op(BinaryOp::Sub))
+            .map_with(|_, e| e.span())
             .repeated()
-            .foldr(call, |_op, rhs|{
-                rhs
+            .foldr(call, |op_span: Span, rhs|{
+                let span = Span::from(op_span.start..rhs.1.end);
+                (Expr::Neg(Box::new(rhs)), span)
             });
 
-        // Product ops (multiply and divide) have equal precedence
+        // Product ops (multiply, divide and modulo) have equal precedence
         let op = 
             just(Token::Op(BinaryOp::Mul)).to(BinaryOp::Mul)
-            .or(just(Token::Op(BinaryOp::Div)).to(BinaryOp::Div));
+            .or(just(Token::Op(BinaryOp::Div)).to(BinaryOp::Div))
+            .or(just(Token::Op(BinaryOp::Mod)).to(BinaryOp::Mod));
 
         let product = unary
             .clone()
@@ -341,7 +451,11 @@ fn expr_parser<'tokens, 'src: 'tokens, I>()
 
         let op = 
             just(Token::Op(BinaryOp::Eq)).to(BinaryOp::Eq)
-            .or(just(Token::Op(BinaryOp::NotEq)).to(BinaryOp::NotEq));
+            .or(just(Token::Op(BinaryOp::NotEq)).to(BinaryOp::NotEq))
+            .or(just(Token::Op(BinaryOp::Lt)).to(BinaryOp::Lt))
+            .or(just(Token::Op(BinaryOp::LtEq)).to(BinaryOp::LtEq))
+            .or(just(Token::Op(BinaryOp::Gt)).to(BinaryOp::Gt))
+            .or(just(Token::Op(BinaryOp::GtEq)).to(BinaryOp::GtEq));
         let compare = sum
             .clone()
             .foldl_with(op.then(sum).repeated(), |a, (op, b), e| {
@@ -380,11 +494,12 @@ fn decl_parser<'tokens, 'src: 'tokens, I>()
             .then_ignore(just(Token::Assign))
             .then(expr_parser())
             .then_ignore(just(Token::SemiColon))
+            .map_with(|(name, rhs), e| (Expr::Let(name, Box::new(rhs)), e.span()))
             .then(decl.clone())
-            .map_with(|((name, rhs), then), e| {
+            .map_with(|(r#let, then), e| {
                 (
                     Expr::Then(
-                        Box::new((Expr::Let(name , Box::new(rhs)), e.span())),
+                        Box::new(r#let),
                         Box::new(then)
                     ), e.span()
                 )
@@ -466,7 +581,9 @@ fn decl_parser<'tokens, 'src: 'tokens, I>()
     })
 }
 
-pub fn lexer_parse(input: &str) -> (Option<Vec<(Token<'_>, SimpleSpan)>>, Vec<Rich<'_, char>>){
+pub type LexResult<'src> = (Option<Vec<Spanned<Token<'src>>>>, Vec<Rich<'src, char>>);
+
+pub fn lexer_parse(input: &str) -> LexResult<'_> {
     lexer().parse(input).into_output_errors()
 }
 
@@ -88,6 +88,7 @@ pub fn mul a:bit32, b:bit32 -> bit32 {
 
     }
 
+    #[allow(dead_code)]
     fn test00_bbb() {
         let input = r#"
         "hello world"
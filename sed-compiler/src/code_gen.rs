@@ -1,16 +1,88 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 
+use crate::const_fold::fold_const_calls;
+use crate::diagnostic::{Diagnostic, Severity, SourceSpan};
+use crate::embedded::BuiltinRegistry;
+use crate::inline::inline_funcs;
+use crate::ir_text::{IrParseErr, parse_funcs, print_funcs};
+use crate::object::{ObjectFile, unresolved_calls};
+use crate::peephole::optimize;
+use crate::sed_label::namespace_labels;
+use crate::strings::escape_str;
+use crate::tail_call::lower_tail_calls;
+use crate::verifier::{VerifyErr, verify_func_table};
+
 // compiler state
 pub struct Unassembled;
 pub struct Assembled;
 pub struct Linked;
 
+/// 生成するものの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrateType {
+    /// entryから実行するプログラム
+    /// entryからもpub関数からも到達できない非pub関数は取り除かれる
+    Binary,
+    /// 他のコンパイル単位から使われる関数の集まり
+    /// pub関数だけが公開される
+    Library,
+}
+
+/// 生成するsedスクリプトの入出力の形式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoFormat {
+    /// 引数は`~`で区切った2進数、返り値は`~`で区切り`;`で終わる2進数
+    #[default]
+    Binary,
+    /// 引数も返り値も空白で区切った10進数
+    /// 引数は32bitに変換され、bool型の返り値は0か1になる
+    Decimal,
+}
+
+/// 生成するsedスクリプトでの数の表現
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberRepr {
+    /// 2進数の文字列 (`~101`)
+    #[default]
+    Binary,
+    /// 先頭に余分な0のない10進数の文字列 (`~5`)
+    /// 演算子は10進数のpreludeの関数を呼び出す
+    /// 2進数を前提とするsed関数はそのままでは使えない
+    Decimal,
+}
+
+/// 10進数の引数を2進数に変換するsed
+const DECIMAL_INPUT_SED: &str = include_str!("../sed/decimal_input.sed");
+/// 2進数の返り値を10進数に変換するsed
+const DECIMAL_OUTPUT_SED: &str = include_str!("../sed/decimal_output.sed");
+
 pub struct CompilerBuilder<State> {
     func_table: Vec<FuncDef>,
+    /// プログラムの開始地点となる関数の名前
+    entry: String,
+    crate_type: CrateType,
+    /// 呼び出されているが定義されていない関数を探す表
+    /// Noneの場合はBuiltinRegistry::standard
+    builtins: Option<BuiltinRegistry>,
+    io_format: IoFormat,
+    number_repr: NumberRepr,
+    /// assembleで使ったラベルの番号の数 リンク時に単位ごとにずらすために使う
     consumed_table: ConsumedTable,
+    /// 開始地点から到達できないため取り除いた関数の名前
+    removed_funcs: Vec<String>,
+    /// 組み込み関数の表からリンクした関数の名前 リンク時に他の単位の同じ関数とまとめる
+    builtin_funcs: Vec<String>,
+    /// assemble時に小さな関数をインライン展開するかどうか
+    inline: bool,
+    /// assemble時に末尾呼び出しを最適化するかどうか
+    tail_calls: bool,
+    /// assemble時に定数の引数による呼び出しを畳み込むかどうか
+    const_fold: bool,
+    /// 生成したsedをピープホール最適化するかどうか
+    peephole: bool,
     _state: PhantomData<State>,
 }
 
@@ -24,10 +96,21 @@ impl CompilerBuilder<Unassembled> {
     pub fn new() -> Self {
         Self {
             func_table: Vec::new(),
+            entry: "entry".to_string(),
+            crate_type: CrateType::Binary,
+            builtins: None,
+            io_format: IoFormat::Binary,
+            number_repr: NumberRepr::Binary,
             consumed_table: ConsumedTable {
                 func_label_id: 0,
                 if_id: 0,
             },
+            removed_funcs: Vec::new(),
+            builtin_funcs: Vec::new(),
+            inline: true,
+            tail_calls: true,
+            const_fold: true,
+            peephole: true,
             _state: PhantomData,
         }
     }
@@ -38,18 +121,118 @@ impl CompilerBuilder<Unassembled> {
         self
     }
 
+    /// テキスト形式のIRに書かれた関数をすべて追加する
+    pub fn add_ir(mut self, text: &str) -> Result<Self, IrParseErr> {
+        self.func_table.extend(parse_funcs(text)?);
+        Ok(self)
+    }
+
+    /// プログラムの開始地点となる関数を指定する
+    /// 指定しない場合は`entry`
+    pub fn entry(mut self, name: &str) -> Self {
+        self.entry = name.to_string();
+        self
+    }
+
+    /// 生成するものの種類を指定する
+    /// 指定しない場合はBinary
+    pub fn crate_type(mut self, crate_type: CrateType) -> Self {
+        self.crate_type = crate_type;
+        self
+    }
+
+    /// 組み込み関数の表を指定する
+    /// 指定しない場合はBuiltinRegistry::standard
+    pub fn builtins(mut self, registry: BuiltinRegistry) -> Self {
+        self.builtins = Some(registry);
+        self
+    }
+
+    /// 入出力の形式を指定する
+    /// 指定しない場合はBinary
+    pub fn io_format(mut self, io_format: IoFormat) -> Self {
+        self.io_format = io_format;
+        self
+    }
+
+    /// 数の表現を指定する
+    /// 指定しない場合はBinary
+    /// 組み込み関数の表を指定しない場合は表現に合わせたpreludeが使われる
+    pub fn number_repr(mut self, number_repr: NumberRepr) -> Self {
+        self.number_repr = number_repr;
+        self
+    }
+
+    /// 小さな関数と`#[inline]`の付いた関数の呼び出しを展開するかどうかを指定する
+    /// 指定しない場合は展開する
+    pub fn inline(mut self, inline: bool) -> Self {
+        self.inline = inline;
+        self
+    }
+
+    /// 末尾呼び出しを最適化するかどうかを指定する
+    /// 指定しない場合は最適化する
+    pub fn tail_calls(mut self, tail_calls: bool) -> Self {
+        self.tail_calls = tail_calls;
+        self
+    }
+
+    /// 引数がすべて定数の呼び出しをコンパイル時に評価するかどうかを指定する
+    /// 指定しない場合は評価する
+    pub fn const_fold(mut self, const_fold: bool) -> Self {
+        self.const_fold = const_fold;
+        self
+    }
+
     /// ID割り当て、オフセット計算、ラベル解決などを行う
     /// 状態を Assembled に遷移させる
     pub fn assemble(mut self) -> CompilerBuilder<Assembled> {
+        // 演算子などの組み込み関数をリンクする
+        let number_repr = self.number_repr;
+        let builtins = self
+            .builtins
+            .take()
+            .unwrap_or_else(|| BuiltinRegistry::for_number_repr(number_repr));
+        let linked = builtins.link(&mut self.func_table);
+        if self.const_fold {
+            fold_const_calls(&mut self.func_table, &linked, number_repr);
+        }
+        if self.inline {
+            inline_funcs(&mut self.func_table);
+        }
+        if self.tail_calls {
+            lower_tail_calls(&mut self.func_table);
+        }
+        // Libraryの関数は他の単位から呼ばれうるので、リンク時に取り除く
+        let removed_funcs = match self.crate_type {
+            CrateType::Binary => remove_unreachable_funcs(&mut self.func_table, &self.entry),
+            CrateType::Library => Vec::new(),
+        };
         // entry pointをリストの先頭に配置する
-        if let Some(elem) = self.func_table.pop_if(|a| a.name == "entry") {
+        if let Some(index) = self.func_table.iter().position(|a| a.name == self.entry) {
+            let elem = self.func_table.remove(index);
             self.func_table.insert(0, elem);
         }
+        let builtin_funcs = linked
+            .into_iter()
+            .filter(|name| self.func_table.iter().any(|f| f.name == *name))
+            .collect();
         // ID割り当て、オフセット計算、ラベル解決など
         let consumed = assemble_funcs(&mut self.func_table);
         CompilerBuilder {
             func_table: self.func_table,
+            entry: self.entry,
+            crate_type: self.crate_type,
+            builtins: None,
+            io_format: self.io_format,
+            number_repr: self.number_repr,
             consumed_table: consumed,
+            removed_funcs,
+            builtin_funcs,
+            inline: self.inline,
+            tail_calls: self.tail_calls,
+            const_fold: self.const_fold,
+            peephole: self.peephole,
             _state: PhantomData,
         }
     }
@@ -59,25 +242,462 @@ impl CompilerBuilder<Unassembled> {
 impl CompilerBuilder<Assembled> {
     /// sedコードを生成する
     pub fn generate(self) -> Result<String, CompileErr> {
-        sedgen_func_table(&self.func_table)
+        generate_program(
+            self.func_table,
+            &self.entry,
+            self.io_format,
+            self.number_repr,
+            self.peephole,
+        )
     }
 
-    /// TODO: debug用関数　後で消す
+    /// 他のコンパイル単位から呼び出せる関数の名前
+    /// Libraryの場合はpub関数、Binaryの場合は何も公開しない
+    pub fn exports(&self) -> Vec<&str> {
+        match self.crate_type {
+            CrateType::Binary => vec![],
+            CrateType::Library => self
+                .func_table
+                .iter()
+                .filter(|f| f.public)
+                .map(|f| f.name.as_str())
+                .collect(),
+        }
+    }
+
+    /// 開始地点から到達できないため取り除いた関数の名前
+    pub fn removed_funcs(&self) -> &[String] {
+        &self.removed_funcs
+    }
+
+    /// 取り除いた関数と解決済みのIRをテキスト形式で表示する
     pub fn resolved_show_table(&self) {
-        println!("{:#?}", self.func_table);
+        print!("{}", show_removed_funcs(&self.removed_funcs));
+        print!("{}", self.dump_ir());
+    }
+
+    /// 分割コンパイルのためのオブジェクトファイルにする
+    pub fn to_object(&self) -> ObjectFile {
+        ObjectFile {
+            entry: self.entry.clone(),
+            crate_type: self.crate_type,
+            io_format: self.io_format,
+            number_repr: self.number_repr,
+            exports: self.exports().iter().map(|name| name.to_string()).collect(),
+            builtins: self.builtin_funcs.clone(),
+            externs: unresolved_calls(&self.func_table),
+            funcs: self.func_table.clone(),
+        }
+    }
+
+    /// オブジェクトファイルから読み込む
+    /// 関数の順序は保存されているので、ラベルはassemble時と同じ番号になる
+    pub fn from_object(object: ObjectFile) -> Self {
+        let mut func_table = object.funcs;
+        let consumed = assemble_funcs(&mut func_table);
+        CompilerBuilder {
+            func_table,
+            entry: object.entry,
+            crate_type: object.crate_type,
+            builtins: None,
+            io_format: object.io_format,
+            number_repr: object.number_repr,
+            consumed_table: consumed,
+            removed_funcs: Vec::new(),
+            builtin_funcs: object.builtins,
+            inline: false,
+            tail_calls: false,
+            const_fold: false,
+            peephole: true,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<State> CompilerBuilder<State> {
+    /// 関数の表をテキスト形式のIRにする
+    /// Unassembledでは組み込み関数はまだリンクされていない
+    pub fn dump_ir(&self) -> String {
+        print_funcs(&self.func_table)
+    }
+
+    /// 生成したsedの冗長なコマンドを取り除くかどうかを指定する
+    /// 指定しない場合は取り除く
+    pub fn peephole(mut self, peephole: bool) -> Self {
+        self.peephole = peephole;
+        self
+    }
+}
+
+/// リンクの失敗 コンパイル単位はlink_compile_builderに渡した順に0から数える
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkErr {
+    /// リンクするコンパイル単位がない
+    NoUnits,
+    /// どのコンパイル単位も開始地点の関数を定義していない
+    MissingEntry(String),
+    /// 複数のコンパイル単位が開始地点の関数を定義している
+    MultipleEntries { name: String, units: Vec<usize> },
+    /// 同じ名前で内容の異なる関数が複数のコンパイル単位にある
+    DuplicateSymbol {
+        name: String,
+        first: usize,
+        second: usize,
+    },
+    /// 呼び出している関数がどのコンパイル単位にもない
+    UndefinedSymbol { name: String, unit: usize },
+    /// 呼び出している関数を定義しているコンパイル単位が公開していない
+    PrivateSymbol {
+        name: String,
+        unit: usize,
+        defined_in: usize,
+    },
+    /// 数の表現が最初のコンパイル単位と異なる
+    MismatchedNumberRepr { unit: usize },
+}
+
+impl std::fmt::Display for LinkErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkErr::NoUnits => write!(f, "no units to link"),
+            LinkErr::MissingEntry(name) => write!(f, "entry point `{}` is not defined", name),
+            LinkErr::MultipleEntries { name, units } => write!(
+                f,
+                "entry point `{}` is defined in multiple units {:?}",
+                name, units
+            ),
+            LinkErr::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "function `{}` is defined differently in units {} and {}",
+                name, first, second
+            ),
+            LinkErr::UndefinedSymbol { name, unit } => {
+                write!(f, "undefined function `{}` called from unit {}", name, unit)
+            }
+            LinkErr::PrivateSymbol {
+                name,
+                unit,
+                defined_in,
+            } => write!(
+                f,
+                "function `{}` called from unit {} is not exported by unit {}",
+                name, unit, defined_in
+            ),
+            LinkErr::MismatchedNumberRepr { unit } => write!(
+                f,
+                "unit {} uses a different number representation from unit 0",
+                unit
+            ),
+        }
+    }
+}
+
+impl LinkErr {
+    /// ソース上の位置を持たない診断に変換する
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::without_span(Severity::Error, "10", &self.to_string())
     }
 }
 
-// entryが一つ
-//
-// fn link_compile_builder(compiler_builder_list: &[CompilerBuilder<Assembled>]) -> CompilerBuilder<Linked>{
-// }
-//
-// impl CompilerBuilder<Linked> {
-// }
+/// assemble済みの複数のコンパイル単位を一つのプログラムにする
+/// - 開始地点の関数の名前は最初のコンパイル単位のもので、ちょうど一つの単位が定義している必要がある
+/// - 他の単位の関数はその単位が公開しているもの(Libraryのpub関数)だけを呼び出せる
+/// - 公開している関数と組み込み関数は、同じ名前で内容が同じ場合に限り一つにまとめる
+/// - 公開していない関数は、他の単位に同じ名前の関数があれば単位ごとの名前にする
+/// - 開始地点から到達できない関数は取り除く
+/// - ラベルはConsumedTableをもとに単位ごとにずらして重複しないようにする
+pub fn link_compile_builder(
+    units: &[CompilerBuilder<Assembled>],
+) -> Result<CompilerBuilder<Linked>, Vec<LinkErr>> {
+    let Some(first) = units.first() else {
+        return Err(vec![LinkErr::NoUnits]);
+    };
+    let entry = first.entry.clone();
+    let mut errs = Vec::new();
+    for (unit, builder) in units.iter().enumerate() {
+        if builder.number_repr != first.number_repr {
+            errs.push(LinkErr::MismatchedNumberRepr { unit });
+        }
+    }
+
+    let tables = rename_private_funcs(units, &entry);
+
+    // 関数の名前から、定義しているコンパイル単位を引く
+    let mut defined: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (unit, table) in tables.iter().enumerate() {
+        for func_def in table {
+            defined.entry(func_def.name()).or_default().push(unit);
+        }
+    }
+    let find = |unit: usize, name: &str| {
+        tables[unit]
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| print_funcs(std::slice::from_ref(f)))
+    };
+    for (name, definers) in &defined {
+        if *name == entry {
+            if 1 < definers.len() {
+                errs.push(LinkErr::MultipleEntries {
+                    name: entry.clone(),
+                    units: definers.clone(),
+                });
+            }
+            continue;
+        }
+        let first_def = find(definers[0], name);
+        if let Some(&second) = definers[1..]
+            .iter()
+            .find(|&&unit| find(unit, name) != first_def)
+        {
+            errs.push(LinkErr::DuplicateSymbol {
+                name: name.to_string(),
+                first: definers[0],
+                second,
+            });
+        }
+    }
+    let Some(entry_unit) = defined.get(entry.as_str()).map(|definers| definers[0]) else {
+        errs.push(LinkErr::MissingEntry(entry));
+        return Err(errs);
+    };
+
+    for (unit, table) in tables.iter().enumerate() {
+        let mut called = Vec::new();
+        for func_def in table {
+            collect_called_funcs(&func_def.proc_contents, &mut called);
+        }
+        called.sort();
+        called.dedup();
+        for name in called {
+            let definers = defined.get(name).map(Vec::as_slice).unwrap_or_default();
+            if definers.contains(&unit) {
+                continue;
+            }
+            let Some(&defined_in) = definers.first() else {
+                errs.push(LinkErr::UndefinedSymbol {
+                    name: name.to_string(),
+                    unit,
+                });
+                continue;
+            };
+            if !definers
+                .iter()
+                .any(|&other| units[other].exports().contains(&name))
+            {
+                errs.push(LinkErr::PrivateSymbol {
+                    name: name.to_string(),
+                    unit,
+                    defined_in,
+                });
+            }
+        }
+    }
+    if !errs.is_empty() {
+        return Err(errs);
+    }
+
+    let mut func_table: Vec<FuncDef> = Vec::new();
+    let mut consumed = ConsumedTable {
+        func_label_id: 0,
+        if_id: 0,
+    };
+    let mut func_id = 0;
+    for (builder, table) in units.iter().zip(&tables) {
+        for func_def in table {
+            if func_table.iter().any(|f| f.name == func_def.name) {
+                continue;
+            }
+            let mut func_def = func_def.clone();
+            func_def.id += func_id;
+            func_def.return_addr_offset.incr(consumed.func_label_id);
+            shift_labels(
+                &mut func_def.proc_contents,
+                consumed.func_label_id,
+                consumed.if_id,
+            );
+            func_table.push(func_def);
+        }
+        func_id += builder.func_table.len();
+        consumed.func_label_id += builder.consumed_table.func_label_id;
+        consumed.if_id += builder.consumed_table.if_id;
+    }
+    // ライブラリのpub関数も開始地点から到達できなければ取り除く
+    let removed_funcs = retain_reachable(&mut func_table, std::slice::from_ref(&entry));
+    if let Some(index) = func_table.iter().position(|f| f.name == entry) {
+        let elem = func_table.remove(index);
+        func_table.insert(0, elem);
+    }
+    Ok(CompilerBuilder {
+        func_table,
+        entry,
+        crate_type: CrateType::Binary,
+        builtins: None,
+        io_format: units[entry_unit].io_format,
+        number_repr: first.number_repr,
+        consumed_table: consumed,
+        removed_funcs,
+        builtin_funcs: Vec::new(),
+        inline: false,
+        tail_calls: false,
+        const_fold: false,
+        peephole: units.iter().all(|unit| unit.peephole),
+        _state: PhantomData,
+    })
+}
+
+/// 単位ごとの関数の表を、公開していない関数の名前を単位ごとに変えて複製する
+/// - 他の単位にも同じ名前の関数がある場合だけ`name__u{単位}`にし、単位の中の呼び出しも書き換える
+/// - 公開している関数、組み込み関数と開始地点の関数はそのままにする
+/// - 名前を変えた関数を呼び出す組み込み関数は他の単位のものと内容が異なるので、同じように名前を変える
+fn rename_private_funcs(units: &[CompilerBuilder<Assembled>], entry: &str) -> Vec<Vec<FuncDef>> {
+    let mut definers: BTreeMap<&str, usize> = BTreeMap::new();
+    for builder in units {
+        for func_def in &builder.func_table {
+            *definers.entry(func_def.name()).or_default() += 1;
+        }
+    }
+    units
+        .iter()
+        .enumerate()
+        .map(|(unit, builder)| {
+            let exports = builder.exports();
+            let shared = |name: &str| name == entry || exports.contains(&name);
+            let mut renamed: BTreeMap<String, String> = BTreeMap::new();
+            loop {
+                let before = renamed.len();
+                for func_def in &builder.func_table {
+                    let name = func_def.name();
+                    if shared(name) || definers[name] < 2 || renamed.contains_key(name) {
+                        continue;
+                    }
+                    let private = if builder.builtin_funcs.iter().any(|b| b == name) {
+                        let mut called = Vec::new();
+                        collect_called_funcs(&func_def.proc_contents, &mut called);
+                        called.iter().any(|callee| renamed.contains_key(*callee))
+                    } else {
+                        true
+                    };
+                    if private {
+                        renamed.insert(name.to_string(), format!("{}__u{}", name, unit));
+                    }
+                }
+                if renamed.len() == before {
+                    break;
+                }
+            }
+            let mut table = builder.func_table.clone();
+            for func_def in &mut table {
+                if let Some(name) = renamed.get(&func_def.name) {
+                    func_def.name = name.clone();
+                }
+                rename_calls(&mut func_def.proc_contents, &renamed);
+            }
+            table
+        })
+        .collect()
+}
+
+/// 命令列の呼び出し先の名前を書き換える
+fn rename_calls(proc_contents: &mut [SedInstruction], renamed: &BTreeMap<String, String>) {
+    for instruction in proc_contents {
+        match instruction {
+            SedInstruction::Call(call_func) | SedInstruction::TailCall(call_func) => {
+                if let Some(name) = renamed.get(&call_func.func_name) {
+                    call_func.func_name = name.clone();
+                }
+            }
+            SedInstruction::IfProc(if_proc) => {
+                rename_calls(&mut if_proc.then_proc, renamed);
+                rename_calls(&mut if_proc.else_proc, renamed);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl CompilerBuilder<Linked> {
+    /// sedコードを生成する
+    pub fn generate(self) -> Result<String, CompileErr> {
+        generate_program(
+            self.func_table,
+            &self.entry,
+            self.io_format,
+            self.number_repr,
+            self.peephole,
+        )
+    }
+
+    /// 開始地点から到達できないため取り除いた関数の名前
+    pub fn removed_funcs(&self) -> &[String] {
+        &self.removed_funcs
+    }
+
+    /// 取り除いた関数とリンク済みのIRをテキスト形式で表示する
+    pub fn resolved_show_table(&self) {
+        print!("{}", show_removed_funcs(&self.removed_funcs));
+        print!("{}", self.dump_ir());
+    }
+}
+
+/// 取り除いた関数をIRのコメントとして並べる
+fn show_removed_funcs(removed_funcs: &[String]) -> String {
+    removed_funcs
+        .iter()
+        .map(|name| format!("// removed unreachable function `{}`\n", name))
+        .collect()
+}
+
+/// 戻りアドレスとifのラベルをずらす
+fn shift_labels(proc_contents: &mut [SedInstruction], ret_offset: usize, if_offset: usize) {
+    for instruction in proc_contents {
+        match instruction {
+            SedInstruction::Call(call_func) => call_func.return_addr_marker.incr(ret_offset),
+            SedInstruction::IfProc(if_proc) => {
+                if_proc.id += if_offset;
+                shift_labels(&mut if_proc.then_proc, ret_offset, if_offset);
+                shift_labels(&mut if_proc.else_proc, ret_offset, if_offset);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 解決済みの関数の表からsedコードを生成する
+fn generate_program(
+    mut func_table: Vec<FuncDef>,
+    entry: &str,
+    io_format: IoFormat,
+    number_repr: NumberRepr,
+    peephole: bool,
+) -> Result<String, CompileErr> {
+    validate_entry(&func_table, entry)?;
+    verify_func_table(&func_table).map_err(CompileErr::InvalidStack)?;
+    if number_repr == NumberRepr::Decimal {
+        for func_def in &mut func_table {
+            decimal_constants(&mut func_def.proc_contents);
+        }
+    }
+    // 変換処理で開始地点の関数を挟む
+    let mut rstr = sedgen_prologue(io_format, number_repr);
+    rstr.push_str(&sedgen_func_table(&func_table, entry)?);
+    let epilogue = sedgen_epilogue(io_format, number_repr);
+    if !epilogue.is_empty() {
+        rstr.push('\n');
+        rstr.push_str(&epilogue);
+    }
+    if peephole {
+        rstr = optimize(&rstr);
+    }
+    Ok(rstr)
+}
 
 /// この関数を使ってreturnアドレスを保存する
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct ReturnAddrMarker(usize);
 impl ReturnAddrMarker {
     pub fn incr(&mut self, d: usize) {
@@ -89,10 +709,10 @@ impl ReturnAddrMarker {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SedCode(pub String);
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct SedProgram(Vec<SedInstruction>);
 
 impl Deref for SedProgram {
@@ -109,7 +729,7 @@ impl DerefMut for SedProgram {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum SedInstruction {
     /// 生のSedプログラム
     Sed(SedCode),
@@ -125,15 +745,57 @@ pub enum SedInstruction {
     Ret,
     /// スタックのtopの値によって条件分岐
     IfProc(IfProc),
+    /// インライン展開した生のsedの関数
+    /// 呼び出しと同じく引数を消費して返り値を積む
+    InlineSed(InlineSed),
+    /// 末尾呼び出し
+    /// 現在のフレームを呼び出し先の引数で置き換えて呼び出し先の本体に移り、呼び出し先が代わりに返る
+    TailCall(CallFunc),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub struct InlineSed {
+    code: String,
+    argc: usize,
+    localc: usize,
+    retc: usize,
+}
+
+impl InlineSed {
+    /// codeは`~a~b`の引数(とローカル変数)を`~r;`の返り値に書き換える生のsed
+    pub fn new(code: &str, argc: usize, localc: usize, retc: usize) -> Self {
+        Self {
+            code: code.to_string(),
+            argc,
+            localc,
+            retc,
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    pub fn localc(&self) -> usize {
+        self.localc
+    }
+
+    pub fn retc(&self) -> usize {
+        self.retc
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Value {
     Arg(usize),
     Local(usize),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct IfProc {
     id: usize, // ラベルを決定するために使う
     then_proc: SedProgram,
@@ -152,9 +814,17 @@ impl IfProc {
     fn set_id(&mut self, id: usize) {
         self.id = id
     }
+
+    pub fn then_proc(&self) -> &[SedInstruction] {
+        &self.then_proc
+    }
+
+    pub fn else_proc(&self) -> &[SedInstruction] {
+        &self.else_proc
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ArgVal {
     id: usize, // 引数の識別、同一スコープ内で重複がないように設定する
 }
@@ -165,7 +835,7 @@ impl ArgVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LocalVal {
     id: usize, // 引数の識別、同一スコープ内で重複がないように設定する
 }
@@ -175,29 +845,86 @@ impl LocalVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ConstVal {
     data: String,
+    /// 整数の定数の場合の値 数の表現に合わせて変換するために使う
+    int: Option<u64>,
+    /// 負の`bigint`の定数かどうか
+    negative: bool,
 }
 impl ConstVal {
     pub fn new(data: &str) -> Self {
         Self {
             data: data.to_string(),
+            int: None,
+            negative: false,
+        }
+    }
+
+    /// 文字列の定数 区切り文字をエスケープし、sedの置換文字列として書ける形にする
+    pub fn str(text: &str) -> Self {
+        let data = escape_str(text)
+            .replace('\\', "\\\\")
+            .replace('/', "\\/")
+            .replace('&', "\\&");
+        Self {
+            data,
+            int: None,
+            negative: false,
         }
     }
+
+    /// bits桁の2進数で表した整数の定数
+    pub fn int(value: u64, bits: usize) -> Self {
+        Self {
+            data: format!("{:0width$b}", value, width = bits),
+            int: Some(value),
+            negative: false,
+        }
+    }
+
+    /// `bigint`の定数 先頭に余分な0のない2進数で、負の場合は`-`を付ける
+    pub fn bigint(value: i64) -> Self {
+        let sign = if value < 0 { "-" } else { "" };
+        Self {
+            data: format!("{}{:b}", sign, value.unsigned_abs()),
+            int: Some(value.unsigned_abs()),
+            negative: value < 0,
+        }
+    }
+
+    /// スタックに積まれる文字列
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    /// 整数の定数の場合の絶対値
+    pub fn int_value(&self) -> Option<u64> {
+        self.int
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FuncDef {
     name: String, //
     id: usize,
     argc: usize,   // 引数の個数
     localc: usize, // ローカル変数の個数
     retc: usize,   // 返り値の個数
+    public: bool,  // pub関数かどうか
+    inline: bool,  // `#[inline]`が付いているかどうか
+    constant: bool, // `const fn`かどうか
     return_addr_offset: ReturnAddrMarker,
     proc_contents: SedProgram,
     arg_list: Vec<ArgVal>,
     local_list: Vec<LocalVal>,
+    /// 関数を定義しているソース上の位置 soilから構築した関数だけが持つ
+    span: Option<SourceSpan>,
 }
 
 impl FuncDef {
@@ -208,10 +935,14 @@ impl FuncDef {
             argc,
             localc,
             retc,
+            public: false,
+            inline: false,
+            constant: false,
             return_addr_offset: ReturnAddrMarker(0),
             proc_contents: SedProgram(vec![]),
             arg_list: (0..argc).map(ArgVal::new).collect(),
             local_list: (0..localc).map(LocalVal::new).collect(),
+            span: None,
         }
     }
 
@@ -225,16 +956,76 @@ impl FuncDef {
     fn get_funclabel(&self) -> String {
         format!("func{}", self.id)
     }
+
+    /// 生のsedのラベルに付ける名前空間
+    fn get_label_suffix(&self) -> String {
+        format!("__f{}", self.id)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 引数の個数
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    /// ローカル変数の個数
+    pub fn localc(&self) -> usize {
+        self.localc
+    }
+
+    /// 返り値の個数
+    pub fn retc(&self) -> usize {
+        self.retc
+    }
+
+    pub fn proc_contents(&self) -> &[SedInstruction] {
+        &self.proc_contents
+    }
+
+    pub fn set_public(&mut self, public: bool) {
+        self.public = public;
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    /// 大きさに関わらずインライン展開する
+    pub fn set_inline(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.inline
+    }
+
+    /// 呼び出しをコンパイル時に評価する関数にする
+    pub fn set_const(&mut self, constant: bool) {
+        self.constant = constant;
+    }
+
+    pub fn is_const(&self) -> bool {
+        self.constant
+    }
+
+    /// 検査のエラーを報告する位置
+    pub fn set_span(&mut self, span: Option<SourceSpan>) {
+        self.span = span;
+    }
+
+    pub fn span(&self) -> Option<&SourceSpan> {
+        self.span.as_ref()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CallFunc {
     // 何を呼ぶか
     // return addr
     func_name: String,
-    /// 呼び出しもとのローカル変数の個数
-    /// 関数の引数とローカル変数を足したもの
-    localc: usize,
     return_addr_marker: ReturnAddrMarker,
 }
 
@@ -242,20 +1033,60 @@ impl CallFunc {
     pub fn new(func_name: &str) -> Self {
         Self {
             func_name: func_name.to_string(),
-            localc: 0,
             return_addr_marker: ReturnAddrMarker(0),
         }
     }
+
+    pub fn func_name(&self) -> &str {
+        &self.func_name
+    }
 }
 
 #[derive(Debug)]
 pub enum CompileErr {
     UndefinedFunction(String),
+    /// entry pointとして指定された関数が存在しない
+    MissingEntry(String),
+    /// entry pointの関数がコマンドライン引数の形式と互換性がない
+    InvalidEntry(String),
+    /// スタックの深さの検査に失敗した
+    InvalidStack(Vec<VerifyErr>),
     StackUnderFlow(String),
     PoppingValueFromEmptyStack(String),
     Fatal,
 }
 
+impl CompileErr {
+    /// 診断に変換する
+    /// スタックの検査のエラーは一つずつ別の診断になり、soilで書いた関数の位置を指す
+    pub fn to_diagnostics(&self) -> Vec<Diagnostic> {
+        let messages = match self {
+            CompileErr::UndefinedFunction(name) => {
+                vec![(format!("undefined function `{}`", name), None)]
+            }
+            CompileErr::MissingEntry(name) => {
+                vec![(format!("entry point `{}` is not defined", name), None)]
+            }
+            CompileErr::InvalidEntry(message) => vec![(message.clone(), None)],
+            CompileErr::InvalidStack(errs) => errs
+                .iter()
+                .map(|err| (err.to_string(), err.span.as_deref()))
+                .collect(),
+            CompileErr::StackUnderFlow(message) => {
+                vec![(format!("stack underflow: {}", message), None)]
+            }
+            CompileErr::PoppingValueFromEmptyStack(message) => {
+                vec![(format!("popping value from empty stack: {}", message), None)]
+            }
+            CompileErr::Fatal => vec![("internal compiler error".to_string(), None)],
+        };
+        messages
+            .iter()
+            .map(|(message, span)| Diagnostic::at(Severity::Error, "7", message, *span))
+            .collect()
+    }
+}
+
 // =========================================================================================
 //                                 ここから 共通実装
 // =========================================================================================
@@ -322,14 +1153,16 @@ impl ReturnAddrOffsetResolver for IfProc {
 }
 
 /// 関数ごとに、帰るべき命令列上のアドレスは絞れるので、それらの紹介用ディクショナリを返す
-fn create_return_dispatcher_btree_map(
+/// 末尾呼び出しされる関数は、呼び出し元の代わりに返るので呼び出し元の戻り先も含める
+fn create_return_addr_btree_map(
     func_table: &[FuncDef],
-) -> Result<BTreeMap<String, Vec<ReturnAddrResolveCode>>, CompileErr> {
-    let mut rdic: BTreeMap<String, Vec<ReturnAddrResolveCode>> = BTreeMap::new();
+    tail_sources: &BTreeMap<String, BTreeSet<String>>,
+) -> Result<BTreeMap<String, Vec<ReturnAddr>>, CompileErr> {
+    let mut rdic: BTreeMap<String, Vec<ReturnAddr>> = BTreeMap::new();
     for i in func_table {
         // ある関数以下での呼び出しをカウント
         // 呼び出されている関数から、呼び出し元をリストアップしたい
-        for j in i.proc_contents.sedgen_return_dispatcher(func_table)? {
+        for j in i.proc_contents.collect_return_addrs(func_table)? {
             if let Some(rdic_mut) = &mut rdic.get_mut(&j.func_name) {
                 rdic_mut.push(j);
             } else {
@@ -337,124 +1170,103 @@ fn create_return_dispatcher_btree_map(
             }
         }
     }
+    let direct = rdic.clone();
+    for (callee, sources) in tail_sources {
+        for source in sources.iter().filter(|source| *source != callee) {
+            if let Some(codes) = direct.get(source) {
+                rdic.entry(callee.clone())
+                    .or_default()
+                    .extend(codes.iter().cloned());
+            }
+        }
+    }
     Ok(rdic)
 }
 
-/// return dispatcherコードの生成
-/// プログラムの呼び出し元を判明させる
-trait SedgenReturnDispatcher {
-    fn sedgen_return_dispatcher(
-        &self,
-        func_table: &[FuncDef],
-    ) -> Result<Vec<ReturnAddrResolveCode>, CompileErr>;
-}
-
-#[derive(Debug)]
-struct ReturnAddrResolveCode {
-    func_name: String,
-    code: String,
-}
-
-impl SedgenReturnDispatcher for SedProgram {
-    fn sedgen_return_dispatcher(
-        &self,
-        func_table: &[FuncDef],
-    ) -> Result<Vec<ReturnAddrResolveCode>, CompileErr> {
-        // let mut rstr = String::from("");
-        let mut rvec = Vec::new();
-        for j in &**self {
-            if let SedInstruction::Call(f) = j {
-                rvec.append(&mut f.sedgen_return_dispatcher(func_table)?);
-                // rstr.push_str(&f.sedgen_return_dispatcher(func_table)?);
-            } else if let SedInstruction::IfProc(if_proc) = j {
-                rvec.append(&mut if_proc.sedgen_return_dispatcher(func_table)?);
+/// 末尾呼び出しされる関数ごとに、末尾呼び出しをたどってその関数に至る関数を集める
+/// 末尾呼び出しされない関数は含まない
+fn tail_call_sources(func_table: &[FuncDef]) -> BTreeMap<String, BTreeSet<String>> {
+    let edges: BTreeMap<&str, Vec<&str>> = func_table
+        .iter()
+        .map(|f| {
+            let mut callees = Vec::new();
+            collect_tail_calls(&f.proc_contents, &mut callees);
+            (f.name.as_str(), callees)
+        })
+        .collect();
+    let mut sources: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for &source in edges.keys() {
+        let mut stack: Vec<&str> = edges[source].clone();
+        let mut visited: HashSet<&str> = HashSet::new();
+        while let Some(callee) = stack.pop() {
+            if !visited.insert(callee) {
+                continue;
             }
+            sources
+                .entry(callee.to_string())
+                .or_default()
+                .insert(source.to_string());
+            stack.extend(edges.get(callee).into_iter().flatten());
         }
-        Ok(rvec)
     }
+    sources
 }
 
-impl SedgenReturnDispatcher for CallFunc {
-    fn sedgen_return_dispatcher(
-        &self,
-        func_table: &[FuncDef],
-    ) -> Result<Vec<ReturnAddrResolveCode>, CompileErr> {
-        let func_def = find_function_definition_by_name(&self.func_name, func_table)?;
-        let mut rstr = "".to_string();
-        let retlabel = self.return_addr_marker.get_retlabel();
-
-        rstr.push_str(&format!("/^.*\\n:{}~[^\\|]*|.*$/ {{\n", retlabel));
-        // s/.../.../形式のマッチ文開始
-        {
-            // pattern
-            rstr.push_str(&format!("s/.*\\n:{}", retlabel));
-            rstr.push_str(&"~[^\\~]*".repeat(func_def.argc));
-            // 呼び出し元のローカル変数を復元する
-            if 0 < self.localc {
-                rstr.push_str(&format!(
-                    "\\({}{}\\)",
-                    "~[^\\~]*".repeat(self.localc - 1),
-                    "~[^\\|]*"
-                ));
-            } else {
-                rstr.push_str("\\(\\)");
+fn collect_tail_calls<'a>(proc_contents: &'a [SedInstruction], callees: &mut Vec<&'a str>) {
+    for instruction in proc_contents {
+        match instruction {
+            SedInstruction::TailCall(call_func) => callees.push(&call_func.func_name),
+            SedInstruction::IfProc(if_proc) => {
+                collect_tail_calls(&if_proc.then_proc, callees);
+                collect_tail_calls(&if_proc.else_proc, callees);
             }
-            rstr.push_str("|\\n");
-            rstr.push_str(&format!("\\({}\\)", "~[^\\~;]*".repeat(func_def.retc)));
-            rstr.push_str(";$/");
-            rstr.push_str("\\1\\2");
-            rstr.push_str("/\n");
-        }
-        rstr.push_str(&format!("b {}\n", retlabel));
-        rstr.push_str("}\n");
-        Ok(vec![ReturnAddrResolveCode {
-            func_name: self.func_name.to_string(),
-            code: rstr,
-        }])
+            _ => {}
+        }
     }
 }
 
-impl SedgenReturnDispatcher for IfProc {
-    fn sedgen_return_dispatcher(
-        &self,
-        func_table: &[FuncDef],
-    ) -> Result<Vec<ReturnAddrResolveCode>, CompileErr> {
-        let mut rvec = Vec::new();
-        rvec.append(&mut self.then_proc.sedgen_return_dispatcher(func_table)?);
-        rvec.append(&mut self.else_proc.sedgen_return_dispatcher(func_table)?);
-        Ok(rvec)
-    }
+/// 呼び出し先の関数ごとに、戻りアドレスを集める
+trait CollectReturnAddrs {
+    fn collect_return_addrs(&self, func_table: &[FuncDef]) -> Result<Vec<ReturnAddr>, CompileErr>;
 }
 
-trait SetLocalc {
-    fn set_localc(&mut self, localc: usize);
+#[derive(Clone, Debug, PartialEq)]
+struct ReturnAddr {
+    func_name: String,
+    /// 呼び出し元のretlabelの番号
+    id: usize,
 }
 
-impl SetLocalc for SedProgram {
-    fn set_localc(&mut self, localc: usize) {
-        for j in &mut **self {
-            if let SedInstruction::Call(call_func) = j {
-                call_func.set_localc(localc);
+impl CollectReturnAddrs for SedProgram {
+    fn collect_return_addrs(&self, func_table: &[FuncDef]) -> Result<Vec<ReturnAddr>, CompileErr> {
+        let mut rvec = Vec::new();
+        for j in &**self {
+            if let SedInstruction::Call(f) = j {
+                rvec.append(&mut f.collect_return_addrs(func_table)?);
             } else if let SedInstruction::IfProc(if_proc) = j {
-                if_proc.set_localc(localc);
+                rvec.append(&mut if_proc.collect_return_addrs(func_table)?);
             }
         }
+        Ok(rvec)
     }
 }
 
-impl SetLocalc for CallFunc {
-    /// この関数を呼び出しているスコープにおいて
-    /// どれだけのローカル変数が使用されているかをセットする
-    /// 主にassemble_funcs
-    fn set_localc(&mut self, localc: usize) {
-        self.localc = localc
+impl CollectReturnAddrs for CallFunc {
+    fn collect_return_addrs(&self, func_table: &[FuncDef]) -> Result<Vec<ReturnAddr>, CompileErr> {
+        find_function_definition_by_name(&self.func_name, func_table)?;
+        Ok(vec![ReturnAddr {
+            func_name: self.func_name.to_string(),
+            id: self.return_addr_marker.0,
+        }])
     }
 }
 
-impl SetLocalc for IfProc {
-    fn set_localc(&mut self, localc: usize) {
-        self.then_proc.set_localc(localc);
-        self.else_proc.set_localc(localc);
+impl CollectReturnAddrs for IfProc {
+    fn collect_return_addrs(&self, func_table: &[FuncDef]) -> Result<Vec<ReturnAddr>, CompileErr> {
+        let mut rvec = Vec::new();
+        rvec.append(&mut self.then_proc.collect_return_addrs(func_table)?);
+        rvec.append(&mut self.else_proc.collect_return_addrs(func_table)?);
+        Ok(rvec)
     }
 }
 
@@ -468,14 +1280,14 @@ pub trait ResolvePopAndSetProc {
 }
 
 impl ResolvePopAndSetProc for ArgVal {
-    fn resolve_pop_and_set_proc(&self, stack_size: usize, _func_def: &FuncDef) -> String {
-        resolve_pop_and_set_proc(stack_size, self.id)
+    fn resolve_pop_and_set_proc(&self, _stack_size: usize, _func_def: &FuncDef) -> String {
+        resolve_pop_and_set_proc(self.id)
     }
 }
 
 impl ResolvePopAndSetProc for LocalVal {
-    fn resolve_pop_and_set_proc(&self, stack_size: usize, func_def: &FuncDef) -> String {
-        resolve_pop_and_set_proc(stack_size, func_def.argc + self.id)
+    fn resolve_pop_and_set_proc(&self, _stack_size: usize, func_def: &FuncDef) -> String {
+        resolve_pop_and_set_proc(func_def.argc + self.id)
     }
 }
 
@@ -498,11 +1310,65 @@ fn find_function_definition_by_name<'a>(
 // ------------------------- resolve instructions -----------------------------
 
 /// 生のsedプログラムを格納する
-fn resolve_sed_instruction(rstr: &mut String, sed: &SedCode, stack_size: usize) -> usize {
-    rstr.push_str(&format!("{}\n", sed.0));
+/// ラベルは関数ごとの名前空間に置く
+fn resolve_sed_instruction(
+    rstr: &mut String,
+    sed: &SedCode,
+    func_def: &FuncDef,
+    stack_size: usize,
+) -> usize {
+    rstr.push_str(&format!(
+        "{}\n",
+        namespace_labels(&sed.0, &func_def.get_label_suffix())
+    ));
     stack_size
 }
 
+/// インライン展開した生のsed
+/// 呼び出し元のフレームをホールドスペースに退避し、パターンスペースを呼び出し時と同じ`~a~b`にして実行する
+/// 残った`~r;`を退避したフレームの引数と置き換え、ホールドスペースを元に戻す
+fn resolve_inline_sed_instruction(
+    rstr: &mut String,
+    inline: &InlineSed,
+    func_def: &FuncDef,
+    stack_size: usize,
+) -> usize {
+    let args = fields(inline.argc);
+    rstr.push_str(&format!(
+        "H\ns/.*\\({}\\)$/\\1{}/\n",
+        args,
+        "~init".repeat(inline.localc)
+    ));
+    rstr.push_str(&format!(
+        "{}\n",
+        namespace_labels(&inline.code, &func_def.get_label_suffix())
+    ));
+    rstr.push_str(&format!(
+        "G\ns/^\\([^;]*\\);.*\\n\\(.*\\){}$/\\2\\1/\nx\ns/\\(.*\\)\\n.*/\\1/\nx\n",
+        args
+    ));
+    stack_size - inline.argc + inline.retc
+}
+
+/// 末尾呼び出し
+/// スタックトップの引数だけを残してローカル変数を追加し、呼び出し先の本体に移る
+/// ホールドスペースには何も積まない
+fn resolve_tail_call_instruction(
+    rstr: &mut String,
+    func_call: &CallFunc,
+    func_table: &[FuncDef],
+) -> Result<usize, CompileErr> {
+    let func_def = find_function_definition_by_name(&func_call.func_name, func_table)?;
+    rstr.push_str(&format!(
+        "# tail call: {}\ns/.*\\({}\\)$/\\1{}/\nb tail{}\n",
+        func_def.name,
+        fields(func_def.argc),
+        "~init".repeat(func_def.localc),
+        func_def.id
+    ));
+    Ok(0)
+}
+
 /// 関数の呼び出し。
 /// スタックトップから引数の個数分消費し、返り値分を積む
 fn resolve_call_instruction(
@@ -513,7 +1379,7 @@ fn resolve_call_instruction(
 ) -> Result<usize, CompileErr> {
     // 関数の定義を見つけ出し関数の呼び方を決定する
     let func_def = find_function_definition_by_name(&func_call.func_name, func_table)?;
-    if let Some(code) = sedgen_func_call(func_def, &func_call.return_addr_marker, stack_size) {
+    if let Some(code) = sedgen_func_call(func_def, &func_call.return_addr_marker) {
         rstr.push_str(&code);
         stack_size -= func_def.argc;
         stack_size += func_def.retc;
@@ -523,21 +1389,14 @@ fn resolve_call_instruction(
     Ok(stack_size)
 }
 
-fn resolve_stack_push_proc(stack_size: usize, offset: usize) -> String {
-    format!(
-        "s/{}/{}/\n",
-        format!(
-            "\\({}\\)\\(~[^\\~]*\\)\\({}\\)",
-            "~[^\\~]*".repeat(offset),
-            "~[^\\~]*".repeat(stack_size - offset - 1),
-        ),
-        "\\1\\2\\3\\2"
-    )
+/// フレームの先頭から数えてoffset番目の値をスタックトップに複製する
+fn resolve_stack_push_proc(offset: usize) -> String {
+    format!("s/^{}\\(~[^\\~]*\\).*$/&\\2/\n", fields(offset))
 }
 
 /// 引数をスタックに積む
 fn resolve_argval_instruction(rstr: &mut String, a: &ArgVal, stack_size: usize) -> usize {
-    rstr.push_str(&resolve_stack_push_proc(stack_size, a.id));
+    rstr.push_str(&resolve_stack_push_proc(a.id));
     stack_size + 1
 }
 
@@ -548,34 +1407,36 @@ fn resolve_localval_instruction(
     func_def: &FuncDef,
     stack_size: usize,
 ) -> usize {
-    rstr.push_str(&resolve_stack_push_proc(stack_size, func_def.argc + a.id));
+    rstr.push_str(&resolve_stack_push_proc(func_def.argc + a.id));
     stack_size + 1
 }
 
 /// 定数をスタックに積む
+/// スタックトップはパターンスペースの末尾なので、深さによらず末尾に追加する
 fn resolve_constval_instruction(rstr: &mut String, a: &ConstVal, mut stack_size: usize) -> usize {
-    rstr.push_str(&format!(
-        "s/{}/{}/\n",
-        format!("\\({}\\)", "~[^\\~]*".repeat(stack_size),),
-        format!("\\1~{}", a.data)
-    ));
+    rstr.push_str(&format!("s/$/~{}/\n", a.data));
     stack_size += 1;
     stack_size
 }
 
-fn resolve_pop_and_set_proc(stack_size: usize, offset: usize) -> String {
+/// スタックトップを取り出して、フレームの先頭から数えてoffset番目の値と置き換える
+fn resolve_pop_and_set_proc(offset: usize) -> String {
     format!(
-        "s/{}/{}/\n",
-        //\1      \2            \3
-        format!(
-            "\\({}\\)~[^\\~]*\\({}\\)\\(~[^\\~]*\\)",
-            "~[^\\~]*".repeat(offset),
-            "~[^\\~]*".repeat(stack_size - offset - 2)
-        ),
-        "\\1\\3\\2"
+        //  \1 (\2)            \3         \4
+        "s/^\\({}\\)~[^\\~]*\\(.*\\)\\(~[^\\~]*\\)$/\\1\\4\\3/\n",
+        fields(offset)
     )
 }
 
+/// フレームの値n個に一致する正規表現
+/// 個数によらず同じ長さで、グループを一つ使う
+fn fields(n: usize) -> String {
+    match n {
+        0 => "\\(\\)".to_string(),
+        n => format!("\\(~[^\\~]*\\)\\{{{}\\}}", n),
+    }
+}
+
 /// スタックトップを消費してローカル変数または引数にセットする
 ///
 fn resolve_set_instruction(
@@ -613,11 +1474,7 @@ fn resolve_ret_instructions(
             func_def.name
         )));
     }
-    let arg_pattern: String = format!(
-        "{}\\({}\\)",
-        "~[^\\~]*".repeat(stack_size - func_def.retc),
-        "~[^\\~]*".repeat(func_def.retc)
-    );
+    let arg_pattern: String = format!("^.*\\({}\\)$", fields(func_def.retc));
     let arg_string = "\\1;";
     rstr.push_str(&format!("s/{}/{}/\n", arg_pattern, arg_string));
     // rstr.push_str("b return_dispatcher\n"); // 最後は必ずreturn
@@ -639,7 +1496,7 @@ fn resolve_if_instructions(
     let else_stack_size = stack_size; // fixed
     let mut then_code = String::new();
     let mut else_code = String::new();
-    resolve_instructions(
+    let then_end = resolve_instructions(
         &mut then_code,
         func_def,
         &a.then_proc,
@@ -647,7 +1504,7 @@ fn resolve_if_instructions(
         0,
         func_table,
     )?;
-    resolve_instructions(
+    let else_end = resolve_instructions(
         &mut else_code,
         func_def,
         &a.else_proc,
@@ -655,6 +1512,12 @@ fn resolve_if_instructions(
         0,
         func_table,
     )?;
+    // インライン展開したreturnのように値を残す節では、終了時の深さを引き継ぐ
+    let end_stack_size = match (always_returns(&a.then_proc), always_returns(&a.else_proc)) {
+        (false, _) => then_end,
+        (true, false) => else_end,
+        (true, true) => stack_size,
+    };
 
     let reset_flag = format!("reset_flag{}", a.id);
     let else_label = format!("else{}", a.id);
@@ -678,7 +1541,18 @@ b {endif_label}
 :{endif_label}
 ",
     ));
-    Ok(stack_size)
+    Ok(end_stack_size)
+}
+
+/// 命令列がすべての経路で`Ret`を通るかどうか
+pub(crate) fn always_returns(proc_contents: &[SedInstruction]) -> bool {
+    proc_contents.iter().any(|instruction| match instruction {
+        SedInstruction::Ret | SedInstruction::TailCall(_) => true,
+        SedInstruction::IfProc(if_proc) => {
+            always_returns(&if_proc.then_proc) && always_returns(&if_proc.else_proc)
+        }
+        _ => false,
+    })
 }
 
 fn resolve_instructions(
@@ -692,7 +1566,7 @@ fn resolve_instructions(
     stack_size += fixed_offset;
     for instruction in proc_contents {
         stack_size = match instruction {
-            SedInstruction::Sed(sed) => resolve_sed_instruction(rstr, sed, stack_size),
+            SedInstruction::Sed(sed) => resolve_sed_instruction(rstr, sed, func_def, stack_size),
             SedInstruction::Call(func_call) => {
                 resolve_call_instruction(rstr, func_call, func_table, stack_size)?
             }
@@ -724,6 +1598,12 @@ fn resolve_instructions(
             SedInstruction::Ret => {
                 resolve_ret_instructions(rstr, func_def, stack_size, fixed_offset)?
             }
+            SedInstruction::InlineSed(inline) => {
+                resolve_inline_sed_instruction(rstr, inline, func_def, stack_size)
+            }
+            SedInstruction::TailCall(func_call) => {
+                resolve_tail_call_instruction(rstr, func_call, func_table)?
+            }
         };
     }
 
@@ -735,20 +1615,16 @@ fn resolve_instructions(
 fn sedgen_func_call(
     func_def: &FuncDef,
     return_addr_marker: &ReturnAddrMarker,
-    stack_size: usize,
 ) -> Option<String> {
     let retlabel = return_addr_marker.get_retlabel();
-    let arg_pattern: String = format!(
-        "\\({}\\)\\({}\\)",
-        "~[^\\~]*".repeat(stack_size - func_def.argc),
-        "~[^\\~]*".repeat(func_def.argc)
-    );
-    let arg_string = "\\2\\1";
+    let arg_pattern: String = format!("^\\(.*\\)\\({}\\)$", fields(func_def.argc));
+    // 呼び出し元のフレームの後ろに引数を置く 戻るときは引数の個数によらず`|`までを復元する
+    let arg_string = "\\1|\\2";
 
     Some(format!(
         "
 # function call: {}
-s/{}/:{}{}|/
+s/{}/:{}{}/
 H
 b {}
 :{}
@@ -765,86 +1641,277 @@ b {}
 fn sedgen_func_def(
     func_def: &FuncDef,
     func_table: &[FuncDef],
-    tree: &BTreeMap<String, Vec<ReturnAddrResolveCode>>,
+    tree: &BTreeMap<String, Vec<ReturnAddr>>,
+    tail_sources: &BTreeMap<String, BTreeSet<String>>,
+    entry: &str,
 ) -> Result<String, CompileErr> {
-    let is_entry = func_def.name == "entry";
+    let is_entry = func_def.name == entry;
+    // entryから末尾呼び出しされた場合はentryの代わりにプログラムを終了する
+    let tail_sources_of = tail_sources.get(&func_def.name);
+    let returns_for_entry = is_entry || tail_sources_of.is_some_and(|s| s.contains(entry));
+    // 呼び出し元のない関数には戻り先がない
+    let return_addrs = tree.get(&func_def.name);
     let fixed_offset = func_def.argc + func_def.localc;
+    let pattern = format!("\\({}\\)", fields(func_def.argc));
+    let locals_out = (0..func_def.localc).map(|_| "~init").collect::<String>();
+    let body_label = format!("body{}", func_def.id);
 
-    let mut rstr = if is_entry {
-        // 引数も考慮する
-        let pattern = format!("\\({}\\)", "~[^\\~]*".repeat(func_def.argc));
-        let locals_out = (0..func_def.localc).map(|_| "~init").collect::<String>();
-        format!("s/{}/\\1{}/\n", pattern, locals_out)
-    } else {
-        let pattern = format!("\\({}\\)", "~[^\\~]*".repeat(func_def.argc));
-        let args_out = "\\1";
-        let locals_out = (0..func_def.localc).map(|_| "~init").collect::<String>();
-        format!(
+    let mut rstr = String::new();
+    if is_entry {
+        // プログラムの開始時は入力された引数にローカル変数を追加する
+        rstr.push_str(&format!("s/{}/\\1{}/\n", pattern, locals_out));
+        if return_addrs.is_some() {
+            rstr.push_str(&format!("b {}\n", body_label));
+        }
+    }
+    if !is_entry || return_addrs.is_some() {
+        rstr.push_str(&format!(
             ":{}\n
-s/:retlabel[0-9]\\+{}[^\\|]*|$/{}{}/
+s/:retlabel[0-9]\\+[^|]*|\\(.*\\)$/\\1{}/
 s/\\n\\(.*\\)/\\1/
 ",
             func_def.get_funclabel(),
-            pattern,
-            args_out,
             locals_out
-        )
-    };
+        ));
+        if is_entry {
+            rstr.push_str(&format!(":{}\n", body_label));
+        }
+    }
 
-    let mut stack_size = 0;
+    if tail_sources_of.is_some() {
+        rstr.push_str(&format!(":tail{}\n", func_def.id));
+    }
 
-    stack_size = resolve_instructions(
+    resolve_instructions(
         &mut rstr,
         func_def,
         &func_def.proc_contents,
         fixed_offset,
-        stack_size,
+        0,
         func_table,
     )?;
 
-    if is_entry {
-        let return_label = format!("return{}", func_def.id);
+    let return_label = format!("return{}", func_def.id);
+    if returns_for_entry && return_addrs.is_none() {
         rstr.push_str(&format!(":{}\n", return_label));
         rstr.push_str("b done\n"); // entry return
-    } else {
-        // TODO リターンdispatcherに巨大なマッチ文を書くのではなく、それぞれの関数が解決する方針について考える
-        // rstr.push_str("b return_dispatcher\n"); // 最後は必ずreturn
-        let return_label = format!("return{}", func_def.id);
-        rstr.push_str(&format!(
-            "
-:{}
-H
+        return Ok(rstr);
+    }
+    rstr.push_str(&format!("\n:{}\n", return_label));
+    if returns_for_entry {
+        // 呼び出し元のフレームがholdスペースに残っていなければプログラムの終了
+        rstr.push_str("x\n/^$/ {\nx\nb done\n}\nx\n");
+    }
+    rstr.push_str(
+        "H
 x
 h
 s/^\\(.*\\)\\(\\n:retlabel[0-9]\\+[^|]*|.*\\)$/\\1/
 x
-s/^\\(.*\\)\\(\\n:retlabel[0-9]\\+[^|]*|.*\\)$/\\2/
 ",
-            return_label
+    );
+    // 呼び出し元のフレームを復元し、戻りアドレスの番号だけを`12:`のように先頭に残す
+    let rets = match func_def.retc {
+        0 => "\\(\\)".to_string(),
+        n => format!("\\(\\(~[^\\~;]*\\)\\{{{}\\}}\\)", n),
+    };
+    let mut ids: Vec<String> = return_addrs
+        .into_iter()
+        .flatten()
+        .map(|addr| addr.id.to_string())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    if let [id] = &ids[..] {
+        rstr.push_str(&format!(
+            "s/^.*\\n:retlabel[0-9]\\+\\([^|]*\\)|[^\\n]*\\n{};$/\\1\\2/\nb retlabel{}\n",
+            rets, id
         ));
-        if let Some(codes) = tree.get(&func_def.name) {
-            for return_addr_resolve_code in codes {
-                rstr.push_str(&return_addr_resolve_code.code);
+    } else {
+        rstr.push_str(&format!(
+            "s/^.*\\n:retlabel\\([0-9]\\+\\)\\([^|]*\\)|[^\\n]*\\n{};$/\\1:\\2\\3/\n",
+            rets
+        ));
+        // 置換の成否を消してから、番号を一桁ずつ取り除いて呼び出し元に直接移る
+        let dispatch_label = format!("dispatch{}_", func_def.id);
+        rstr.push_str(&format!("t {}\n:{}\n", dispatch_label, dispatch_label));
+        sedgen_return_jumps(&mut rstr, &dispatch_label, "", &ids);
+        // どの呼び出し元とも一致しない場合はholdスペースが壊れているので、次の関数に進まずに終了する
+        rstr.push_str(&format!(
+            ":{}error\ns/.*/invalid return address/\nw /dev/stderr\nQ 1\n",
+            dispatch_label
+        ));
+    }
+    Ok(rstr)
+}
+
+/// 戻りアドレスの番号の先頭の一桁ごとに分岐する
+/// 呼び出し元の数によらず、一桁につき高々11回の置換で戻り先に着く
+/// 残りが一つに決まる場合は、残りの桁をまとめて取り除いて戻り先に移る
+/// どれにも一致しない場合は`{label}error`に移る
+fn sedgen_return_jumps(rstr: &mut String, label: &str, prefix: &str, ids: &[String]) {
+    let mut digits: Vec<char> = Vec::new();
+    for id in ids {
+        let rest = &id[prefix.len()..];
+        let Some(digit) = rest.chars().next() else {
+            rstr.push_str(&format!("s/^://\nt retlabel{}\n", id));
+            continue;
+        };
+        if ids.iter().filter(|other| other[prefix.len()..].starts_with(digit)).count() == 1 {
+            rstr.push_str(&format!("s/^{}://\nt retlabel{}\n", rest, id));
+        } else if !digits.contains(&digit) {
+            digits.push(digit);
+        }
+    }
+    for digit in &digits {
+        rstr.push_str(&format!("s/^{}//\nt {}{}{}\n", digit, label, prefix, digit));
+    }
+    rstr.push_str(&format!("b {}error\n", label));
+    for digit in digits {
+        let prefix = format!("{}{}", prefix, digit);
+        rstr.push_str(&format!(":{}{}\n", label, prefix));
+        let children: Vec<String> = ids
+            .iter()
+            .filter(|id| id.starts_with(&prefix))
+            .cloned()
+            .collect();
+        sedgen_return_jumps(rstr, label, &prefix, &children);
+    }
+}
+
+/// 命令列から呼び出している関数の名前を集める
+pub(crate) fn collect_called_funcs<'a>(
+    proc_contents: &'a [SedInstruction],
+    called: &mut Vec<&'a str>,
+) {
+    for instruction in proc_contents {
+        match instruction {
+            SedInstruction::Call(call_func) | SedInstruction::TailCall(call_func) => {
+                called.push(&call_func.func_name)
+            }
+            SedInstruction::IfProc(if_proc) => {
+                collect_called_funcs(&if_proc.then_proc, called);
+                collect_called_funcs(&if_proc.else_proc, called);
             }
-        } else {
-            return Err(CompileErr::Fatal);
+            _ => {}
         }
     }
-    Ok(rstr)
+}
+
+/// entryとpub関数から到達できない関数をテーブルから取り除き、取り除いた関数の名前を返す
+/// entryが存在しない場合は何もしない(validate_entryでエラーになる)
+fn remove_unreachable_funcs(func_table: &mut Vec<FuncDef>, entry: &str) -> Vec<String> {
+    if !func_table.iter().any(|f| f.name == entry) {
+        return Vec::new();
+    }
+    let roots: Vec<String> = func_table
+        .iter()
+        .filter(|f| f.public || f.name == entry)
+        .map(|f| f.name.clone())
+        .collect();
+    retain_reachable(func_table, &roots)
+}
+
+/// rootsから到達できない関数をテーブルから取り除き、取り除いた関数の名前を返す
+fn retain_reachable(func_table: &mut Vec<FuncDef>, roots: &[String]) -> Vec<String> {
+    let mut reachable: HashSet<String> = HashSet::new();
+    let mut stack: Vec<&str> = roots.iter().map(String::as_str).collect();
+    while let Some(name) = stack.pop() {
+        if !reachable.insert(name.to_string()) {
+            continue;
+        }
+        for func_def in func_table.iter().filter(|f| f.name == name) {
+            collect_called_funcs(&func_def.proc_contents, &mut stack);
+        }
+    }
+    let removed = func_table
+        .iter()
+        .filter(|f| !reachable.contains(&f.name))
+        .map(|f| f.name.clone())
+        .collect();
+    func_table.retain(|f| reachable.contains(&f.name));
+    removed
+}
+
+/// entry pointがsedの入出力の形式で呼び出せるかを検査する
+/// 入力は`~arg1~arg2...`、出力は`~ret1~ret2...;`の形式になる
+/// - 関数がテーブルの先頭に存在すること
+/// - 返り値が一つ以上あること
+fn validate_entry(func_table: &[FuncDef], entry: &str) -> Result<(), CompileErr> {
+    let Some(entry_def) = func_table.first().filter(|f| f.name == entry) else {
+        return Err(CompileErr::MissingEntry(entry.to_string()));
+    };
+    if entry_def.retc == 0 {
+        return Err(CompileErr::InvalidEntry(format!(
+            "entry point `{}` must return at least one value",
+            entry
+        )));
+    }
+    Ok(())
+}
+
+/// 整数の定数を10進数に書き換える
+fn decimal_constants(proc_contents: &mut [SedInstruction]) {
+    for instruction in proc_contents {
+        match instruction {
+            SedInstruction::ConstVal(const_val) => {
+                if let Some(value) = const_val.int {
+                    let sign = if const_val.negative { "-" } else { "" };
+                    const_val.data = format!("{}{}", sign, value);
+                }
+            }
+            SedInstruction::IfProc(if_proc) => {
+                decimal_constants(&mut if_proc.then_proc);
+                decimal_constants(&mut if_proc.else_proc);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 入力を開始地点の関数の引数の形式に変換するsed
+fn sedgen_prologue(io_format: IoFormat, number_repr: NumberRepr) -> String {
+    match (io_format, number_repr) {
+        (IoFormat::Binary, NumberRepr::Binary) => String::new(),
+        (IoFormat::Decimal, NumberRepr::Binary) => DECIMAL_INPUT_SED.to_string(),
+        // `5 10` -> `~5~10`
+        (IoFormat::Binary, NumberRepr::Decimal) => {
+            format!("{}s/[0-9]\\+/~&/g\ns/ //g\n", DECIMAL_OUTPUT_SED)
+        }
+        // `bigint`の引数のために先頭の`-`を残す
+        (IoFormat::Decimal, NumberRepr::Decimal) => concat!(
+            "s/-\\?[0-9]\\+/~&/g\n",
+            "s/[^-~0-9]//g\n",
+            "s/~\\(-\\?\\)0*\\([0-9]\\)/~\\1\\2/g\n",
+            "s/-0\\(~\\|$\\)/0\\1/g\n",
+        )
+        .to_string(),
+    }
+}
+
+/// 開始地点の関数の返り値を出力の形式に変換するsed
+fn sedgen_epilogue(io_format: IoFormat, number_repr: NumberRepr) -> String {
+    match (io_format, number_repr) {
+        (IoFormat::Binary, NumberRepr::Binary) => String::new(),
+        (IoFormat::Decimal, NumberRepr::Binary) => DECIMAL_OUTPUT_SED.to_string(),
+        // `~5~10;` -> `~101~1010;`
+        (IoFormat::Binary, NumberRepr::Decimal) => {
+            format!("s/;$//\ns/~/ /g\n{}s/$/;/\n", DECIMAL_INPUT_SED)
+        }
+        (IoFormat::Decimal, NumberRepr::Decimal) => "s/;$//\ns/~/ /g\ns/^ //\n".to_string(),
+    }
 }
 
 /// この関数を呼び出す前に必ずassemble_funcsを実行しfunc_tableの設定を終わらせる必要がある
 /// 関数のテーブルを作成する
-fn sedgen_func_table(func_table: &[FuncDef]) -> Result<String, CompileErr> {
+fn sedgen_func_table(func_table: &[FuncDef], entry: &str) -> Result<String, CompileErr> {
     let mut rstr = "".to_string();
-    let tree = create_return_dispatcher_btree_map(func_table)?;
+    let tail_sources = tail_call_sources(func_table);
+    let tree = create_return_addr_btree_map(func_table, &tail_sources)?;
     for i in func_table {
-        let code = sedgen_func_def(i, func_table, &tree)?;
+        let code = sedgen_func_def(i, func_table, &tree, &tail_sources, entry)?;
         rstr.push_str(&code);
     }
-    // === return dispatcher section === // TODO後でこの巨大なマッチ文は取り除く
-    // let code = sedgen_return_dispatcher(func_table)?;
-    // rstr.push_str(&code);
     rstr.push_str(":done");
     Ok(rstr)
 }
@@ -865,7 +1932,9 @@ fn resolve_if_label(proc_contents: &mut Vec<SedInstruction>, mut min_id: usize)
 }
 
 struct ConsumedTable {
+    /// 戻りアドレス(retlabel)の数
     func_label_id: usize,
+    /// ifのラベルの数
     if_id: usize,
 }
 
@@ -875,17 +1944,10 @@ struct ConsumedTable {
 /// 関数のラベルも解決する
 fn assemble_funcs(func_table: &mut [FuncDef]) -> ConsumedTable {
     let mut pad = 0;
-    let mut label_id = 0;
-    for i in &mut *func_table {
+    for (label_id, i) in func_table.iter_mut().enumerate() {
         pad += i.set_return_addr_offset(pad);
 
         i.id = label_id;
-        label_id += 1;
-    }
-
-    // ローカル変数の解決
-    for i in &mut *func_table {
-        i.proc_contents.set_localc(i.localc + i.argc);
     }
 
     // ifスコープのラベル解決
@@ -910,7 +1972,7 @@ mod code_gen_test {
         },
     };
     #[test]
-    fn create_return_dispatcher_btree_map_test00() {
+    fn create_return_addr_btree_map_test00() {
         let mut entry = FuncDef::new("entry", 0, 2, 1);
         let func_mul = em_mul();
         let func_add = em_add();
@@ -942,10 +2004,86 @@ mod code_gen_test {
             func_is_empty,
             func_ends_with_zero,
         ];
-        if let Ok(tree) = create_return_dispatcher_btree_map(&func_def_table) {
-            println!("{:#?}", tree);
-        } else {
-            println!("Something wrong");
+        let tree = create_return_addr_btree_map(&func_def_table, &BTreeMap::new()).unwrap();
+        let addrs = &tree["mul"];
+        // entryからの1回とmulの中での再帰呼び出し
+        assert_eq!(addrs.len(), 3);
+        assert!(addrs.iter().all(|a| a.func_name == "mul"));
+    }
+
+    #[test]
+    fn validate_entry_test00() {
+        // entryが存在しない
+        let mut main = FuncDef::new("main", 0, 0, 1);
+        main.set_proc_contents(vec![
+            SedInstruction::ConstVal(ConstVal::new("1")),
+            SedInstruction::Ret,
+        ]);
+        let result = CompilerBuilder::new().add_func(main).assemble().generate();
+        assert!(matches!(result, Err(CompileErr::MissingEntry(name)) if name == "entry"));
+
+        // entry pointの名前を指定する
+        let mut main = FuncDef::new("main", 0, 0, 1);
+        main.set_proc_contents(vec![
+            SedInstruction::ConstVal(ConstVal::new("1")),
+            SedInstruction::Ret,
+        ]);
+        let result = CompilerBuilder::new()
+            .add_func(em_is_empty())
+            .add_func(main)
+            .entry("main")
+            .assemble()
+            .generate();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn validate_entry_test01() {
+        // 返り値がない
+        let entry = FuncDef::new("entry", 0, 0, 0);
+        let result = CompilerBuilder::new().add_func(entry).assemble().generate();
+        assert!(matches!(result, Err(CompileErr::InvalidEntry(_))));
+    }
+
+    #[test]
+    fn crate_type_test00() {
+        fn build(crate_type: CrateType) -> CompilerBuilder<Assembled> {
+            let mut entry = FuncDef::new("entry", 1, 0, 1);
+            entry.set_proc_contents(vec![
+                SedInstruction::Val(Value::Arg(0)),
+                SedInstruction::Val(Value::Arg(0)),
+                SedInstruction::IfProc(IfProc::new(
+                    vec![SedInstruction::Call(CallFunc::new("shift_left1"))],
+                    vec![],
+                )),
+                SedInstruction::Ret,
+            ]);
+            let mut is_empty = em_is_empty();
+            is_empty.set_public(true);
+            CompilerBuilder::new()
+                .add_func(entry)
+                .add_func(em_shift_left1())
+                .add_func(em_shift_right1())
+                .add_func(is_empty)
+                .crate_type(crate_type)
+                .inline(false)
+                .assemble()
+        }
+        fn names(builder: &CompilerBuilder<Assembled>) -> Vec<&str> {
+            builder.func_table.iter().map(|f| f.name()).collect()
         }
+
+        // entryからもpub関数からも到達できない非pub関数だけが取り除かれる
+        let binary = build(CrateType::Binary);
+        assert_eq!(names(&binary), vec!["entry", "shift_left1", "is_empty"]);
+        assert_eq!(binary.removed_funcs(), ["shift_right1"]);
+        assert!(binary.exports().is_empty());
+        assert!(binary.generate().is_ok());
+
+        // Libraryでは何も取り除かず、pub関数だけを公開する
+        let library = build(CrateType::Library);
+        assert_eq!(library.func_table.len(), 4);
+        assert!(library.removed_funcs().is_empty());
+        assert_eq!(library.exports(), vec!["is_empty"]);
     }
 }
@@ -1,12 +1,20 @@
-use std::{collections::HashSet, marker::PhantomData, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    vec,
+};
 
 use crate::code_gen::{
-    self, CallFunc, CompilerBuilder, ConstVal, FuncDef, IfProc, SedCode, SedInstruction,
+    self, CallFunc, CompilerBuilder, ConstVal, CrateType, FuncDef, IfProc, IoFormat, NumberRepr,
+    SedCode, SedInstruction,
 };
+use crate::diagnostic::{Diagnostic, ErrorFormat, Severity, SourceSpan, UNKNOWN_FILE};
+use crate::inline::contains_sed;
+use crate::lint::{LintConfig, LintLevel, lint_funcs};
+use crate::regex::{RegexKind, regex_func, regex_func_name};
+use crate::types::{IntType, Signatures, TypeEnv, check_func_types, op_func_name};
 use sed_compiler_frontend::parser::*;
 
-use ariadne::{Color, Label, Report, ReportKind, Source};
-
 #[derive(Debug)]
 struct TypeArg;
 #[derive(Debug)]
@@ -120,12 +128,23 @@ fn create_arg_name_registry<'a>(func: &Func<'a>) -> Result<NameRegistry<TypeArg>
     Ok(name_reg)
 }
 
-fn build_func_ir<'a>(func: &Func<'a>) -> Result<FuncDef, BuildIRErr> {
+pub(crate) fn build_func_ir<'a>(func: &Func<'a>) -> Result<FuncDef, BuildIRErr> {
+    build_typed_func_ir(func, &Signatures::default(), false)
+}
+
+/// 他の関数の型を使ってIRを構築する
+/// checkedの場合、演算子は桁あふれを検査する関数を呼び出す
+fn build_typed_func_ir<'a>(
+    func: &Func<'a>,
+    signatures: &Signatures<'a>,
+    checked: bool,
+) -> Result<FuncDef, BuildIRErr> {
     let local_name_registry = if let Ok(a) = create_local_name_registry(&func.body.0) {
         a
     } else {
         return Err(BuildIRErr {
             note: "failed to create local_name_registry".to_string(),
+            span: None,
         });
     };
     let arg_name_registry = if let Ok(a) = create_arg_name_registry(func) {
@@ -133,6 +152,7 @@ fn build_func_ir<'a>(func: &Func<'a>) -> Result<FuncDef, BuildIRErr> {
     } else {
         return Err(BuildIRErr {
             note: "failed to create arg_name_registry".to_string(),
+            span: None,
         });
     };
     let mut func_def = FuncDef::new(
@@ -141,12 +161,24 @@ fn build_func_ir<'a>(func: &Func<'a>) -> Result<FuncDef, BuildIRErr> {
         local_name_registry.names.len(),
         func.rtype.len(),
     );
+    func_def.set_public(func.public);
+    func_def.set_inline(func.inline);
+    func_def.set_const(func.constant);
 
+    let env = TypeEnv::new(func, signatures, checked);
     func_def.set_proc_contents(build_ir(
-        &func.body.0,
+        &func.body,
         &arg_name_registry,
         &local_name_registry,
+        &env,
     )?);
+    // const fnはコンパイル時に評価するので、生のsedを含めない
+    if func.constant && contains_sed(func_def.proc_contents()) {
+        return Err(BuildIRErr {
+            note: format!("const fn `{}` cannot contain a sed block", func.name),
+            span: Some(SourceSpan::new(func.body.1.into_range())),
+        });
+    }
     Ok(func_def)
 }
 
@@ -163,25 +195,43 @@ fn find_value_from_name_registry(
 #[derive(Clone, Debug)]
 pub struct BuildIRErr {
     pub note: String,
+    /// エラーの原因になった文や関数の位置
+    /// 既に位置付きの診断を報告した後のまとめのエラーはNone
+    pub span: Option<SourceSpan>,
+}
+
+impl BuildIRErr {
+    /// 診断に変換する
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::at(Severity::Error, "1", &self.note, self.span.as_ref())
+    }
+
+    /// 位置を持つ場合は、そのファイルの名前を設定する
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.span = self.span.map(|span| span.in_file(file));
+        self
+    }
 }
 
 fn build_ir<'a>(
-    expr: &Expr<'a>,
+    expr: &Spanned<Expr<'a>>,
     arg_name_registry: &NameRegistry<TypeArg>,
     local_name_registry: &NameRegistry<TypeLocal>,
+    env: &TypeEnv<'_, 'a>,
 ) -> Result<Vec<SedInstruction>, BuildIRErr> {
-    match &expr {
+    match &expr.0 {
         Expr::Error => {
             Err(BuildIRErr {
                 note: "ast contains \"Error\"".to_string(),
+                span: Some(SourceSpan::new(expr.1.into_range())),
             })
         }
         Expr::If(cond, then, else_) => {
-            let mut cond_ir = build_ir(&cond.0, arg_name_registry, local_name_registry)?;
+            let mut cond_ir = build_ir(cond, arg_name_registry, local_name_registry, env)?;
             let if_inst = SedInstruction::IfProc(IfProc::new(
-                build_ir(&then.0, arg_name_registry, local_name_registry)?,
-                if let Some((else_, span)) = &**else_ {
-                    build_ir(else_, arg_name_registry, local_name_registry)?
+                build_ir(then, arg_name_registry, local_name_registry, env)?,
+                if let Some(else_) = &**else_ {
+                    build_ir(else_, arg_name_registry, local_name_registry, env)?
                 } else {
                     vec![]
                 },
@@ -190,8 +240,8 @@ fn build_ir<'a>(
             Ok(cond_ir)
         }
         Expr::Then(a, b) => {
-            let mut a_ir = build_ir(&a.0, arg_name_registry, local_name_registry)?;
-            let mut b_ir = build_ir(&b.0, arg_name_registry, local_name_registry)?;
+            let mut a_ir = build_ir(a, arg_name_registry, local_name_registry, env)?;
+            let mut b_ir = build_ir(b, arg_name_registry, local_name_registry, env)?;
             a_ir.append(&mut b_ir);
             Ok(a_ir)
         }
@@ -199,13 +249,20 @@ fn build_ir<'a>(
             if let Some(val_number) =
                 find_value_from_name_registry(arg_name_registry, local_name_registry, a)
             {
-                let mut ir = build_ir(&b.0, arg_name_registry, local_name_registry)?;
+                let mut ir = build_typed_ir(
+                    b,
+                    env.var_type(a),
+                    arg_name_registry,
+                    local_name_registry,
+                    env,
+                )?;
                 ir.push(SedInstruction::Set(val_number));
                 Ok(ir)
             } else {
                 // error
                 Err(BuildIRErr {
                     note: format!("could not find value \"{}\" from the registry.", a),
+                    span: Some(SourceSpan::new(expr.1.into_range())),
                 })
             }
         }
@@ -222,9 +279,22 @@ fn build_ir<'a>(
             Ok(r_inst)
         }
         Expr::Call(a, b) => {
+            if let Some((kind, re)) = regex_call(&a.0, &b.0) {
+                // 正規表現はcheck_regexesで生成した関数に置き換える
+                let mut instructions =
+                    build_ir(&b.0[0], arg_name_registry, local_name_registry, env)?;
+                let name = regex_func_name(kind, re);
+                instructions.push(SedInstruction::Call(CallFunc::new(&name)));
+                return Ok(instructions);
+            }
             let mut instructions = vec![];
-            for (expr, _) in &b.0 {
-                let mut inst = build_ir(expr, arg_name_registry, local_name_registry)?;
+            for (index, arg) in b.0.iter().enumerate() {
+                let ty = match &a.0 {
+                    Expr::Local(name) => env.arg_type(name, index),
+                    _ => None,
+                };
+                let mut inst =
+                    build_typed_ir(arg, ty, arg_name_registry, local_name_registry, env)?;
                 instructions.append(&mut inst);
             }
             if let Expr::Local(name) = &a.0 {
@@ -233,6 +303,7 @@ fn build_ir<'a>(
             } else {
                 return Err(BuildIRErr {
                     note: "function name must be local".to_string(),
+                    span: Some(SourceSpan::new(a.1.into_range())),
                 });
             }
             Ok(instructions)
@@ -247,10 +318,16 @@ fn build_ir<'a>(
                         SedInstruction::ConstVal(ConstVal::new("0"))
                     }
                 }
-                Value::Str(data) => SedInstruction::ConstVal(ConstVal::new(data)),
+                Value::Str(data) => SedInstruction::ConstVal(ConstVal::str(data)),
                 Value::Func(name) => SedInstruction::Call(CallFunc::new(name)),
-                Value::Int32(i) => SedInstruction::ConstVal(ConstVal::new(&format!("{:032b}", i))),
-                Value::Int64(i) => SedInstruction::ConstVal(ConstVal::new(&format!("{:064b}", i))),
+                Value::Regex(re) => {
+                    return Err(BuildIRErr {
+                        note: format!("unexpected regex literal `/{}/`", re),
+                        span: Some(SourceSpan::new(expr.1.into_range())),
+                    });
+                }
+                Value::Int32(i) => SedInstruction::ConstVal(ConstVal::int(*i as u32 as u64, 32)),
+                Value::Int64(i) => SedInstruction::ConstVal(ConstVal::int(*i as u64, 64)),
             };
             Ok(vec![data_inst])
         }
@@ -263,6 +340,7 @@ fn build_ir<'a>(
                 // localでかつこれに変数名引数名に該当しない場合は関数
                 Err(BuildIRErr {
                     note: "context error".to_string(),
+                    span: Some(SourceSpan::new(expr.1.into_range())),
                 })
             }
         }
@@ -274,11 +352,20 @@ fn build_ir<'a>(
                 | BinaryOp::Div
                 | BinaryOp::Mod
                 | BinaryOp::NotEq
-                | BinaryOp::Eq => {
-                    let mut lhs = build_ir(&lhs.0, arg_name_registry, local_name_registry)?;
-                    let mut rhs = build_ir(&rhs.0, arg_name_registry, local_name_registry)?;
+                | BinaryOp::Eq
+                | BinaryOp::Lt
+                | BinaryOp::LtEq
+                | BinaryOp::Gt
+                | BinaryOp::GtEq => {
+                    // 演算子の関数は両辺の型から決める
+                    let ty = Some(env.operand_type(&lhs.0, &rhs.0));
+                    let func_name = env.op_func(op, &lhs.0, &rhs.0);
+                    let mut lhs =
+                        build_typed_ir(lhs, ty, arg_name_registry, local_name_registry, env)?;
+                    let mut rhs =
+                        build_typed_ir(rhs, ty, arg_name_registry, local_name_registry, env)?;
                     lhs.append(&mut rhs);
-                    lhs.push(SedInstruction::Call(CallFunc::new(op_func_table(op))));
+                    lhs.push(SedInstruction::Call(CallFunc::new(func_name)));
                     Ok(lhs)
                 } //BinaryOp::Assign => {
                   //    // 重要
@@ -301,25 +388,40 @@ fn build_ir<'a>(
             }
         }
         Expr::Neg(a) => {
-            Err(BuildIRErr {
-                note: "not yet".to_string(),
-            })
+            if int_literal(&expr.0).is_some() {
+                return build_typed_ir(expr, None, arg_name_registry, local_name_registry, env);
+            }
+            // 0から引く
+            let ty = env
+                .infer(&a.0)
+                .and_then(IntType::from_name)
+                .unwrap_or(IntType::U32);
+            let mut ir = vec![SedInstruction::ConstVal(int_const(0, ty))];
+            ir.append(&mut build_ir(a, arg_name_registry, local_name_registry, env)?);
+            ir.push(SedInstruction::Call(CallFunc::new(op_func_name(
+                &BinaryOp::Sub,
+                ty,
+                env.checked,
+            ))));
+            Ok(ir)
         }
-        Expr::Return((a, span)) => {
+        Expr::Return((a, _span)) => {
             // 返り値の型が違うエラー
             let mut ir = vec![];
-            for (expr, _span) in a {
-                ir.append(&mut build_ir(
-                    expr,
+            for (index, value) in a.iter().enumerate() {
+                ir.append(&mut build_typed_ir(
+                    value,
+                    env.return_type(index),
                     arg_name_registry,
                     local_name_registry,
+                    env,
                 )?);
             }
             ir.push(SedInstruction::Ret);
             Ok(ir)
         }
         Expr::Assign(lhs, rhs) => {
-            let mut rhs_ir = build_ir(&rhs.0, arg_name_registry, local_name_registry)?;
+            let mut rhs_ir = build_ir(rhs, arg_name_registry, local_name_registry, env)?;
 
             for (value, value_span) in lhs.0.iter().rev() {
                 if let Expr::Local(a) = &value {
@@ -330,12 +432,14 @@ fn build_ir<'a>(
                     } else {
                         return Err(BuildIRErr {
                             note: format!("could not find value \"{}\" from the registry.", a),
+                            span: Some(SourceSpan::new(value_span.into_range())),
                         });
                     }
                 } else {
                     // unreachable
                     return Err(BuildIRErr {
                         note: "invalid left expresion".to_string(),
+                        span: Some(SourceSpan::new(value_span.into_range())),
                     });
                 }
             }
@@ -344,77 +448,638 @@ fn build_ir<'a>(
     }
 }
 
-fn op_func_table<'a>(binop: &BinaryOp) -> &'a str {
-    // 組み込み関数の名前にする
-    match binop {
-        BinaryOp::Add => "add",
-        BinaryOp::Sub => "sub32",
-        BinaryOp::Mul => "mul32",
-        BinaryOp::Div => "div",
-        BinaryOp::Eq => "eq",
-        BinaryOp::NotEq => "neq",
-        BinaryOp::Mod => "mod",
+/// 符号を含む整数リテラルの値
+fn int_literal(expr: &Expr<'_>) -> Option<i64> {
+    match expr {
+        Expr::Value(Value::Int32(i)) => Some(*i as i64),
+        Expr::Value(Value::Int64(i)) => Some(*i),
+        Expr::Neg(a) => int_literal(&a.0).map(i64::wrapping_neg),
+        _ => None,
+    }
+}
+
+/// 整数リテラルの定数 固定幅の型ではその幅で桁あふれを捨てる
+fn int_const(value: i64, ty: IntType) -> ConstVal {
+    match ty.bits() {
+        Some(bits) => ConstVal::int(value as u64 & (u64::MAX >> (64 - bits)), bits),
+        None => ConstVal::bigint(value),
+    }
+}
+
+/// 型に合わせて式のIRを構築する
+/// 整数リテラルはその型の幅の2進数になる 型が分からない場合は32bit
+fn build_typed_ir<'a>(
+    expr: &Spanned<Expr<'a>>,
+    ty: Option<IntType>,
+    arg_name_registry: &NameRegistry<TypeArg>,
+    local_name_registry: &NameRegistry<TypeLocal>,
+    env: &TypeEnv<'_, 'a>,
+) -> Result<Vec<SedInstruction>, BuildIRErr> {
+    let Some(value) = int_literal(&expr.0) else {
+        return build_ir(expr, arg_name_registry, local_name_registry, env);
+    };
+    Ok(vec![SedInstruction::ConstVal(int_const(
+        value,
+        ty.unwrap_or(IntType::U32),
+    ))])
+}
+
+/// 型が分からない場合に演算子が呼び出す組み込み関数の名前
+pub(crate) fn op_func_table(binop: &BinaryOp) -> &'static str {
+    op_func_name(binop, IntType::U32, false)
+}
+
+/// フロントエンドの設定
+#[derive(Clone, Debug)]
+pub struct FrontendOptions {
+    /// 警告ごとのレベル
+    pub lint: LintConfig,
+    /// プログラムの開始地点となる関数の名前
+    pub entry: String,
+    /// 生成するものの種類
+    pub crate_type: CrateType,
+    /// 生成するsedスクリプトの入出力の形式
+    pub io_format: IoFormat,
+    /// 生成するsedスクリプトでの数の表現
+    pub number_repr: NumberRepr,
+    /// 演算子で桁あふれや0による除算が起きた場合にスクリプトを中断する
+    pub checked: bool,
+    /// 診断の出力形式
+    pub error_format: ErrorFormat,
+}
+
+impl Default for FrontendOptions {
+    fn default() -> Self {
+        Self {
+            lint: LintConfig::default(),
+            entry: "entry".to_string(),
+            crate_type: CrateType::Binary,
+            io_format: IoFormat::Binary,
+            number_repr: NumberRepr::Binary,
+            checked: false,
+            error_format: ErrorFormat::Human,
+        }
+    }
+}
+
+/// コンパイル単位を構成するソースファイル
+/// 一つのファイルが一つのモジュールになる
+#[derive(Clone, Copy, Debug)]
+pub struct SourceFile<'a> {
+    /// 診断で表示されるファイルの名前
+    pub name: &'a str,
+    pub code: &'a str,
+}
+
+/// すべてのソースファイルを参照して診断を出力する
+struct Reporter<'a> {
+    sources: Vec<(&'a str, &'a str)>,
+    format: ErrorFormat,
+}
+
+impl<'a> Reporter<'a> {
+    fn new(files: &[SourceFile<'a>], format: ErrorFormat) -> Self {
+        Self {
+            sources: files.iter().map(|f| (f.name, f.code)).collect(),
+            format,
+        }
+    }
+
+    fn report(&self, diagnostic: Diagnostic) {
+        diagnostic.emit(&self.sources, self.format);
+    }
+}
+
+/// entry pointの引数と返り値に使える型
+/// sedの入力`~arg1~arg2...`と出力`~ret1~ret2...;`に直接書ける値に限られる
+const ENTRY_VALUE_TYPES: [&str; 9] = [
+    "bit32", "bit64", "u32", "i32", "u64", "i64", "bigint", "bool", "str",
+];
+
+/// entry pointの型がコマンドライン引数の形式と互換性があるかを検査する
+fn check_entry_signature(
+    func: &Func<'_>,
+    file: &SourceFile<'_>,
+    reporter: &Reporter<'_>,
+) -> Result<(), BuildIRErr> {
+    let invalid_types = func
+        .args
+        .iter()
+        .map(|(arg, span)| (arg.type_, span))
+        .chain(func.rtype.iter().map(|(type_, span)| (*type_, span)))
+        .filter(|(type_, _)| !ENTRY_VALUE_TYPES.contains(type_));
+    let mut count = 0;
+    for (type_, span) in invalid_types {
+        count += 1;
+        let diagnostic = Diagnostic::new(
+            Severity::Error,
+            "4",
+            &format!("type `{}` cannot be passed through the entry point `{}`", type_, func.name),
+            span.into_range(),
+        )
+        .with_label("unsupported type for command-line arguments")
+        .with_note(&format!("expected one of {}", ENTRY_VALUE_TYPES.join(", ")))
+        .in_file(file.name);
+        reporter.report(diagnostic);
+    }
+    if func.rtype.is_empty() {
+        count += 1;
+        reporter.report(
+            Diagnostic::new(
+                Severity::Error,
+                "4",
+                &format!("entry point `{}` must return at least one value", func.name),
+                func.body.1.into_range(),
+            )
+            .in_file(file.name),
+        );
+    }
+    if 0 < count {
+        Err(BuildIRErr {
+            note: format!("invalid entry point `{}`", func.name),
+            span: None,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// `--number-repr decimal`のpreludeが演算子を持たない型
+/// 10進数の表現では`u32`と`bigint`だけを計算できる
+const BINARY_ONLY_TYPES: [&str; 4] = ["i32", "u64", "bit64", "i64"];
+
+/// 数の表現で計算できない型とcheckedを検査する
+fn check_number_repr(
+    files: &[SourceFile<'_>],
+    modules: &[Vec<Spanned<Func<'_>>>],
+    options: &FrontendOptions,
+    reporter: &Reporter<'_>,
+) -> Result<(), BuildIRErr> {
+    if options.number_repr != NumberRepr::Decimal {
+        return Ok(());
+    }
+    let mut count = 0;
+    if options.checked {
+        count += 1;
+        reporter.report(
+            Diagnostic::without_span(
+                Severity::Error,
+                "11",
+                "checked arithmetic is not supported with `--number-repr decimal`",
+            )
+            .with_note("remove `--checked` or use `--number-repr binary`"),
+        );
+    }
+    for (file, funcs) in files.iter().zip(modules) {
+        for (func, _) in funcs {
+            let types = func
+                .args
+                .iter()
+                .map(|(arg, span)| (arg.type_, span))
+                .chain(func.rtype.iter().map(|(type_, span)| (*type_, span)))
+                .filter(|(type_, _)| BINARY_ONLY_TYPES.contains(type_));
+            for (type_, span) in types {
+                count += 1;
+                reporter.report(
+                    Diagnostic::new(
+                        Severity::Error,
+                        "11",
+                        &format!("type `{}` is not supported with `--number-repr decimal`", type_),
+                        span.into_range(),
+                    )
+                    .in_file(file.name)
+                    .with_label("unsupported type for the decimal representation")
+                    .with_note("use `u32` or `bigint`, or `--number-repr binary`"),
+                );
+            }
+        }
+    }
+    if 0 < count {
+        Err(BuildIRErr {
+            note: format!("aborting due to {} number representation error(s)", count),
+            span: None,
+        })
+    } else {
+        Ok(())
     }
 }
 
-///
+/// soilのソースコードからIRを構築する
 pub fn compiler_frontend(code: &str) -> Result<CompilerBuilder<code_gen::Unassembled>, BuildIRErr> {
-    let (tokens, err) = lexer_parse(code);
-    let mut compile_builder = CompilerBuilder::new();
-
-    match &tokens {
-        Some(tokens) => {
-            let parse_result = parser_parse(code, tokens);
-            // println!("{:#?}", parse_result);
-            match parse_result {
-                Ok(a) => {
-                    for (func, _) in a {
-                        match build_func_ir(&func) {
-                            Ok(instructions) => {
-                                // println!("{:#?}", instructions);
-                                compile_builder = compile_builder.add_func(instructions)
-                            }
-                            Err(e) => {
-                                return Err(BuildIRErr {
-                                    note: e.note.to_string(),
-                                });
-                            }
-                        }
-                    }
-                    Ok(compile_builder)
+    compiler_frontend_with_options(code, &FrontendOptions::default())
+}
+
+/// 設定を指定してsoilのソースコードからIRを構築する
+/// 警告はIRの構築前に表示され、Denyに設定された警告があればエラーになる
+pub fn compiler_frontend_with_options(
+    code: &str,
+    options: &FrontendOptions,
+) -> Result<CompilerBuilder<code_gen::Unassembled>, BuildIRErr> {
+    compiler_frontend_files(
+        &[SourceFile {
+            name: UNKNOWN_FILE,
+            code,
+        }],
+        options,
+    )
+}
+
+/// 関数の呼び出しとその位置を集める
+fn collect_calls<'a>(expr: &Spanned<Expr<'a>>, calls: &mut Vec<(&'a str, Span)>) {
+    match &expr.0 {
+        Expr::Value(Value::Func(name)) => calls.push((name, expr.1)),
+        Expr::Error | Expr::Value(_) | Expr::Local(_) | Expr::Sed(_) => {}
+        Expr::Neg(a) | Expr::Let(_, a) | Expr::Assign(_, a) => collect_calls(a, calls),
+        Expr::Then(a, b) => {
+            collect_calls(a, calls);
+            collect_calls(b, calls);
+        }
+        Expr::Binary(lhs, op, rhs) => {
+            calls.push((op_func_table(op), expr.1));
+            collect_calls(lhs, calls);
+            collect_calls(rhs, calls);
+        }
+        Expr::If(cond, then, else_) => {
+            collect_calls(cond, calls);
+            collect_calls(then, calls);
+            if let Some(else_) = &**else_ {
+                collect_calls(else_, calls);
+            }
+        }
+        Expr::Call(f, args) => {
+            if let Expr::Local(name) = &f.0 {
+                calls.push((name, f.1));
+            } else {
+                collect_calls(f, calls);
+            }
+            for arg in &args.0 {
+                collect_calls(arg, calls);
+            }
+        }
+        Expr::Return((values, _)) => {
+            for value in values {
+                collect_calls(value, calls);
+            }
+        }
+    }
+}
+
+/// 関数を定義しているモジュール
+struct FuncSymbol {
+    /// 定義しているファイルのインデックス
+    file: usize,
+    public: bool,
+    span: Span,
+}
+
+/// モジュールをまたぐ関数の呼び出しを検査する
+/// - 他のモジュールの非pub関数を呼び出していないこと
+/// - 同じ名前の関数が複数のモジュールで定義されていないこと
+fn check_visibility(
+    files: &[SourceFile<'_>],
+    modules: &[Vec<Spanned<Func<'_>>>],
+    reporter: &Reporter<'_>,
+) -> Result<(), BuildIRErr> {
+    let mut symbols: HashMap<&str, FuncSymbol> = HashMap::new();
+    let mut count = 0;
+    for (file, funcs) in modules.iter().enumerate() {
+        for (func, span) in funcs {
+            match symbols.get(func.name) {
+                Some(defined) if defined.file != file => {
+                    count += 1;
+                    let diagnostic = Diagnostic::new(
+                        Severity::Error,
+                        "6",
+                        &format!("function `{}` is defined in multiple modules", func.name),
+                        span.into_range(),
+                    )
+                    .in_file(files[file].name)
+                    .with_label("redefined here")
+                    .with_related(
+                        files[defined.file].name,
+                        defined.span.into_range(),
+                        "first defined here",
+                    );
+                    reporter.report(diagnostic);
+                }
+                Some(_) => {}
+                None => {
+                    symbols.insert(
+                        func.name,
+                        FuncSymbol {
+                            file,
+                            public: func.public,
+                            span: *span,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    for (file, funcs) in modules.iter().enumerate() {
+        let mut calls = Vec::new();
+        for (func, _) in funcs {
+            collect_calls(&func.body, &mut calls);
+        }
+        for (name, span) in calls {
+            let Some(defined) = symbols.get(name) else {
+                continue;
+            };
+            if defined.file != file && !defined.public {
+                count += 1;
+                let diagnostic = Diagnostic::new(
+                    Severity::Error,
+                    "5",
+                    &format!("function `{}` is private", name),
+                    span.into_range(),
+                )
+                .in_file(files[file].name)
+                .with_label("private function called from another module")
+                .with_related(
+                    files[defined.file].name,
+                    defined.span.into_range(),
+                    &format!("`{}` is defined here without `pub`", name),
+                )
+                .with_note(&format!(
+                    "add `pub` to export `{}` from {}",
+                    name, files[defined.file].name
+                ));
+                reporter.report(diagnostic);
+            }
+        }
+    }
+    if 0 < count {
+        Err(BuildIRErr {
+            note: format!("aborting due to {} visibility error(s)", count),
+            span: None,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// 異なる整数型どうしの演算がないかを検査する
+fn check_types<'a>(
+    files: &[SourceFile<'_>],
+    modules: &[Vec<Spanned<Func<'a>>>],
+    signatures: &Signatures<'a>,
+    reporter: &Reporter<'_>,
+) -> Result<(), BuildIRErr> {
+    let mut count = 0;
+    for (file, funcs) in files.iter().zip(modules) {
+        for (func, _) in funcs {
+            for err in check_func_types(func, signatures) {
+                count += 1;
+                reporter.report(
+                    Diagnostic::new(Severity::Error, "8", &err.message, err.span.into_range())
+                        .in_file(file.name)
+                        .with_label("operands have different integer types"),
+                );
+            }
+        }
+    }
+    if 0 < count {
+        Err(BuildIRErr {
+            note: format!("aborting due to {} type error(s)", count),
+            span: None,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// `matches(s, /re/)`と`captures(s, /re/)`の呼び出しであれば種類と正規表現を返す
+fn regex_call<'a>(f: &Expr<'a>, args: &[Spanned<Expr<'a>>]) -> Option<(RegexKind, &'a str)> {
+    let Expr::Local(name) = f else {
+        return None;
+    };
+    let kind = RegexKind::from_name(name)?;
+    match args {
+        [_, (Expr::Value(Value::Regex(re)), _)] => Some((kind, re)),
+        _ => None,
+    }
+}
+
+/// 正規表現リテラルとその位置を集める
+/// matchesとcapturesの第2引数に置かれたものは種類も返す
+fn collect_regexes<'a>(
+    expr: &Spanned<Expr<'a>>,
+    regexes: &mut Vec<(Option<RegexKind>, &'a str, Span)>,
+) {
+    match &expr.0 {
+        Expr::Value(Value::Regex(re)) => regexes.push((None, re, expr.1)),
+        Expr::Error | Expr::Value(_) | Expr::Local(_) | Expr::Sed(_) => {}
+        Expr::Neg(a) | Expr::Let(_, a) | Expr::Assign(_, a) => collect_regexes(a, regexes),
+        Expr::Then(a, b) | Expr::Binary(a, _, b) => {
+            collect_regexes(a, regexes);
+            collect_regexes(b, regexes);
+        }
+        Expr::If(cond, then, else_) => {
+            collect_regexes(cond, regexes);
+            collect_regexes(then, regexes);
+            if let Some(else_) = &**else_ {
+                collect_regexes(else_, regexes);
+            }
+        }
+        Expr::Call(f, args) => {
+            collect_regexes(f, regexes);
+            if let Some((kind, re)) = regex_call(&f.0, &args.0) {
+                collect_regexes(&args.0[0], regexes);
+                regexes.push((Some(kind), re, args.0[1].1));
+            } else {
+                for arg in &args.0 {
+                    collect_regexes(arg, regexes);
                 }
-                Err(errs) => {
-                    println!("{:#?}", errs);
-                    for err in errs {
-                        Report::build(ReportKind::Error, ((), err.span().into_range()))
-                            .with_config(
-                                ariadne::Config::new().with_index_type(ariadne::IndexType::Byte),
-                            )
-                            .with_code(3)
-                            //.with_message(err.to_string())
-                            .with_label(
-                                Label::new(((), err.span().into_range()))
-                                    //.with_message(err.reason().to_string())
-                                    .with_color(Color::Red),
-                            )
-                            .finish()
-                            .eprint(Source::from(code))
-                            .unwrap();
+            }
+        }
+        Expr::Return((values, _)) => {
+            for value in values {
+                collect_regexes(value, regexes);
+            }
+        }
+    }
+}
+
+/// 正規表現リテラルを検査し、照合する関数を生成する
+/// 同じ正規表現の関数は一つだけ生成する
+fn check_regexes(
+    files: &[SourceFile<'_>],
+    modules: &[Vec<Spanned<Func<'_>>>],
+    reporter: &Reporter<'_>,
+) -> Result<Vec<FuncDef>, BuildIRErr> {
+    let mut count = 0;
+    let mut generated = Vec::new();
+    let mut seen = HashSet::new();
+    for (file, funcs) in files.iter().zip(modules) {
+        let mut regexes = Vec::new();
+        for (func, _) in funcs {
+            collect_regexes(&func.body, &mut regexes);
+        }
+        for (kind, re, span) in regexes {
+            let Some(kind) = kind else {
+                count += 1;
+                reporter.report(
+                    Diagnostic::new(
+                        Severity::Error,
+                        "9",
+                        "regex literal outside of `matches` or `captures`",
+                        span.into_range(),
+                    )
+                    .in_file(file.name)
+                    .with_label("only allowed as the second argument of `matches` or `captures`"),
+                );
+                continue;
+            };
+            match regex_func(kind, re) {
+                Ok(func) => {
+                    if seen.insert(regex_func_name(kind, re)) {
+                        generated.push(func);
                     }
-                    Err(BuildIRErr {
-                        note: "failed while parsing".to_string(),
-                    })
+                }
+                Err(err) => {
+                    count += 1;
+                    reporter.report(
+                        Diagnostic::new(
+                            Severity::Error,
+                            "9",
+                            &format!("invalid regex `/{}/`: {}", re, err.message),
+                            span.into_range(),
+                        )
+                        .in_file(file.name)
+                        .with_label(&err.message),
+                    );
                 }
             }
         }
-        None => {
-            println!("Some Error Occured");
+    }
+    if 0 < count {
+        Err(BuildIRErr {
+            note: format!("aborting due to {} regex error(s)", count),
+            span: None,
+        })
+    } else {
+        Ok(generated)
+    }
+}
 
-            Err(BuildIRErr {
-                note: "failed while tokenize".to_string(),
-            })
+/// 字句解析、構文解析のエラーのラベル
+fn unexpected<T: std::fmt::Display>(found: Option<&T>) -> String {
+    match found {
+        Some(found) => format!("unexpected `{}`", found),
+        None => "unexpected end of input".to_string(),
+    }
+}
+
+/// 複数のソースファイルからIRを構築する
+/// ファイルごとに別のモジュールとして扱い、pubでない関数は他のモジュールから呼び出せない
+pub fn compiler_frontend_files(
+    files: &[SourceFile<'_>],
+    options: &FrontendOptions,
+) -> Result<CompilerBuilder<code_gen::Unassembled>, BuildIRErr> {
+    let mut compile_builder = CompilerBuilder::new()
+        .entry(&options.entry)
+        .crate_type(options.crate_type)
+        .io_format(options.io_format)
+        .number_repr(options.number_repr);
+
+    let reporter = Reporter::new(files, options.error_format);
+
+    let mut token_list = Vec::new();
+    for file in files {
+        let (tokens, errs) = lexer_parse(file.code);
+        for err in &errs {
+            reporter.report(
+                Diagnostic::new(
+                    Severity::Error,
+                    "2",
+                    &err.to_string(),
+                    err.span().into_range(),
+                )
+                .in_file(file.name)
+                .with_label(&unexpected(err.found())),
+            );
+        }
+        match tokens {
+            Some(tokens) if errs.is_empty() => token_list.push(tokens),
+            _ => {
+                return Err(BuildIRErr {
+                    note: "failed while tokenize".to_string(),
+                    span: None,
+                });
+            }
+        }
+    }
+
+    let mut modules = Vec::new();
+    for (file, tokens) in files.iter().zip(&token_list) {
+        match parser_parse(file.code, tokens) {
+            Ok(funcs) => modules.push(funcs),
+            Err(errs) => {
+                for err in errs {
+                    reporter.report(
+                        Diagnostic::new(
+                            Severity::Error,
+                            "3",
+                            &err.to_string(),
+                            err.span().into_range(),
+                        )
+                        .in_file(file.name)
+                        .with_label(&unexpected(err.found())),
+                    );
+                }
+                return Err(BuildIRErr {
+                    note: "failed while parsing".to_string(),
+                    span: None,
+                });
+            }
+        }
+    }
+
+    let mut denied = 0;
+    for (file, funcs) in files.iter().zip(&modules) {
+        for warning in lint_funcs(funcs, &options.entry) {
+            let level = options.lint.level(warning.lint);
+            if level == LintLevel::Deny {
+                denied += 1;
+            }
+            reporter.report(warning.to_diagnostic(level).in_file(file.name));
+        }
+    }
+    if 0 < denied {
+        return Err(BuildIRErr {
+            note: format!("aborting due to {} denied lint(s)", denied),
+            span: None,
+        });
+    }
+    check_visibility(files, &modules, &reporter)?;
+    check_number_repr(files, &modules, options, &reporter)?;
+    for (file, funcs) in files.iter().zip(&modules) {
+        if let Some((entry, _)) = funcs.iter().find(|(func, _)| func.name == options.entry) {
+            check_entry_signature(entry, file, &reporter)?;
+        }
+    }
+
+    let signatures = Signatures::new(modules.iter().flatten().map(|(func, _)| func));
+    check_types(files, &modules, &signatures, &reporter)?;
+    for func in check_regexes(files, &modules, &reporter)? {
+        compile_builder = compile_builder.add_func(func);
+    }
+
+    for (file, funcs) in files.iter().zip(&modules) {
+        for (func, span) in funcs {
+            match build_typed_func_ir(func, &signatures, options.checked) {
+                Ok(mut instructions) => {
+                    // println!("{:#?}", instructions);
+                    // 生成時の検査のエラーは関数の位置を指す
+                    let span = SourceSpan::new(span.into_range()).in_file(file.name);
+                    instructions.set_span(Some(span));
+                    compile_builder = compile_builder.add_func(instructions)
+                }
+                Err(e) => return Err(e.in_file(file.name)),
+            }
         }
     }
+    Ok(compile_builder)
 }
 
 #[cfg(test)]
@@ -443,7 +1108,7 @@ pub fn mul a:bit32, b:bit32 -> bit32, bit32 {
 }
 "#;
 
-        let (tokens, err) = lexer_parse(code);
+        let (tokens, _err) = lexer_parse(code);
 
         match tokens {
             Some(tokens) => {
@@ -506,7 +1171,7 @@ pub fn mul a:bit32, b:bit32 -> bit32, bit32 {
 }
 "#;
 
-        let (tokens, err) = lexer_parse(code);
+        let (tokens, _err) = lexer_parse(code);
 
         match tokens {
             Some(tokens) => {
@@ -515,10 +1180,9 @@ pub fn mul a:bit32, b:bit32 -> bit32, bit32 {
                 match parse_result {
                     Ok(a) => {
                         for (func, _) in a {
-                            let locals_name_dir = create_local_name_registry(&func.body.0)
+                            create_local_name_registry(&func.body.0)
                                 .expect("ローカル変数の構成に失敗");
-                            let args_name_dir =
-                                create_arg_name_registry(&func).expect("引数の構成に失敗");
+                            create_arg_name_registry(&func).expect("引数の構成に失敗");
 
                             match build_func_ir(&func) {
                                 Ok(instructions) => {
@@ -588,4 +1252,64 @@ pub fn mul a:bit32, b:bit32 -> bit32, bit32 {
             }
         }
     }
+
+    #[test]
+    fn visibility_test00() {
+        use super::{FrontendOptions, SourceFile, compiler_frontend_files};
+
+        let main = r#"
+pub fn entry a:bit32 -> bit32 {
+    return double(a);
+}
+"#;
+        let private_lib = r#"
+fn double a:bit32 -> bit32 {
+    sed ${
+        "s/\\(~[01]*\\)/\\10;/"
+    }$
+}
+"#;
+        let public_lib = r#"
+pub fn double a:bit32 -> bit32 {
+    sed ${
+        "s/\\(~[01]*\\)/\\10;/"
+    }$
+}
+"#;
+        let files = |lib| {
+            [
+                SourceFile {
+                    name: "main.soil",
+                    code: main,
+                },
+                SourceFile {
+                    name: "lib.soil",
+                    code: lib,
+                },
+            ]
+        };
+        let options = FrontendOptions::default();
+
+        // 他のモジュールの非pub関数は呼び出せない
+        match compiler_frontend_files(&files(private_lib), &options) {
+            Err(err) => assert_eq!(err.note, "aborting due to 1 visibility error(s)"),
+            Ok(_) => panic!("private function must not be callable"),
+        }
+
+        // pub関数なら呼び出せる
+        assert!(compiler_frontend_files(&files(public_lib), &options).is_ok());
+
+        // 同じ名前の関数を複数のモジュールで定義できない
+        let files = [
+            SourceFile {
+                name: "a.soil",
+                code: public_lib,
+            },
+            SourceFile {
+                name: "b.soil",
+                code: public_lib,
+            },
+        ];
+        assert!(compiler_frontend_files(&files, &options).is_err());
+    }
 }
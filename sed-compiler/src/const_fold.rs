@@ -0,0 +1,408 @@
+//! 定数の畳み込み
+//! 引数がすべて定数の呼び出しをコンパイル時に評価し、返り値の定数を積む命令で置き換える
+//!
+//! - 演算子の組み込み関数は、数を2進数で表す場合にRustで同じ計算をする
+//! - soilで書かれた関数はIRを解釈して評価する 生のsedに行き当たった場合は評価をやめる
+//! - `const fn`は評価できる命令の数の上限が大きい
+//!
+//! 桁あふれや0による除算で実行を中断するchecked演算は、畳み込まずに実行時に任せる
+
+use std::collections::{HashMap, HashSet};
+
+use crate::code_gen::{ConstVal, FuncDef, IfProc, NumberRepr, SedInstruction, Value};
+
+/// 一つの呼び出しを評価するときに実行できる命令の数の上限
+pub const EVAL_FUEL: usize = 1_000;
+
+/// `const fn`の呼び出しを評価するときに実行できる命令の数の上限
+pub const CONST_FN_FUEL: usize = 100_000;
+
+/// 評価中の呼び出しの深さの上限
+pub const EVAL_DEPTH: usize = 200;
+
+/// すべての関数で引数が定数の呼び出しを評価し、評価できた呼び出しの数を返す
+/// builtinsはリンクした組み込み関数の名前で、同じ名前の利用者の関数は計算を置き換えない
+pub(crate) fn fold_const_calls(
+    func_table: &mut [FuncDef],
+    builtins: &[String],
+    number_repr: NumberRepr,
+) -> usize {
+    let originals = func_table.to_vec();
+    let natives: HashSet<&str> = match number_repr {
+        NumberRepr::Binary => builtins.iter().map(|name| name.as_str()).collect(),
+        NumberRepr::Decimal => HashSet::new(),
+    };
+    let mut evaluator = Evaluator {
+        funcs: originals.iter().map(|f| (f.name(), f)).collect(),
+        natives,
+        fuel: 0,
+        depth: 0,
+    };
+    let mut count = 0;
+    for func_def in func_table.iter_mut() {
+        let before = count;
+        let proc_contents = evaluator.fold_block(func_def.proc_contents(), &mut count);
+        if count == before {
+            continue;
+        }
+        let mut folded = FuncDef::new(
+            func_def.name(),
+            func_def.argc(),
+            func_def.localc(),
+            func_def.retc(),
+        );
+        folded.set_public(func_def.is_public());
+        folded.set_inline(func_def.is_inline());
+        folded.set_const(func_def.is_const());
+        folded.set_span(func_def.span().cloned());
+        folded.set_proc_contents(proc_contents);
+        *func_def = folded;
+    }
+    count
+}
+
+struct Evaluator<'a> {
+    funcs: HashMap<&'a str, &'a FuncDef>,
+    /// Rustで計算する組み込み関数
+    natives: HashSet<&'a str>,
+    /// 評価中の呼び出しで残りいくつの命令を実行できるか
+    fuel: usize,
+    depth: usize,
+}
+
+/// 関数の本体を実行するときの値
+struct Frame {
+    args: Vec<ConstVal>,
+    locals: Vec<ConstVal>,
+    stack: Vec<ConstVal>,
+}
+
+/// 命令列を実行し終えたときの状態
+enum Flow {
+    Continue,
+    Return,
+}
+
+impl Evaluator<'_> {
+    /// 命令列の中の、直前に引数の定数が並んでいる呼び出しを評価する
+    fn fold_block(
+        &mut self,
+        proc_contents: &[SedInstruction],
+        count: &mut usize,
+    ) -> Vec<SedInstruction> {
+        let mut out: Vec<SedInstruction> = Vec::new();
+        for instruction in proc_contents {
+            match instruction {
+                SedInstruction::Call(call_func) => {
+                    if let Some(results) = self.fold_call(call_func.func_name(), &out) {
+                        let argc = self.funcs[call_func.func_name()].argc();
+                        out.truncate(out.len() - argc);
+                        out.extend(results.into_iter().map(SedInstruction::ConstVal));
+                        *count += 1;
+                        continue;
+                    }
+                }
+                SedInstruction::IfProc(if_proc) => {
+                    let then_proc = self.fold_block(if_proc.then_proc(), count);
+                    let else_proc = self.fold_block(if_proc.else_proc(), count);
+                    out.push(SedInstruction::IfProc(IfProc::new(then_proc, else_proc)));
+                    continue;
+                }
+                _ => {}
+            }
+            out.push(instruction.clone());
+        }
+        out
+    }
+
+    /// 引数がすべて直前の定数の場合に呼び出しを評価する
+    fn fold_call(&mut self, name: &str, out: &[SedInstruction]) -> Option<Vec<ConstVal>> {
+        let func_def = *self.funcs.get(name)?;
+        let start = out.len().checked_sub(func_def.argc())?;
+        let args = out[start..]
+            .iter()
+            .map(|instruction| match instruction {
+                SedInstruction::ConstVal(val) => Some(val.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        self.fuel = if func_def.is_const() {
+            CONST_FN_FUEL
+        } else {
+            EVAL_FUEL
+        };
+        self.call(name, args)
+    }
+
+    fn call(&mut self, name: &str, args: Vec<ConstVal>) -> Option<Vec<ConstVal>> {
+        if self.natives.contains(name)
+            && let Some(results) = eval_builtin(name, &args)
+        {
+            return Some(results);
+        }
+        let func_def = *self.funcs.get(name)?;
+        if self.depth >= EVAL_DEPTH {
+            return None;
+        }
+        let mut frame = Frame {
+            args,
+            locals: vec![ConstVal::new("init"); func_def.localc()],
+            stack: Vec::new(),
+        };
+        self.depth += 1;
+        let flow = self.run(func_def.proc_contents(), &mut frame);
+        self.depth -= 1;
+        match flow? {
+            Flow::Return => {
+                let start = frame.stack.len().checked_sub(func_def.retc())?;
+                Some(frame.stack.split_off(start))
+            }
+            // returnせずに関数の終わりに達した場合は評価しない
+            Flow::Continue => None,
+        }
+    }
+
+    /// 命令列を実行する 生のsedに行き当たるか命令の数の上限に達した場合はNone
+    fn run(&mut self, proc_contents: &[SedInstruction], frame: &mut Frame) -> Option<Flow> {
+        for instruction in proc_contents {
+            self.fuel = self.fuel.checked_sub(1)?;
+            match instruction {
+                SedInstruction::Sed(_) | SedInstruction::InlineSed(_) => return None,
+                SedInstruction::ConstVal(val) => frame.stack.push(val.clone()),
+                SedInstruction::Val(value) => {
+                    let val = match value {
+                        Value::Arg(i) => frame.args.get(*i)?,
+                        Value::Local(i) => frame.locals.get(*i)?,
+                    };
+                    frame.stack.push(val.clone());
+                }
+                SedInstruction::Set(value) => {
+                    let val = frame.stack.pop()?;
+                    match value {
+                        Value::Arg(i) => *frame.args.get_mut(*i)? = val,
+                        Value::Local(i) => *frame.locals.get_mut(*i)? = val,
+                    }
+                }
+                SedInstruction::Call(call_func) | SedInstruction::TailCall(call_func) => {
+                    let argc = self.funcs.get(call_func.func_name())?.argc();
+                    let start = frame.stack.len().checked_sub(argc)?;
+                    let args = frame.stack.split_off(start);
+                    let results = self.call(call_func.func_name(), args)?;
+                    frame.stack.extend(results);
+                    if matches!(instruction, SedInstruction::TailCall(_)) {
+                        return Some(Flow::Return);
+                    }
+                }
+                SedInstruction::Ret => return Some(Flow::Return),
+                SedInstruction::IfProc(if_proc) => {
+                    let cond = frame.stack.pop()?;
+                    let branch = if is_false(&cond) {
+                        if_proc.else_proc()
+                    } else {
+                        if_proc.then_proc()
+                    };
+                    if let Flow::Return = self.run(branch, frame)? {
+                        return Some(Flow::Return);
+                    }
+                }
+            }
+        }
+        Some(Flow::Continue)
+    }
+}
+
+/// ifの条件が偽になる値かどうか 生成するsedと同じく、1文字以上の0だけからなる値を偽とする
+fn is_false(val: &ConstVal) -> bool {
+    !val.data().is_empty() && val.data().bytes().all(|b| b == b'0')
+}
+
+/// width桁以下の2進数の定数の値
+fn bits(val: &ConstVal, width: u32) -> Option<u128> {
+    let data = val.data();
+    if data.is_empty()
+        || data.len() > width as usize
+        || !data.bytes().all(|b| b == b'0' || b == b'1')
+    {
+        return None;
+    }
+    u128::from_str_radix(data, 2).ok()
+}
+
+/// 演算子の組み込み関数をRustで計算する
+/// 知らない関数や、checked演算が実行を中断する場合はNone
+fn eval_builtin(name: &str, args: &[ConstVal]) -> Option<Vec<ConstVal>> {
+    let width: u32 = if name.ends_with("64") { 64 } else { 32 };
+    let [a, b] = args else {
+        return None;
+    };
+    let (a, b) = (bits(a, width)?, bits(b, width)?);
+    let mask = (1u128 << width) - 1;
+    let signed = |x: u128| {
+        let x = x as i128;
+        if x >> (width - 1) == 0 {
+            x
+        } else {
+            x - (1i128 << width)
+        }
+    };
+    let (sa, sb) = (signed(a), signed(b));
+    let fits = |x: i128| -(1i128 << (width - 1)) <= x && x < (1i128 << (width - 1));
+    let truth = match name {
+        "eq" | "eq64" => Some(a == b),
+        "neq" | "neq64" => Some(a != b),
+        "lt_u32" | "lt_u64" => Some(a < b),
+        "le_u32" | "le_u64" => Some(a <= b),
+        "gt_u32" | "gt_u64" => Some(a > b),
+        "ge_u32" | "ge_u64" => Some(a >= b),
+        "lt_i32" | "lt_i64" => Some(sa < sb),
+        "le_i32" | "le_i64" => Some(sa <= sb),
+        "gt_i32" | "gt_i64" => Some(sa > sb),
+        "ge_i32" | "ge_i64" => Some(sa >= sb),
+        _ => None,
+    };
+    if let Some(truth) = truth {
+        return Some(vec![ConstVal::new(if truth { "1" } else { "0" })]);
+    }
+    let (a, b) = (a as i128, b as i128);
+    let value = match name {
+        "add" | "add64" => a + b,
+        "sub32" | "sub64" => a - b,
+        "mul32" | "mul64" => a * b,
+        // 0で割った場合、商はすべてのbitが1、余りは割られる数になる
+        "div" | "div_u64" if b == 0 => -1,
+        "div" | "div_u64" => a / b,
+        "mod" | "mod_u64" if b == 0 => a,
+        "mod" | "mod_u64" => a % b,
+        "div_i32" | "div_i64" if sb == 0 => -1,
+        "div_i32" | "div_i64" => sa / sb,
+        "mod_i32" | "mod_i64" if sb == 0 => sa,
+        "mod_i32" | "mod_i64" => sa % sb,
+        "add_checked_u32" | "add_checked_u64" => Some(a + b).filter(|x| *x <= mask as i128)?,
+        "sub_checked_u32" | "sub_checked_u64" => Some(a - b).filter(|x| *x >= 0)?,
+        "mul_checked_u32" | "mul_checked_u64" => Some(a * b).filter(|x| *x <= mask as i128)?,
+        "div_checked_u32" | "div_checked_u64" if b != 0 => a / b,
+        "mod_checked_u32" | "mod_checked_u64" if b != 0 => a % b,
+        "add_checked_i32" | "add_checked_i64" => Some(sa + sb).filter(|x| fits(*x))?,
+        "sub_checked_i32" | "sub_checked_i64" => Some(sa - sb).filter(|x| fits(*x))?,
+        "mul_checked_i32" | "mul_checked_i64" => Some(sa * sb).filter(|x| fits(*x))?,
+        "div_checked_i32" | "div_checked_i64" if sb != 0 && fits(sa / sb) => sa / sb,
+        "mod_checked_i32" | "mod_checked_i64" if sb != 0 && fits(sa / sb) => sa % sb,
+        _ => return None,
+    };
+    Some(vec![ConstVal::int(
+        ((value as u128) & mask) as u64,
+        width as usize,
+    )])
+}
+
+#[cfg(test)]
+mod const_fold_test {
+    use super::*;
+    use crate::compiler::compiler_frontend;
+
+    fn int(value: u64, width: usize) -> ConstVal {
+        ConstVal::int(value, width)
+    }
+
+    #[test]
+    fn const_fold_test00() {
+        // 組み込み関数は生成するsedと同じく桁あふれを切り捨てる
+        let eval = |name: &str, a: ConstVal, b: ConstVal| {
+            eval_builtin(name, &[a, b]).map(|results| results[0].data().to_string())
+        };
+        assert_eq!(
+            eval("add", int(u32::MAX as u64, 32), int(2, 32)),
+            Some(format!("{:032b}", 1))
+        );
+        assert_eq!(
+            eval("sub32", int(0, 32), int(1, 32)),
+            Some(format!("{:032b}", u32::MAX))
+        );
+        assert_eq!(
+            eval("div", int(7, 32), int(0, 32)),
+            Some(format!("{:032b}", u32::MAX))
+        );
+        assert_eq!(
+            eval("mod_u64", int(7, 64), int(0, 64)),
+            Some(format!("{:064b}", 7))
+        );
+        let min = int(i32::MIN as u32 as u64, 32);
+        let minus_one = int(u32::MAX as u64, 32);
+        assert_eq!(
+            eval("div_i32", min.clone(), minus_one.clone()),
+            Some(min.data().to_string())
+        );
+        assert_eq!(
+            eval("lt_i32", minus_one.clone(), int(0, 32)),
+            Some("1".to_string())
+        );
+        assert_eq!(
+            eval("lt_u32", minus_one.clone(), int(0, 32)),
+            Some("0".to_string())
+        );
+        // 幅の違う値はそろえて比べる
+        assert_eq!(
+            eval("eq", ConstVal::new("1"), int(1, 32)),
+            Some("1".to_string())
+        );
+        // 実行時に中断するchecked演算は評価しない
+        assert_eq!(eval("add_checked_u32", minus_one.clone(), int(1, 32)), None);
+        assert_eq!(eval("div_checked_i32", min, minus_one), None);
+        assert_eq!(eval("mod_checked_u64", int(1, 64), int(0, 64)), None);
+        assert_eq!(
+            eval("sub_checked_i64", int(1, 64), int(2, 64)),
+            Some(format!("{:064b}", u64::MAX))
+        );
+        // 2進数でない値は評価しない
+        assert_eq!(eval("add", ConstVal::str("a"), int(1, 32)), None);
+    }
+
+    #[test]
+    fn const_fold_test01() {
+        // soilの関数はIRを解釈して評価し、生のsedを含む関数は評価しない
+        let code = r#"
+pub fn entry a:u32 -> u32, u32, u32 {
+    return fact(5), shl(3), fact(a);
+}
+
+const fn fact n:u32 -> u32 {
+    if n == 0 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+fn shl a:u32 -> u32 {
+    sed ${
+        "s/\\(~[01]*\\)$/\\10;/",
+    }$
+}
+"#;
+        let ir = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .assemble()
+            .dump_ir();
+        assert!(ir.contains("push.int 120 32"));
+        assert!(ir.contains("call shl"));
+        assert!(ir.contains("call fact"));
+
+        let ir = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .const_fold(false)
+            .assemble()
+            .dump_ir();
+        assert!(!ir.contains("push.int 120 32"));
+
+        // 生のsedを書いたconst fnはエラーになる
+        let code = r#"
+const fn shl a:u32 -> u32 {
+    sed ${
+        "s/\\(~[01]*\\)$/\\10;/",
+    }$
+}
+"#;
+        assert!(compiler_frontend(code).is_err());
+    }
+}
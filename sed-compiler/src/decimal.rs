@@ -0,0 +1,139 @@
+//! 10進数の表現で使う組み込み関数のうち、一桁ごとの対応表をもつもの
+//! 対応表はsの置換として生成する
+//! 引数は先頭に余分な0のない10進数であることを前提とする
+
+use crate::code_gen::*;
+
+/// 二つの数の和 桁数の制限はない
+pub fn dec_add_raw() -> FuncDef {
+    let mut func = FuncDef::new("__dec_add_raw", 2, 0, 1);
+    let mut lines = vec![
+        // `a;b;繰り上がり;結果`の形式で下の桁から計算する
+        "s/^~\\([0-9]*\\)~\\([0-9]*\\)$/\\1;\\2;0;/".to_string(),
+        ":__prelude_dec_add_loop".to_string(),
+        // 桁数の少ない方に0を補う
+        "s/^;\\([0-9]\\)/0;\\1/".to_string(),
+        "s/^\\([0-9][0-9]*\\);;/\\1;0;/".to_string(),
+        "s/^\\([0-9]*\\)\\([0-9]\\);\\([0-9]*\\)\\([0-9]\\);\\([01]\\);/\\1;\\3;\\2\\4\\5;/"
+            .to_string(),
+    ];
+    for x in 0..10 {
+        for y in 0..10 {
+            for c in 0..2 {
+                let sum = x + y + c;
+                lines.push(format!(
+                    "s/^\\([0-9]*;[0-9]*;\\){}{}{};/\\1{};{}/",
+                    x,
+                    y,
+                    c,
+                    sum / 10,
+                    sum % 10
+                ));
+            }
+        }
+    }
+    lines.extend([
+        "/^;;/!b __prelude_dec_add_loop".to_string(),
+        "s/^;;0;\\([0-9]*\\)$/~\\1;/".to_string(),
+        "s/^;;1;\\([0-9]*\\)$/~1\\1;/".to_string(),
+    ]);
+    func.set_proc_contents(sed_lines(lines));
+    func
+}
+
+/// 二つの数の差 aがb以上であることを前提とする
+pub fn dec_sub_raw() -> FuncDef {
+    let mut func = FuncDef::new("__dec_sub_raw", 2, 0, 1);
+    let mut lines = vec![
+        // `a;b;繰り下がり;結果`の形式で下の桁から計算する
+        "s/^~\\([0-9]*\\)~\\([0-9]*\\)$/\\1;\\2;0;/".to_string(),
+        ":__prelude_dec_sub_loop".to_string(),
+        "s/^\\([0-9][0-9]*\\);;/\\1;0;/".to_string(),
+        "s/^\\([0-9]*\\)\\([0-9]\\);\\([0-9]*\\)\\([0-9]\\);\\([01]\\);/\\1;\\3;\\2\\4\\5;/"
+            .to_string(),
+    ];
+    for x in 0..10 {
+        for y in 0..10 {
+            for c in 0..2 {
+                let diff = x - y - c;
+                let (borrow, digit) = if diff < 0 { (1, diff + 10) } else { (0, diff) };
+                lines.push(format!(
+                    "s/^\\([0-9]*;[0-9]*;\\){}{}{};/\\1{};{}/",
+                    x, y, c, borrow, digit
+                ));
+            }
+        }
+    }
+    lines.extend([
+        "/^;;/!b __prelude_dec_sub_loop".to_string(),
+        "s/^;;0;\\([0-9]*\\)$/~\\1;/".to_string(),
+        "s/^~0*\\([0-9]\\)/~\\1/".to_string(),
+    ]);
+    func.set_proc_contents(sed_lines(lines));
+    func
+}
+
+/// aがb以上かどうか
+pub fn dec_geq() -> FuncDef {
+    let mut func = FuncDef::new("__dec_geq", 2, 0, 1);
+    let mut lines = vec![
+        // 桁数を比べるために写しを作り、一桁ずつ取り除く
+        "s/^~\\([0-9]*\\)~\\([0-9]*\\)$/\\1;\\2|\\1;\\2/".to_string(),
+        ":__prelude_dec_geq_len".to_string(),
+        "s/^[0-9]\\([0-9]*;\\)[0-9]/\\1/".to_string(),
+        "t __prelude_dec_geq_len".to_string(),
+        "s/^[0-9][0-9]*;|.*$/~1;/".to_string(),
+        "s/^;[0-9][0-9]*|.*$/~0;/".to_string(),
+        // 桁数が同じ場合は上の桁から比べる
+        "s/^;|//".to_string(),
+        ":__prelude_dec_geq_loop".to_string(),
+        "s/^\\([0-9]\\)\\([0-9]*\\);\\1\\([0-9]*\\)$/\\2;\\3/".to_string(),
+        "t __prelude_dec_geq_loop".to_string(),
+        "s/^;$/~1;/".to_string(),
+    ];
+    for x in 1..10 {
+        lines.push(format!("s/^{}[0-9]*;[0-{}][0-9]*$/~1;/", x, x - 1));
+    }
+    lines.push("s/^[0-9]*;[0-9]*$/~0;/".to_string());
+    func.set_proc_contents(sed_lines(lines));
+    func
+}
+
+/// 対応表をもつすべての関数
+pub fn lookup_funcs() -> Vec<FuncDef> {
+    vec![dec_add_raw(), dec_sub_raw(), dec_geq()]
+}
+
+fn sed_lines(lines: Vec<String>) -> Vec<SedInstruction> {
+    lines
+        .into_iter()
+        .map(|line| SedInstruction::Sed(SedCode(line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod decimal_test {
+    use super::*;
+    use crate::embedded::BuiltinRegistry;
+    use crate::verifier::verify_func_table;
+
+    #[test]
+    fn decimal_test00() {
+        // 演算子の関数をリンクすると対応表をもつ関数も追加される
+        let mut entry = FuncDef::new("entry", 2, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::Val(Value::Arg(1)),
+            SedInstruction::Call(CallFunc::new("add")),
+            SedInstruction::Ret,
+        ]);
+        let mut func_table = vec![entry];
+        let linked = BuiltinRegistry::decimal().link(&mut func_table);
+        assert!(linked.contains(&"__dec_add_raw".to_string()));
+        assert!(linked.contains(&"__dec_geq".to_string()));
+        assert_eq!(verify_func_table(&func_table), Ok(()));
+
+        // 2進数の組み込み関数は含まれない
+        assert!(BuiltinRegistry::decimal().get("shift_left1").is_none());
+    }
+}
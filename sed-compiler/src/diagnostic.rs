@@ -0,0 +1,301 @@
+use std::ops::Range;
+
+use ariadne::{Color, Label, Report, ReportKind};
+
+/// ファイル名が指定されていない場合に表示される名前
+pub const UNKNOWN_FILE: &str = "<unknown>";
+
+/// 診断の重大度
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// 診断の出力形式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// ariadneによる人間向けの表示
+    #[default]
+    Human,
+    /// 一行に一つのJSONオブジェクト
+    Json,
+}
+
+/// IRやエラーが持つソース上の位置
+/// ファイル名はin_fileで後から設定できる
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub file: String,
+    pub span: Range<usize>,
+}
+
+impl SourceSpan {
+    pub fn new(span: Range<usize>) -> Self {
+        Self {
+            file: UNKNOWN_FILE.to_string(),
+            span,
+        }
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+}
+
+/// 診断に付け加える別の位置
+/// 別のファイルを指してもよい
+#[derive(Clone, Debug)]
+pub struct RelatedSpan {
+    pub file: String,
+    pub span: Range<usize>,
+    pub label: String,
+}
+
+/// コンパイラが利用者に報告する診断情報
+/// エラー、警告のどちらもこの形に変換してから表示する
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: String,
+    pub message: String,
+    /// spanが指すファイルの名前
+    pub file: String,
+    /// ソース上の位置(バイトオフセット)
+    /// ソース上の位置を持たないエラーの場合はNone
+    pub span: Option<Range<usize>>,
+    pub label: Option<String>,
+    pub related: Vec<RelatedSpan>,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &str, message: &str, span: Range<usize>) -> Self {
+        Self {
+            severity,
+            code: code.to_string(),
+            message: message.to_string(),
+            file: UNKNOWN_FILE.to_string(),
+            span: Some(span),
+            label: None,
+            related: Vec::new(),
+            note: None,
+        }
+    }
+
+    /// ソース上の位置を持たない診断を作る
+    pub fn without_span(severity: Severity, code: &str, message: &str) -> Self {
+        Self {
+            span: None,
+            ..Self::new(severity, code, message, 0..0)
+        }
+    }
+
+    /// 位置が分かればその位置の、分からなければ位置を持たない診断を作る
+    pub fn at(severity: Severity, code: &str, message: &str, span: Option<&SourceSpan>) -> Self {
+        match span {
+            Some(span) => Self::new(severity, code, message, span.span.clone()).in_file(&span.file),
+            None => Self::without_span(severity, code, message),
+        }
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_related(mut self, file: &str, span: Range<usize>, label: &str) -> Self {
+        self.related.push(RelatedSpan {
+            file: file.to_string(),
+            span,
+            label: label.to_string(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.note = Some(note.to_string());
+        self
+    }
+
+    /// 指定された形式で標準エラー出力に表示する
+    pub fn emit(&self, sources: &[(&str, &str)], format: ErrorFormat) {
+        match format {
+            ErrorFormat::Human => self.eprint_sources(sources),
+            ErrorFormat::Json => eprintln!("{}", self.to_json(sources)),
+        }
+    }
+
+    /// 複数のファイルにまたがる診断を表示する
+    /// `sources`はファイル名とその内容の組
+    pub fn eprint_sources(&self, sources: &[(&str, &str)]) {
+        let (kind, color) = match self.severity {
+            Severity::Error => (ReportKind::Error, Color::Red),
+            Severity::Warning => (ReportKind::Warning, Color::Yellow),
+        };
+        let Some(span) = &self.span else {
+            eprintln!("{}[{}]: {}", self.severity.name(), self.code, self.message);
+            if let Some(note) = &self.note {
+                eprintln!("  = note: {}", note);
+            }
+            return;
+        };
+        let mut label = Label::new((self.file.clone(), span.clone())).with_color(color);
+        if let Some(message) = &self.label {
+            label = label.with_message(message);
+        }
+        let mut report = Report::build(kind, (self.file.clone(), span.clone()))
+            .with_config(ariadne::Config::new().with_index_type(ariadne::IndexType::Byte))
+            .with_code(&self.code)
+            .with_message(&self.message)
+            .with_label(label);
+        for related in &self.related {
+            report = report.with_label(
+                Label::new((related.file.clone(), related.span.clone()))
+                    .with_color(Color::Blue)
+                    .with_message(&related.label),
+            );
+        }
+        if let Some(note) = &self.note {
+            report = report.with_note(note);
+        }
+        let sources = sources
+            .iter()
+            .map(|(file, code)| (file.to_string(), code.to_string()));
+        report.finish().eprint(ariadne::sources(sources)).unwrap();
+    }
+}
+
+/// JSONの文字列リテラルにする
+fn json_string(s: &str) -> String {
+    let mut r = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c if (c as u32) < 0x20 => r.push_str(&format!("\\u{:04x}", c as u32)),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
+fn json_option(s: Option<&str>) -> String {
+    s.map(json_string).unwrap_or_else(|| "null".to_string())
+}
+
+/// バイトオフセットから1始まりの行と列(文字数)を求める
+fn line_column(code: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(code.len());
+    while !code.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &code[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+fn json_span(sources: &[(&str, &str)], file: &str, span: &Range<usize>) -> String {
+    let mut r = format!("{{\"start\":{},\"end\":{}", span.start, span.end);
+    if let Some((_, code)) = sources.iter().find(|(name, _)| *name == file) {
+        let (line_start, column_start) = line_column(code, span.start);
+        let (line_end, column_end) = line_column(code, span.end);
+        r.push_str(&format!(
+            ",\"line_start\":{},\"column_start\":{},\"line_end\":{},\"column_end\":{}",
+            line_start, column_start, line_end, column_end
+        ));
+    }
+    r.push('}');
+    r
+}
+
+impl Diagnostic {
+    /// 一行のJSONオブジェクトにする
+    /// 行と列は`sources`からspanが指すファイルを探して求める
+    pub fn to_json(&self, sources: &[(&str, &str)]) -> String {
+        let mut labels = Vec::new();
+        if let Some(span) = &self.span {
+            labels.push(format!(
+                "{{\"file\":{},\"span\":{},\"message\":{},\"primary\":true}}",
+                json_string(&self.file),
+                json_span(sources, &self.file, span),
+                json_option(self.label.as_deref())
+            ));
+        }
+        for related in &self.related {
+            labels.push(format!(
+                "{{\"file\":{},\"span\":{},\"message\":{},\"primary\":false}}",
+                json_string(&related.file),
+                json_span(sources, &related.file, &related.span),
+                json_string(&related.label)
+            ));
+        }
+        let (file, span) = match &self.span {
+            Some(span) => (json_string(&self.file), json_span(sources, &self.file, span)),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        format!(
+            "{{\"file\":{},\"span\":{},\"severity\":{},\"code\":{},\"message\":{},\"labels\":[{}],\"note\":{}}}",
+            file,
+            span,
+            json_string(self.severity.name()),
+            json_string(&self.code),
+            json_string(&self.message),
+            labels.join(","),
+            json_option(self.note.as_deref())
+        )
+    }
+}
+
+#[cfg(test)]
+mod diagnostic_test {
+    use super::*;
+
+    #[test]
+    fn to_json_test00() {
+        let code = "fn f -> bit32 {\n    return \"a\";\n}\n";
+        let diagnostic = Diagnostic::new(Severity::Error, "5", "bad \"value\"", 27..30)
+            .in_file("a.soil")
+            .with_label("here")
+            .with_related("b.soil", 0..2, "defined")
+            .with_note("note");
+        let json = diagnostic.to_json(&[("a.soil", code)]);
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"file":"a.soil","span":{"start":27,"end":30,"line_start":2,"column_start":12,"line_end":2,"column_end":15},"#,
+                r#""severity":"error","code":"5","message":"bad \"value\"","#,
+                r#""labels":[{"file":"a.soil","span":{"start":27,"end":30,"line_start":2,"column_start":12,"line_end":2,"column_end":15},"message":"here","primary":true},"#,
+                r#"{"file":"b.soil","span":{"start":0,"end":2},"message":"defined","primary":false}],"#,
+                r#""note":"note"}"#
+            )
+        );
+
+        let json = Diagnostic::without_span(Severity::Error, "7", "a\nb").to_json(&[]);
+        assert_eq!(
+            json,
+            r#"{"file":null,"span":null,"severity":"error","code":"7","message":"a\nb","labels":[],"note":null}"#
+        );
+    }
+}
@@ -1,4 +1,166 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::OnceLock;
+
 use crate::code_gen::*;
+use crate::prelude::{decimal_prelude_funcs, prelude_funcs};
+
+/// 名前で参照できる組み込み関数
+#[derive(Clone, Debug)]
+pub struct Builtin {
+    def: FuncDef,
+    /// この関数が呼び出す関数の名前(自分自身は含まない)
+    deps: Vec<String>,
+}
+
+impl Builtin {
+    pub fn def(&self) -> &FuncDef {
+        &self.def
+    }
+
+    pub fn deps(&self) -> &[String] {
+        &self.deps
+    }
+}
+
+/// 組み込み関数の名前から定義と依存関係を引く表
+#[derive(Clone, Debug, Default)]
+pub struct BuiltinRegistry {
+    builtins: BTreeMap<String, Builtin>,
+}
+
+impl BuiltinRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 関数を依存関係とともに登録する
+    /// 同じ名前の関数がすでに登録されている場合は何もせずfalseを返す
+    pub fn register(&mut self, def: FuncDef, deps: &[&str]) -> bool {
+        if self.builtins.contains_key(def.name()) {
+            return false;
+        }
+        let deps = deps.iter().map(|dep| dep.to_string()).collect();
+        self.builtins
+            .insert(def.name().to_string(), Builtin { def, deps });
+        true
+    }
+
+    /// 命令列から依存関係を求めて関数を登録する
+    pub fn register_func(&mut self, def: FuncDef) -> bool {
+        let mut called = Vec::new();
+        collect_called_funcs(def.proc_contents(), &mut called);
+        let mut seen = HashSet::new();
+        let deps: Vec<String> = called
+            .into_iter()
+            .filter(|name| *name != def.name() && seen.insert(*name))
+            .map(|name| name.to_string())
+            .collect();
+        let deps: Vec<&str> = deps.iter().map(|dep| dep.as_str()).collect();
+        self.register(def, &deps)
+    }
+
+    /// このファイルで定義されている組み込み関数の表
+    pub fn embedded() -> Self {
+        let mut registry = Self::new();
+        registry.register(em_shift_left1(), &[]);
+        registry.register(em_shift_right1(), &[]);
+        registry.register(em_is_empty(), &[]);
+        registry.register(em_ends_with_zero(), &[]);
+        registry.register(
+            em_mul(),
+            &[
+                "is_empty",
+                "ends_with_zero",
+                "shift_left1",
+                "shift_right1",
+                "add",
+            ],
+        );
+        registry.register(em_add(), &[]);
+        registry.register(em_sub32(), &["zero_padding32", "twos_complement", "add"]);
+        registry.register(em_twos_complement(), &[]);
+        registry.register(em_zero_padding32(), &[]);
+        registry
+    }
+
+    /// CompilerBuilderが標準で使う表
+    /// 同じ名前の関数はpreludeのものが優先される
+    pub fn standard() -> Self {
+        let mut registry = Self::new();
+        for def in prelude_funcs() {
+            registry.register_func(def);
+        }
+        for (_, builtin) in Self::embedded().builtins {
+            registry
+                .builtins
+                .entry(builtin.def.name().to_string())
+                .or_insert(builtin);
+        }
+        registry
+    }
+
+    /// 数を10進数で表す場合に使う表
+    /// 2進数を前提とするこのファイルの関数は含まない
+    pub fn decimal() -> Self {
+        let mut registry = Self::new();
+        for def in decimal_prelude_funcs() {
+            registry.register_func(def);
+        }
+        registry
+    }
+
+    /// 数の表現に合わせた標準の表
+    /// preludeの構文解析は数の表現ごとに一度だけ行い、以降は複製を返す
+    pub fn for_number_repr(number_repr: NumberRepr) -> Self {
+        static STANDARD: OnceLock<BuiltinRegistry> = OnceLock::new();
+        static DECIMAL: OnceLock<BuiltinRegistry> = OnceLock::new();
+        match number_repr {
+            NumberRepr::Binary => STANDARD.get_or_init(Self::standard).clone(),
+            NumberRepr::Decimal => DECIMAL.get_or_init(Self::decimal).clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Builtin> {
+        self.builtins.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.builtins.keys().map(|name| name.as_str())
+    }
+
+    /// テーブル内で呼び出されているが定義されていない関数を追加する
+    /// 追加した関数の依存関係もたどり、それぞれ一度だけ追加する
+    /// テーブルに定義されている関数は置き換えない
+    /// 追加した関数の名前を返す
+    pub fn link(&self, func_table: &mut Vec<FuncDef>) -> Vec<String> {
+        let mut defined: HashSet<String> =
+            func_table.iter().map(|f| f.name().to_string()).collect();
+        let mut called = Vec::new();
+        for func_def in func_table.iter() {
+            collect_called_funcs(func_def.proc_contents(), &mut called);
+        }
+        let mut queue: Vec<String> = called
+            .into_iter()
+            .rev()
+            .map(|name| name.to_string())
+            .collect();
+        let mut linked = Vec::new();
+        while let Some(name) = queue.pop() {
+            if defined.contains(&name) {
+                continue;
+            }
+            // 組み込み関数でもない場合は検査でエラーになる
+            let Some(builtin) = self.builtins.get(&name) else {
+                continue;
+            };
+            defined.insert(name.clone());
+            func_table.push(builtin.def.clone());
+            queue.extend(builtin.deps.iter().rev().cloned());
+            linked.push(name);
+        }
+        linked
+    }
+}
 
 pub fn em_shift_left1() -> FuncDef {
     let mut func_shift_left1 = FuncDef::new("shift_left1", 1, 0, 1);
@@ -36,12 +198,7 @@ pub fn em_ends_with_zero() -> FuncDef {
     func_ends_with_zero
 }
 
-/// you need to define
-/// - shift_left1
-/// - is_empty
-/// - ends_with_zero
-/// - shift_left1
-/// - shift_right1
+/// 依存する関数はBuiltinRegistry::embeddedに記録されている
 pub fn em_mul() -> FuncDef {
     let mut func_mul = FuncDef::new("mul", 2, 1, 1);
     func_mul.set_proc_contents(vec![
@@ -114,10 +271,7 @@ pub fn em_add() -> FuncDef {
     func_add
 }
 
-/// you need to define
-/// - twos_complement
-/// - zero_padding32
-/// - add
+/// 依存する関数はBuiltinRegistry::embeddedに記録されている
 pub fn em_sub32() -> FuncDef {
     let mut func_sub32 = FuncDef::new("sub32", 2, 0, 1);
     func_sub32.set_proc_contents(vec![
@@ -169,3 +323,85 @@ s/.*\\(................................\\)$/~\\1;/
     ))]);
     func_zero_padding32
 }
+
+#[cfg(test)]
+mod embedded_test {
+    use super::*;
+
+    #[test]
+    fn registry_test00() {
+        // 記録された依存関係は実際に呼び出している関数と一致する
+        let registry = BuiltinRegistry::embedded();
+        for name in registry.names() {
+            let builtin = registry.get(name).unwrap();
+            let mut called = Vec::new();
+            collect_called_funcs(builtin.def().proc_contents(), &mut called);
+            let called: HashSet<&str> = called.into_iter().filter(|c| *c != name).collect();
+            let deps: HashSet<&str> = builtin.deps().iter().map(|d| d.as_str()).collect();
+            assert_eq!(called, deps, "dependencies of `{}`", name);
+            for dep in deps {
+                assert!(registry.get(dep).is_some(), "`{}` is not registered", dep);
+            }
+        }
+    }
+
+    #[test]
+    fn registry_test01() {
+        // 呼び出しグラフに現れる関数だけを一度ずつ追加する
+        let mut entry = FuncDef::new("entry", 2, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::Val(Value::Arg(1)),
+            SedInstruction::Call(CallFunc::new("mul")),
+            SedInstruction::Val(Value::Arg(1)),
+            SedInstruction::Call(CallFunc::new("sub32")),
+            SedInstruction::Ret,
+        ]);
+        let mut func_table = vec![entry, em_shift_left1()];
+        let mut linked = BuiltinRegistry::embedded().link(&mut func_table);
+        linked.sort();
+        assert_eq!(
+            linked,
+            vec![
+                "add",
+                "ends_with_zero",
+                "is_empty",
+                "mul",
+                "shift_right1",
+                "sub32",
+                "twos_complement",
+                "zero_padding32",
+            ]
+        );
+        assert_eq!(func_table.len(), 10);
+    }
+
+    #[test]
+    fn registry_test02() {
+        // 標準の表ではpreludeの関数が優先される
+        let registry = BuiltinRegistry::standard();
+        assert!(
+            registry
+                .get("add")
+                .unwrap()
+                .deps()
+                .contains(&"__add_eq".to_string())
+        );
+        assert!(registry.get("mul").is_some());
+    }
+
+    #[test]
+    fn registry_test03() {
+        // 数の表現ごとの表は一度だけ作られ、返された表を変更しても次の結果に影響しない
+        let mut registry = BuiltinRegistry::for_number_repr(NumberRepr::Binary);
+        assert!(registry.register(FuncDef::new("extra", 0, 0, 1), &[]));
+        assert!(
+            BuiltinRegistry::for_number_repr(NumberRepr::Binary)
+                .get("extra")
+                .is_none()
+        );
+        let decimal = BuiltinRegistry::for_number_repr(NumberRepr::Decimal);
+        assert!(decimal.get("shift_left1").is_none());
+        assert!(decimal.get("add").is_some());
+    }
+}
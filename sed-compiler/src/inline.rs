@@ -0,0 +1,315 @@
+//! 関数のインライン展開
+//! 小さな関数と`#[inline]`の付いた関数の呼び出しを、呼び出し先の本体で置き換える
+//! 呼び出しのたびに行うフレームの退避とreturn dispatcherでの照合を省ける
+//!
+//! - soilで書かれた関数は、引数とローカル変数を呼び出し元のローカル変数に割り当て直して埋め込む
+//! - 生のsedだけで書かれた関数は、ラベルの名前を変えてInlineSedとして埋め込む
+//!
+//! 展開は一段だけで、展開した本体の中の呼び出しはそのまま残す
+
+use crate::code_gen::{FuncDef, IfProc, InlineSed, SedInstruction, Value};
+use crate::sed_label::rename_labels;
+
+/// `#[inline]`がなくても展開する関数の大きさの上限
+/// 大きさは命令の数で、生のsedは一行を一つと数える
+pub const INLINE_SIZE_LIMIT: usize = 8;
+
+/// 呼び出しを展開できる関数の呼び出しを、すべての関数で展開する
+/// 展開した呼び出しの数を返す
+pub(crate) fn inline_funcs(func_table: &mut [FuncDef]) -> usize {
+    let originals = func_table.to_vec();
+    let mut count = 0;
+    for func_def in func_table.iter_mut() {
+        // 生のsedはフレーム全体を扱うので、ローカル変数を増やせない
+        if contains_sed(func_def.proc_contents()) {
+            continue;
+        }
+        let mut inliner = Inliner {
+            caller: func_def.name(),
+            localc: func_def.localc(),
+            sites: 0,
+            originals: &originals,
+        };
+        let proc_contents = inliner.block(func_def.proc_contents());
+        if inliner.sites == 0 {
+            continue;
+        }
+        count += inliner.sites;
+        let mut inlined = FuncDef::new(
+            func_def.name(),
+            func_def.argc(),
+            inliner.localc,
+            func_def.retc(),
+        );
+        inlined.set_public(func_def.is_public());
+        inlined.set_inline(func_def.is_inline());
+        inlined.set_const(func_def.is_const());
+        inlined.set_span(func_def.span().cloned());
+        inlined.set_proc_contents(proc_contents);
+        *func_def = inlined;
+    }
+    count
+}
+
+struct Inliner<'a> {
+    caller: &'a str,
+    /// 展開した関数の引数とローカル変数を含めた、呼び出し元のローカル変数の個数
+    localc: usize,
+    /// 展開した呼び出しの数 sedのラベルを呼び出しごとに別の名前にするために使う
+    sites: usize,
+    originals: &'a [FuncDef],
+}
+
+impl Inliner<'_> {
+    fn block(&mut self, proc_contents: &[SedInstruction]) -> Vec<SedInstruction> {
+        let mut out = Vec::new();
+        for instruction in proc_contents {
+            match instruction {
+                SedInstruction::Call(call_func) => {
+                    match self
+                        .originals
+                        .iter()
+                        .find(|f| f.name() == call_func.func_name())
+                        .filter(|callee| can_inline(callee, self.caller))
+                    {
+                        Some(callee) => self.expand(callee, &mut out),
+                        None => out.push(instruction.clone()),
+                    }
+                }
+                SedInstruction::IfProc(if_proc) => {
+                    let then_proc = self.block(if_proc.then_proc());
+                    let else_proc = self.block(if_proc.else_proc());
+                    out.push(SedInstruction::IfProc(IfProc::new(then_proc, else_proc)));
+                }
+                _ => out.push(instruction.clone()),
+            }
+        }
+        out
+    }
+
+    fn expand(&mut self, callee: &FuncDef, out: &mut Vec<SedInstruction>) {
+        if is_sed_only(callee) {
+            let suffix = format!("__inline_{}_{}", self.caller, self.sites);
+            out.push(SedInstruction::InlineSed(inline_sed(callee, &suffix)));
+        } else {
+            let base = self.localc;
+            self.localc += callee.argc() + callee.localc();
+            // 引数は最後のものがスタックの先頭にある
+            for i in (0..callee.argc()).rev() {
+                out.push(SedInstruction::Set(Value::Local(base + i)));
+            }
+            out.extend(rebase(callee.proc_contents(), base, callee.argc()));
+        }
+        self.sites += 1;
+    }
+}
+
+/// 展開してよい関数かどうか
+/// soilの関数は最後の`Ret`か、末尾のifの両方の節の`Ret`でだけ返るものに限る
+fn can_inline(callee: &FuncDef, caller: &str) -> bool {
+    if callee.name() == caller || callee.proc_contents().is_empty() {
+        return false;
+    }
+    if !callee.is_inline() && INLINE_SIZE_LIMIT < size(callee.proc_contents()) {
+        return false;
+    }
+    is_sed_only(callee)
+        || (!contains_sed(callee.proc_contents()) && single_exit(callee.proc_contents()))
+}
+
+fn size(proc_contents: &[SedInstruction]) -> usize {
+    proc_contents
+        .iter()
+        .map(|instruction| match instruction {
+            SedInstruction::Sed(sed) => sed.0.lines().count(),
+            SedInstruction::IfProc(if_proc) => {
+                1 + size(if_proc.then_proc()) + size(if_proc.else_proc())
+            }
+            _ => 1,
+        })
+        .sum()
+}
+
+fn is_sed_only(func_def: &FuncDef) -> bool {
+    !func_def.proc_contents().is_empty()
+        && func_def
+            .proc_contents()
+            .iter()
+            .all(|instruction| matches!(instruction, SedInstruction::Sed(_)))
+}
+
+pub(crate) fn contains_sed(proc_contents: &[SedInstruction]) -> bool {
+    proc_contents.iter().any(|instruction| match instruction {
+        SedInstruction::Sed(_) => true,
+        SedInstruction::IfProc(if_proc) => {
+            contains_sed(if_proc.then_proc()) || contains_sed(if_proc.else_proc())
+        }
+        _ => false,
+    })
+}
+
+fn single_exit(proc_contents: &[SedInstruction]) -> bool {
+    match proc_contents.split_last() {
+        Some((SedInstruction::Ret, rest)) => !contains_ret(rest),
+        Some((SedInstruction::IfProc(if_proc), rest)) => {
+            !contains_ret(rest)
+                && single_exit(if_proc.then_proc())
+                && single_exit(if_proc.else_proc())
+        }
+        _ => false,
+    }
+}
+
+/// 末尾呼び出しは呼び出し元のフレームを置き換えてしまうので`Ret`と同じく扱う
+fn contains_ret(proc_contents: &[SedInstruction]) -> bool {
+    proc_contents.iter().any(|instruction| match instruction {
+        SedInstruction::Ret | SedInstruction::TailCall(_) => true,
+        SedInstruction::IfProc(if_proc) => {
+            contains_ret(if_proc.then_proc()) || contains_ret(if_proc.else_proc())
+        }
+        _ => false,
+    })
+}
+
+/// 呼び出し先の引数とローカル変数を、呼び出し元のbaseから始まるローカル変数に置き換える
+/// `Ret`は取り除き、返り値はスタックに残す
+fn rebase(proc_contents: &[SedInstruction], base: usize, argc: usize) -> Vec<SedInstruction> {
+    let value = |value: &Value| match *value {
+        Value::Arg(index) => Value::Local(base + index),
+        Value::Local(index) => Value::Local(base + argc + index),
+    };
+    proc_contents
+        .iter()
+        .filter(|instruction| !matches!(instruction, SedInstruction::Ret))
+        .map(|instruction| match instruction {
+            SedInstruction::Val(v) => SedInstruction::Val(value(v)),
+            SedInstruction::Set(v) => SedInstruction::Set(value(v)),
+            SedInstruction::IfProc(if_proc) => SedInstruction::IfProc(IfProc::new(
+                rebase(if_proc.then_proc(), base, argc),
+                rebase(if_proc.else_proc(), base, argc),
+            )),
+            _ => instruction.clone(),
+        })
+        .collect()
+}
+
+/// 生のsedの関数を、ラベルの名前を変えてそのまま埋め込む
+fn inline_sed(callee: &FuncDef, suffix: &str) -> InlineSed {
+    let lines: Vec<&str> = callee
+        .proc_contents()
+        .iter()
+        .filter_map(|instruction| match instruction {
+            SedInstruction::Sed(sed) => Some(sed.0.as_str()),
+            _ => None,
+        })
+        .flat_map(str::lines)
+        .collect();
+    InlineSed::new(
+        &rename_labels(&lines, suffix).join("\n"),
+        callee.argc(),
+        callee.localc(),
+        callee.retc(),
+    )
+}
+
+#[cfg(test)]
+mod inline_test {
+    use super::*;
+    use crate::code_gen::{CallFunc, CompilerBuilder, ConstVal, SedCode, collect_called_funcs};
+    use crate::embedded::em_shift_left1;
+    use crate::ir_text::{parse_funcs, print_funcs};
+
+    #[test]
+    fn inline_test00() {
+        // 生のsedのラベルは呼び出しごとに別の名前になる
+        let lines = [
+            "b loop",
+            ":loop",
+            "s/~1/~0/",
+            "t loop",
+            "/x/ !{ tother }",
+            "bloop",
+        ];
+        assert_eq!(
+            rename_labels(&lines, "_1"),
+            vec![
+                "b loop_1",
+                ":loop_1",
+                "s/~1/~0/",
+                "t loop_1",
+                "/x/ !{ tother }",
+                "bloop_1"
+            ]
+        );
+
+        let mut entry = FuncDef::new("entry", 1, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::Call(CallFunc::new("twice")),
+            SedInstruction::Call(CallFunc::new("shift_left1")),
+            SedInstruction::Ret,
+        ]);
+        // 引数をそのまま返すか、左に2回シフトする
+        let mut twice = FuncDef::new("twice", 1, 1, 1);
+        twice.set_inline(true);
+        twice.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::Call(CallFunc::new("shift_left1")),
+            SedInstruction::Set(Value::Local(0)),
+            SedInstruction::ConstVal(ConstVal::new("1")),
+            SedInstruction::IfProc(IfProc::new(
+                vec![
+                    SedInstruction::Val(Value::Local(0)),
+                    SedInstruction::Call(CallFunc::new("shift_left1")),
+                    SedInstruction::Ret,
+                ],
+                vec![SedInstruction::Val(Value::Arg(0)), SedInstruction::Ret],
+            )),
+        ]);
+        let mut func_table = vec![entry, twice, em_shift_left1()];
+        assert_eq!(inline_funcs(&mut func_table), 4);
+        assert_eq!(func_table[0].localc(), 2);
+        // 展開は一段だけなので、展開したtwiceの中の呼び出しは残る
+        let mut called = Vec::new();
+        collect_called_funcs(func_table[0].proc_contents(), &mut called);
+        assert_eq!(called, vec!["shift_left1", "shift_left1"]);
+
+        // テキスト形式のIRでも展開した結果を保存できる
+        let text = print_funcs(&func_table);
+        assert!(text.contains("inline fn twice"));
+        assert!(text.contains("sed.inline args=1 locals=0 rets=1"));
+        assert_eq!(print_funcs(&parse_funcs(&text).unwrap()), text);
+    }
+
+    #[test]
+    fn inline_test01() {
+        // 再帰呼び出しと大きな関数は展開しない
+        let mut entry = FuncDef::new("entry", 1, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::Call(CallFunc::new("entry")),
+            SedInstruction::Ret,
+        ]);
+        let mut large = FuncDef::new("large", 0, 0, 1);
+        large.set_proc_contents(vec![SedInstruction::Sed(SedCode("s/$/~;/\n".repeat(9)))]);
+        let mut func_table = vec![entry, large];
+        assert_eq!(inline_funcs(&mut func_table), 0);
+
+        func_table[1].set_inline(true);
+        let mut caller = FuncDef::new("caller", 0, 0, 1);
+        caller.set_proc_contents(vec![
+            SedInstruction::Call(CallFunc::new("large")),
+            SedInstruction::Ret,
+        ]);
+        func_table.push(caller);
+        assert_eq!(inline_funcs(&mut func_table), 1);
+        assert!(
+            CompilerBuilder::new()
+                .add_func(func_table[2].clone())
+                .entry("caller")
+                .assemble()
+                .generate()
+                .is_ok()
+        );
+    }
+}
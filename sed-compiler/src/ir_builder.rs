@@ -0,0 +1,528 @@
+//! soil以外のフロントエンドからIRを組み立てるためのビルダー
+//! 命令を追加するたびにスタックの深さを検査し、verifierと同じVerifyErrを返す
+//! 戻りアドレスの番号付けはbuildの中で行うため、呼び出す順序を気にする必要はない
+//!
+//! ```
+//! use sed_compiler::code_gen::CompilerBuilder;
+//! use sed_compiler::ir_builder::{FuncBuilder, FuncSig};
+//!
+//! // fn entry a -> r { if is_empty(a) { r = 0 } else { r = add(a, a) } return r }
+//! let mut f = FuncBuilder::new("entry");
+//! let a = f.arg();
+//! let r = f.local();
+//! f.returns(1);
+//! let is_empty = FuncSig::new("is_empty", 1, 1);
+//! let add = FuncSig::new("add", 2, 1);
+//! let entry = f
+//!     .build(|b| {
+//!         b.push_arg(a)?;
+//!         b.call(&is_empty)?;
+//!         b.if_else(
+//!             |then| {
+//!                 then.push_int(0, 32)?;
+//!                 then.set_local(r)
+//!             },
+//!             |else_| {
+//!                 else_.push_arg(a)?;
+//!                 else_.push_arg(a)?;
+//!                 else_.call(&add)?;
+//!                 else_.set_local(r)
+//!             },
+//!         )?;
+//!         b.push_local(r)?;
+//!         b.ret()
+//!     })
+//!     .unwrap();
+//! let sed = CompilerBuilder::new().add_func(entry).assemble().generate();
+//! assert!(sed.is_ok());
+//! ```
+
+use crate::code_gen::{CallFunc, ConstVal, FuncDef, IfProc, SedCode, SedInstruction, Value};
+use crate::verifier::{VerifyErr, VerifyErrKind};
+
+/// 呼び出し先の関数の名前と、引数と返り値の個数
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncSig {
+    name: String,
+    argc: usize,
+    retc: usize,
+}
+
+impl FuncSig {
+    pub fn new(name: &str, argc: usize, retc: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            argc,
+            retc,
+        }
+    }
+
+    /// 定義済みの関数(組み込み関数を含む)の呼び出し方
+    pub fn of(func: &FuncDef) -> Self {
+        Self::new(func.name(), func.argc(), func.retc())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    pub fn retc(&self) -> usize {
+        self.retc
+    }
+}
+
+/// FuncBuilder::argで宣言した引数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArgHandle(usize);
+
+/// FuncBuilder::localで宣言したローカル変数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalHandle(usize);
+
+impl ArgHandle {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl LocalHandle {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// 関数の名前と引数、ローカル変数、返り値の個数を宣言してから本体を組み立てる
+#[derive(Clone, Debug)]
+pub struct FuncBuilder {
+    name: String,
+    argc: usize,
+    localc: usize,
+    retc: usize,
+    public: bool,
+    inline: bool,
+    constant: bool,
+}
+
+impl FuncBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            argc: 0,
+            localc: 0,
+            retc: 0,
+            public: false,
+            inline: false,
+            constant: false,
+        }
+    }
+
+    /// 引数を一つ追加する
+    pub fn arg(&mut self) -> ArgHandle {
+        self.argc += 1;
+        ArgHandle(self.argc - 1)
+    }
+
+    /// ローカル変数を一つ追加する
+    pub fn local(&mut self) -> LocalHandle {
+        self.localc += 1;
+        LocalHandle(self.localc - 1)
+    }
+
+    /// 返り値の個数
+    pub fn returns(&mut self, retc: usize) {
+        self.retc = retc;
+    }
+
+    pub fn set_public(&mut self, public: bool) {
+        self.public = public;
+    }
+
+    /// 大きさに関わらずインライン展開する
+    pub fn set_inline(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
+    /// 呼び出しをコンパイル時に評価する関数にする
+    pub fn set_const(&mut self, constant: bool) {
+        self.constant = constant;
+    }
+
+    /// 再帰呼び出しのための、この関数自身の呼び出し方
+    pub fn signature(&self) -> FuncSig {
+        FuncSig::new(&self.name, self.argc, self.retc)
+    }
+
+    /// 本体を組み立てる 本体はすべての経路で`ret`を通る必要がある
+    pub fn build(
+        self,
+        body: impl FnOnce(&mut BlockBuilder<'_>) -> Result<(), VerifyErr>,
+    ) -> Result<FuncDef, VerifyErr> {
+        let mut block = BlockBuilder::new(&self, vec![], 0);
+        body(&mut block)?;
+        if !block.returned {
+            return Err(block.err(VerifyErrKind::MissingReturn));
+        }
+        let mut func = self.func_def();
+        func.set_proc_contents(block.instructions);
+        Ok(func)
+    }
+
+    /// 本体を生のsedで書く
+    /// パターンスペースは引数を並べた`~a~b`で始まり、返り値を`~r;`の形で残す
+    /// ホールドスペースには呼び出し元のフレームがあるので変更してはいけない
+    pub fn build_sed(self, code: &[&str]) -> FuncDef {
+        let mut func = self.func_def();
+        func.set_proc_contents(
+            code.iter()
+                .map(|line| SedInstruction::Sed(SedCode(line.to_string())))
+                .collect(),
+        );
+        func
+    }
+
+    fn func_def(&self) -> FuncDef {
+        let mut func = FuncDef::new(&self.name, self.argc, self.localc, self.retc);
+        func.set_public(self.public);
+        func.set_inline(self.inline);
+        func.set_const(self.constant);
+        func
+    }
+}
+
+/// 命令列を組み立てる
+/// `if`のthen節とelse節はそれぞれ別のBlockBuilderになる
+pub struct BlockBuilder<'f> {
+    func: &'f FuncBuilder,
+    /// この命令列の位置 VerifyErr::pathと同じ形式
+    path: Vec<usize>,
+    instructions: Vec<SedInstruction>,
+    /// 引数とローカル変数を除いたスタックの深さ
+    depth: usize,
+    /// `ret`を通ったかどうか
+    returned: bool,
+}
+
+impl<'f> BlockBuilder<'f> {
+    fn new(func: &'f FuncBuilder, path: Vec<usize>, depth: usize) -> Self {
+        Self {
+            func,
+            path,
+            instructions: Vec::new(),
+            depth,
+            returned: false,
+        }
+    }
+
+    /// 現在のスタックの深さ
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn err(&self, kind: VerifyErrKind) -> VerifyErr {
+        let mut path = self.path.clone();
+        path.push(self.instructions.len());
+        VerifyErr {
+            func_name: self.func.name.clone(),
+            path,
+            depth: self.depth,
+            kind,
+            span: None,
+        }
+    }
+
+    /// 命令を追加する前の検査 needed個の値を消費できる必要がある
+    fn check(&self, needed: usize) -> Result<(), VerifyErr> {
+        if self.returned {
+            return Err(self.err(VerifyErrKind::Unreachable));
+        }
+        if self.depth < needed {
+            return Err(self.err(VerifyErrKind::StackUnderflow {
+                needed,
+                available: self.depth,
+            }));
+        }
+        Ok(())
+    }
+
+    fn value(&self, value: Value) -> Result<Value, VerifyErr> {
+        let (index, count, kind) = match value {
+            Value::Arg(index) => (index, self.func.argc, "argument"),
+            Value::Local(index) => (index, self.func.localc, "local"),
+        };
+        if count <= index {
+            return Err(self.err(VerifyErrKind::UndefinedValue(format!("{} {}", kind, index))));
+        }
+        Ok(value)
+    }
+
+    fn push(&mut self, instruction: SedInstruction, popped: usize, pushed: usize) {
+        self.instructions.push(instruction);
+        self.depth = self.depth - popped + pushed;
+    }
+
+    pub fn push_arg(&mut self, arg: ArgHandle) -> Result<(), VerifyErr> {
+        self.check(0)?;
+        let value = self.value(Value::Arg(arg.0))?;
+        self.push(SedInstruction::Val(value), 0, 1);
+        Ok(())
+    }
+
+    pub fn push_local(&mut self, local: LocalHandle) -> Result<(), VerifyErr> {
+        self.check(0)?;
+        let value = self.value(Value::Local(local.0))?;
+        self.push(SedInstruction::Val(value), 0, 1);
+        Ok(())
+    }
+
+    /// スタックから取り出して引数に代入する
+    pub fn set_arg(&mut self, arg: ArgHandle) -> Result<(), VerifyErr> {
+        self.check(1)?;
+        let value = self.value(Value::Arg(arg.0))?;
+        self.push(SedInstruction::Set(value), 1, 0);
+        Ok(())
+    }
+
+    /// スタックから取り出してローカル変数に代入する
+    pub fn set_local(&mut self, local: LocalHandle) -> Result<(), VerifyErr> {
+        self.check(1)?;
+        let value = self.value(Value::Local(local.0))?;
+        self.push(SedInstruction::Set(value), 1, 0);
+        Ok(())
+    }
+
+    /// bits桁の2進数で表した整数 `NumberRepr::Decimal`では10進数に変換される
+    pub fn push_int(&mut self, value: u64, bits: usize) -> Result<(), VerifyErr> {
+        self.push_const(ConstVal::int(value, bits))
+    }
+
+    pub fn push_bigint(&mut self, value: i64) -> Result<(), VerifyErr> {
+        self.push_const(ConstVal::bigint(value))
+    }
+
+    pub fn push_bool(&mut self, value: bool) -> Result<(), VerifyErr> {
+        self.push_const(ConstVal::new(if value { "1" } else { "0" }))
+    }
+
+    /// 文字列 strings.rsの形式にエスケープされる
+    pub fn push_str(&mut self, text: &str) -> Result<(), VerifyErr> {
+        self.push_const(ConstVal::str(text))
+    }
+
+    pub fn push_const(&mut self, const_val: ConstVal) -> Result<(), VerifyErr> {
+        self.check(0)?;
+        self.push(SedInstruction::ConstVal(const_val), 0, 1);
+        Ok(())
+    }
+
+    /// 引数の個数だけ取り出し、返り値を積む
+    pub fn call(&mut self, sig: &FuncSig) -> Result<(), VerifyErr> {
+        self.check(sig.argc)?;
+        self.push(
+            SedInstruction::Call(CallFunc::new(&sig.name)),
+            sig.argc,
+            sig.retc,
+        );
+        Ok(())
+    }
+
+    /// スタックにある値をすべて返す 値の個数は返り値の個数と一致する必要がある
+    pub fn ret(&mut self) -> Result<(), VerifyErr> {
+        self.check(0)?;
+        if self.depth != self.func.retc {
+            return Err(self.err(VerifyErrKind::ReturnCountMismatch {
+                expected: self.func.retc,
+                available: self.depth,
+            }));
+        }
+        self.push(SedInstruction::Ret, 0, 0);
+        self.returned = true;
+        Ok(())
+    }
+
+    /// スタックから取り出した値で分岐する
+    /// 両方の節が終わったときのスタックの深さは一致する必要がある(`ret`を通る節は除く)
+    pub fn if_else(
+        &mut self,
+        then: impl FnOnce(&mut BlockBuilder<'f>) -> Result<(), VerifyErr>,
+        else_: impl FnOnce(&mut BlockBuilder<'f>) -> Result<(), VerifyErr>,
+    ) -> Result<(), VerifyErr> {
+        self.check(1)?;
+        let depth = self.depth - 1;
+        let index = self.instructions.len();
+        let branch = |n: usize| {
+            let mut path = self.path.clone();
+            path.extend([index, n]);
+            BlockBuilder::new(self.func, path, depth)
+        };
+        let mut then_block = branch(0);
+        let mut else_block = branch(1);
+        then(&mut then_block)?;
+        else_(&mut else_block)?;
+        let (end, returned) = match (then_block.returned, else_block.returned) {
+            (false, false) if then_block.depth != else_block.depth => {
+                self.depth = depth;
+                return Err(self.err(VerifyErrKind::BranchMismatch {
+                    then_depth: then_block.depth,
+                    else_depth: else_block.depth,
+                }));
+            }
+            (false, _) => (then_block.depth, false),
+            (true, false) => (else_block.depth, false),
+            (true, true) => (depth, true),
+        };
+        let if_proc = IfProc::new(then_block.instructions, else_block.instructions);
+        self.instructions.push(SedInstruction::IfProc(if_proc));
+        self.depth = end;
+        self.returned = returned;
+        Ok(())
+    }
+
+    /// else節のない`if`
+    pub fn if_then(
+        &mut self,
+        then: impl FnOnce(&mut BlockBuilder<'f>) -> Result<(), VerifyErr>,
+    ) -> Result<(), VerifyErr> {
+        self.if_else(then, |_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod ir_builder_test {
+    use super::*;
+    use crate::code_gen::CompilerBuilder;
+    use crate::embedded::BuiltinRegistry;
+    use crate::verifier::verify_func_table;
+
+    #[test]
+    fn ir_builder_test00() {
+        // 組み立てた関数はverifierを通り、soilから作ったものと同じIRになる
+        let registry = BuiltinRegistry::standard();
+        let add = FuncSig::of(registry.get("add").unwrap().def());
+
+        let mut f = FuncBuilder::new("double");
+        let a = f.arg();
+        f.returns(1);
+        let double = f
+            .build(|b| {
+                b.push_arg(a)?;
+                b.push_arg(a)?;
+                b.call(&add)?;
+                b.ret()
+            })
+            .unwrap();
+
+        let mut f = FuncBuilder::new("entry");
+        let a = f.arg();
+        f.returns(2);
+        f.set_public(true);
+        let entry = f
+            .build(|b| {
+                b.push_arg(a)?;
+                b.push_int(0, 32)?;
+                b.call(&FuncSig::new("eq", 2, 1))?;
+                b.if_then(|then| {
+                    then.push_str("zero")?;
+                    then.push_arg(a)?;
+                    then.ret()
+                })?;
+                b.push_str("double")?;
+                b.push_arg(a)?;
+                b.call(&FuncSig::of(&double))?;
+                b.ret()
+            })
+            .unwrap();
+        let code = r#"
+pub fn entry a:bit32 -> str, bit32 {
+    if a == 0 {
+        return "zero", a;
+    }
+    return "double", double(a);
+}
+
+fn double a:bit32 -> bit32 {
+    return a + a;
+}
+"#;
+        let built = CompilerBuilder::new().add_func(entry).add_func(double);
+        let expected = crate::compiler::compiler_frontend(code).unwrap();
+        assert_eq!(built.dump_ir(), expected.dump_ir());
+        assert_eq!(
+            built.assemble().generate().unwrap(),
+            expected.assemble().generate().unwrap()
+        );
+    }
+
+    #[test]
+    fn ir_builder_test01() {
+        // 問題のある命令の位置で止まる
+        let mut f = FuncBuilder::new("f");
+        let a = f.arg();
+        f.returns(1);
+        let err = f
+            .build(|b| {
+                b.push_arg(a)?;
+                b.if_else(|then| then.push_int(1, 32), |_| Ok(()))
+            })
+            .unwrap_err();
+        assert_eq!(err.path, vec![1]);
+        assert_eq!(
+            err.kind,
+            VerifyErrKind::BranchMismatch {
+                then_depth: 1,
+                else_depth: 0
+            }
+        );
+
+        let mut f = FuncBuilder::new("f");
+        f.returns(1);
+        let err = f
+            .build(|b| {
+                b.push_bool(true)?;
+                b.if_then(|then| then.call(&FuncSig::new("add", 2, 1)))
+            })
+            .unwrap_err();
+        assert_eq!(err.path, vec![1, 0, 0]);
+        assert_eq!(
+            err.kind,
+            VerifyErrKind::StackUnderflow {
+                needed: 2,
+                available: 0
+            }
+        );
+
+        let mut f = FuncBuilder::new("f");
+        f.returns(1);
+        let err = f
+            .build(|b| {
+                b.push_bool(true)?;
+                b.ret()?;
+                b.push_bool(false)
+            })
+            .unwrap_err();
+        assert_eq!(err.kind, VerifyErrKind::Unreachable);
+
+        let mut other = FuncBuilder::new("g");
+        let local = other.local();
+        let err = FuncBuilder::new("f")
+            .build(|b| b.push_local(local))
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            VerifyErrKind::UndefinedValue("local 0".to_string())
+        );
+
+        let err = FuncBuilder::new("f").build(|_| Ok(())).unwrap_err();
+        assert_eq!(err.kind, VerifyErrKind::MissingReturn);
+
+        // 生のsedの関数も同じ表で検査できる
+        let mut f = FuncBuilder::new("not");
+        f.arg();
+        f.returns(1);
+        let not = f.build_sed(&["s/^~1$/~2/", "s/^~0$/~1/", "s/^~2$/~0/", "s/$/;/"]);
+        assert!(verify_func_table(&[not]).is_ok());
+    }
+}
@@ -0,0 +1,485 @@
+//! IRのテキスト形式
+//! 関数の表を保存、編集してから`CompilerBuilder::add_ir`で読み戻すために使う
+//!
+//! ```text
+//! pub fn entry args=1 locals=1 rets=1 {
+//!     push.arg 0
+//!     push.int 5 32
+//!     call add
+//!     set.local 0
+//!     push.local 0
+//!     if {
+//!         sed "s/^~//"
+//!     } else {
+//!         push.const "1"
+//!     }
+//!     ret
+//! }
+//! ```
+//!
+//! 一行に一つの命令を書き、`//`から行末まではコメントになる
+//! ラベルや戻りアドレスなどassembleで決まるものは含まない
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::code_gen::{
+    CallFunc, ConstVal, FuncDef, IfProc, InlineSed, SedCode, SedInstruction, Value,
+};
+
+/// テキスト形式のIRの誤り
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IrParseErr {
+    /// 1から数えた行番号
+    pub line: usize,
+    pub message: String,
+}
+
+impl IrParseErr {
+    pub(crate) fn new(line: usize, message: &str) -> Self {
+        Self {
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    /// textの中でのエラーの行のバイトオフセットの範囲
+    pub fn span(&self, text: &str) -> Range<usize> {
+        let mut start = 0;
+        for (i, line) in text.split('\n').enumerate() {
+            if i + 1 == self.line {
+                return start..start + line.len();
+            }
+            start += line.len() + 1;
+        }
+        text.len()..text.len()
+    }
+}
+
+impl fmt::Display for IrParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// 関数の表をテキスト形式にする
+pub fn print_funcs(funcs: &[FuncDef]) -> String {
+    let mut out = String::new();
+    for (index, func) in funcs.iter().enumerate() {
+        if 0 < index {
+            out.push('\n');
+        }
+        print_func(&mut out, func);
+    }
+    out
+}
+
+fn print_func(out: &mut String, func: &FuncDef) {
+    let public = if func.is_public() { "pub " } else { "" };
+    let inline = if func.is_inline() { "inline " } else { "" };
+    let constant = if func.is_const() { "const " } else { "" };
+    out.push_str(&format!(
+        "{}{}{}fn {} args={} locals={} rets={} {{\n",
+        public,
+        inline,
+        constant,
+        func.name(),
+        func.argc(),
+        func.localc(),
+        func.retc()
+    ));
+    print_block(out, func.proc_contents(), 1);
+    out.push_str("}\n");
+}
+
+fn print_block(out: &mut String, instructions: &[SedInstruction], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for instruction in instructions {
+        out.push_str(&indent);
+        match instruction {
+            SedInstruction::Sed(code) => out.push_str(&format!("sed {}", quote(&code.0))),
+            SedInstruction::Val(value) => out.push_str(&format!("push.{}", value_operand(value))),
+            SedInstruction::ConstVal(const_val) => out.push_str(&const_operand(const_val)),
+            SedInstruction::Call(call) => out.push_str(&format!("call {}", call.func_name())),
+            SedInstruction::TailCall(call) => {
+                out.push_str(&format!("call.tail {}", call.func_name()))
+            }
+            SedInstruction::Set(value) => out.push_str(&format!("set.{}", value_operand(value))),
+            SedInstruction::Ret => out.push_str("ret"),
+            SedInstruction::InlineSed(inline) => out.push_str(&format!(
+                "sed.inline args={} locals={} rets={} {}",
+                inline.argc(),
+                inline.localc(),
+                inline.retc(),
+                quote(inline.code())
+            )),
+            SedInstruction::IfProc(if_proc) => {
+                out.push_str("if {\n");
+                print_block(out, if_proc.then_proc(), depth + 1);
+                if !if_proc.else_proc().is_empty() {
+                    out.push_str(&format!("{}}} else {{\n", indent));
+                    print_block(out, if_proc.else_proc(), depth + 1);
+                }
+                out.push_str(&format!("{}}}", indent));
+            }
+        }
+        out.push('\n');
+    }
+}
+
+fn value_operand(value: &Value) -> String {
+    match value {
+        Value::Arg(index) => format!("arg {}", index),
+        Value::Local(index) => format!("local {}", index),
+    }
+}
+
+/// 整数の定数は数の表現に合わせて変換できるように値で書く
+fn const_operand(const_val: &ConstVal) -> String {
+    let data = const_val.data();
+    match const_val.int_value() {
+        Some(value) if const_val.is_negative() && data == format!("-{:b}", value) => {
+            format!("push.bigint -{}", value)
+        }
+        Some(value) if data == format!("{:0width$b}", value, width = data.len()) => {
+            format!("push.int {} {}", value, data.len())
+        }
+        _ => format!("push.const {}", quote(data)),
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// テキスト形式から関数の表を読み込む
+pub fn parse_funcs(text: &str) -> Result<Vec<FuncDef>, IrParseErr> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, strip_comment(line).trim()))
+        .filter(|(_, line)| !line.is_empty());
+    let mut funcs = Vec::new();
+    while let Some((line_no, line)) = lines.next() {
+        let mut func = parse_header(line_no, line)?;
+        let (instructions, end) = parse_block(&mut lines, line_no)?;
+        if end == BlockEnd::Else {
+            return Err(IrParseErr::new(line_no, "`else` without `if`"));
+        }
+        func.set_proc_contents(instructions);
+        funcs.push(func);
+    }
+    Ok(funcs)
+}
+
+/// 文字列の外にある`//`から行末を取り除く
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            '/' if !in_str && line[index..].starts_with("//") => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// `pub inline const fn name args=1 locals=0 rets=1 {`
+fn parse_header(line_no: usize, line: &str) -> Result<FuncDef, IrParseErr> {
+    let err = || {
+        IrParseErr::new(
+            line_no,
+            &format!("expected a function header, found `{}`", line),
+        )
+    };
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+    let public = words.first() == Some(&"pub");
+    if public {
+        words.remove(0);
+    }
+    let inline = words.first() == Some(&"inline");
+    if inline {
+        words.remove(0);
+    }
+    let constant = words.first() == Some(&"const");
+    if constant {
+        words.remove(0);
+    }
+    let ["fn", name, args, locals, rets, "{"] = words.as_slice() else {
+        return Err(err());
+    };
+    let count = |word: &str, key: &str| {
+        word.strip_prefix(key)
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(err)
+    };
+    let mut func = FuncDef::new(
+        name,
+        count(args, "args=")?,
+        count(locals, "locals=")?,
+        count(rets, "rets=")?,
+    );
+    func.set_public(public);
+    func.set_inline(inline);
+    func.set_const(constant);
+    Ok(func)
+}
+
+/// `args=1 locals=0 rets=1 "code"`
+fn parse_inline_sed(line_no: usize, operand: &str) -> Result<SedInstruction, IrParseErr> {
+    let err = || {
+        IrParseErr::new(
+            line_no,
+            "`sed.inline` needs `args=N locals=N rets=N` and a code",
+        )
+    };
+    let mut words = operand.splitn(4, char::is_whitespace);
+    let mut count = |key: &str| {
+        words
+            .next()
+            .and_then(|word| word.strip_prefix(key))
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(err)
+    };
+    let (argc, localc, retc) = (count("args=")?, count("locals=")?, count("rets=")?);
+    let code = unquote(line_no, words.next().ok_or_else(err)?.trim())?;
+    Ok(SedInstruction::InlineSed(InlineSed::new(
+        &code, argc, localc, retc,
+    )))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BlockEnd {
+    /// `}`
+    Close,
+    /// `} else {`
+    Else,
+}
+
+/// `}`または`} else {`までの命令を読む
+fn parse_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    open_line: usize,
+) -> Result<(Vec<SedInstruction>, BlockEnd), IrParseErr> {
+    let mut instructions = Vec::new();
+    while let Some((line_no, line)) = lines.next() {
+        let (op, operand) = match line.split_once(char::is_whitespace) {
+            Some((op, operand)) => (op, operand.trim()),
+            None => (line, ""),
+        };
+        let instruction = match (op, operand) {
+            ("}", "") => return Ok((instructions, BlockEnd::Close)),
+            ("}", "else {") => return Ok((instructions, BlockEnd::Else)),
+            ("ret", "") => SedInstruction::Ret,
+            ("sed", code) => SedInstruction::Sed(SedCode(unquote(line_no, code)?)),
+            ("sed.inline", operand) => parse_inline_sed(line_no, operand)?,
+            ("call", name) if is_name(name) => SedInstruction::Call(CallFunc::new(name)),
+            ("call.tail", name) if is_name(name) => SedInstruction::TailCall(CallFunc::new(name)),
+            ("push.arg", index) => SedInstruction::Val(Value::Arg(parse_num(line_no, index)?)),
+            ("push.local", index) => SedInstruction::Val(Value::Local(parse_num(line_no, index)?)),
+            ("set.arg", index) => SedInstruction::Set(Value::Arg(parse_num(line_no, index)?)),
+            ("set.local", index) => SedInstruction::Set(Value::Local(parse_num(line_no, index)?)),
+            ("push.const", data) => {
+                SedInstruction::ConstVal(ConstVal::new(&unquote(line_no, data)?))
+            }
+            ("push.int", operand) => {
+                let Some((value, bits)) = operand.split_once(char::is_whitespace) else {
+                    return Err(IrParseErr::new(
+                        line_no,
+                        "`push.int` needs a value and a width",
+                    ));
+                };
+                let value: u64 = parse_num(line_no, value)?;
+                let bits: usize = parse_num(line_no, bits.trim())?;
+                if bits == 0 || 64 < bits {
+                    return Err(IrParseErr::new(
+                        line_no,
+                        &format!("width `{}` must be between 1 and 64", bits),
+                    ));
+                }
+                if bits < 64 && 1 << bits <= value {
+                    return Err(IrParseErr::new(
+                        line_no,
+                        &format!("`{}` does not fit in {} bits", value, bits),
+                    ));
+                }
+                SedInstruction::ConstVal(ConstVal::int(value, bits))
+            }
+            ("push.bigint", value) => match value.parse::<i64>() {
+                Ok(value) => SedInstruction::ConstVal(ConstVal::bigint(value)),
+                Err(_) => {
+                    return Err(IrParseErr::new(
+                        line_no,
+                        &format!("invalid bigint `{}`", value),
+                    ));
+                }
+            },
+            ("if", "{") => {
+                let (then_proc, end) = parse_block(lines, line_no)?;
+                let else_proc = match end {
+                    BlockEnd::Close => vec![],
+                    BlockEnd::Else => match parse_block(lines, line_no)? {
+                        (else_proc, BlockEnd::Close) => else_proc,
+                        (_, BlockEnd::Else) => {
+                            return Err(IrParseErr::new(line_no, "`if` has more than one `else`"));
+                        }
+                    },
+                };
+                SedInstruction::IfProc(IfProc::new(then_proc, else_proc))
+            }
+            _ => {
+                return Err(IrParseErr::new(
+                    line_no,
+                    &format!("unknown instruction `{}`", line),
+                ));
+            }
+        };
+        instructions.push(instruction);
+    }
+    Err(IrParseErr::new(open_line, "unclosed `{`"))
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(char::is_whitespace)
+}
+
+fn parse_num<T: std::str::FromStr>(line_no: usize, text: &str) -> Result<T, IrParseErr> {
+    text.parse()
+        .map_err(|_| IrParseErr::new(line_no, &format!("invalid number `{}`", text)))
+}
+
+fn unquote(line_no: usize, text: &str) -> Result<String, IrParseErr> {
+    let err = |message: &str| IrParseErr::new(line_no, message);
+    let Some(body) = text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    else {
+        return Err(err("expected a quoted string"));
+    };
+    let mut out = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => out.push('\\'),
+                Some('"') => out.push('"'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                _ => return Err(err("invalid escape in string")),
+            },
+            '"' => return Err(err("unescaped `\"` in string")),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod ir_text_test {
+    use super::*;
+    use crate::compiler::compiler_frontend;
+
+    #[test]
+    fn ir_text_test00() {
+        // 書き出したIRを読み戻すと同じsedが生成される
+        let code = r#"
+pub fn entry a:bit32 -> bit32, bigint, str {
+    let r = a * 2;
+    if a == 0 {
+        r = a + 1;
+    } else {
+        r = double(a);
+    }
+    return r, -5, "x\"y";
+}
+
+fn double a:bit32 -> bit32 {
+    sed ${
+        "s/^~\\(.*\\)$/~\\10;/",
+    }$
+}
+"#;
+        let builder = compiler_frontend(code).unwrap();
+        let text = builder.dump_ir();
+        assert!(text.contains("push.int 2 32"));
+        assert!(text.contains("push.bigint -5"));
+
+        let parsed = crate::code_gen::CompilerBuilder::new()
+            .add_ir(&text)
+            .unwrap();
+        assert_eq!(parsed.dump_ir(), text);
+        assert_eq!(
+            parsed.assemble().generate().unwrap(),
+            builder.assemble().generate().unwrap()
+        );
+    }
+
+    #[test]
+    fn ir_text_test01() {
+        let cases = [
+            (
+                "fn f args=0 locals=0 rets=1 {\n    ret\n",
+                1,
+                "unclosed `{`",
+            ),
+            (
+                "fn f args=0 rets=1 {\n}\n",
+                1,
+                "expected a function header, found `fn f args=0 rets=1 {`",
+            ),
+            (
+                "fn f args=0 locals=0 rets=0 {\n  // c\n  push.x 1\n}\n",
+                3,
+                "unknown instruction `push.x 1`",
+            ),
+            (
+                "fn f args=0 locals=0 rets=0 {\n  sed \"a\\q\"\n}\n",
+                2,
+                "invalid escape in string",
+            ),
+            (
+                "fn f args=0 locals=0 rets=0 {\n} else {\n",
+                1,
+                "`else` without `if`",
+            ),
+            (
+                "fn f args=0 locals=0 rets=1 {\n  push.int 256 8\n  ret\n}\n",
+                2,
+                "`256` does not fit in 8 bits",
+            ),
+            (
+                "fn f args=0 locals=0 rets=1 {\n  push.int 0 0\n  ret\n}\n",
+                2,
+                "width `0` must be between 1 and 64",
+            ),
+            (
+                "fn f args=0 locals=0 rets=1 {\n  push.int 1 65\n  ret\n}\n",
+                2,
+                "width `65` must be between 1 and 64",
+            ),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(
+                parse_funcs(text).err(),
+                Some(IrParseErr::new(line, message)),
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -1,3 +1,19 @@
 pub mod code_gen;
 pub mod compiler;
+pub mod const_fold;
+pub mod decimal;
+pub mod diagnostic;
 pub mod embedded;
+pub mod inline;
+pub mod ir_builder;
+pub mod ir_text;
+pub mod lint;
+pub mod object;
+pub mod peephole;
+pub mod prelude;
+pub mod regex;
+pub mod sed_label;
+pub mod strings;
+pub mod tail_call;
+pub mod types;
+pub mod verifier;
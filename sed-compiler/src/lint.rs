@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use sed_compiler_frontend::parser::*;
+
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::types::op_func_names;
+
+/// 警告の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lint {
+    /// 代入されているが一度も読まれないローカル変数
+    UnusedVariables,
+    /// 一度も使われない引数
+    UnusedArguments,
+    /// entryから到達できない非pub関数
+    DeadCode,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 3] = [Lint::UnusedVariables, Lint::UnusedArguments, Lint::DeadCode];
+
+    /// `-W`/`-D`で指定するときの名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused_variables",
+            Lint::UnusedArguments => "unused_arguments",
+            Lint::DeadCode => "dead_code",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LintLevel {
+    /// 警告として表示する
+    Warn,
+    /// エラーとして扱いコンパイルを中断する
+    Deny,
+}
+
+#[derive(Debug)]
+pub struct LintConfigErr {
+    pub note: String,
+}
+
+/// 警告ごとのレベルを管理する
+/// デフォルトではすべての警告がWarn
+#[derive(Clone, Debug)]
+pub struct LintConfig {
+    levels: BTreeMap<Lint, LintLevel>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            levels: Lint::ALL
+                .into_iter()
+                .map(|lint| (lint, LintLevel::Warn))
+                .collect(),
+        }
+    }
+}
+
+impl LintConfig {
+    /// 名前で警告のレベルを設定する
+    /// `warnings`はすべての警告を指す
+    pub fn set_level(&mut self, name: &str, level: LintLevel) -> Result<(), LintConfigErr> {
+        if name == "warnings" {
+            for lint in Lint::ALL {
+                self.levels.insert(lint, level);
+            }
+            Ok(())
+        } else if let Some(lint) = Lint::from_name(name) {
+            self.levels.insert(lint, level);
+            Ok(())
+        } else {
+            Err(LintConfigErr {
+                note: format!("unknown lint: `{}`", name),
+            })
+        }
+    }
+
+    pub fn level(&self, lint: Lint) -> LintLevel {
+        self.levels.get(&lint).copied().unwrap_or(LintLevel::Warn)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LintWarning {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Span,
+}
+
+impl LintWarning {
+    /// 設定されたレベルに応じて診断情報に変換する
+    pub fn to_diagnostic(&self, level: LintLevel) -> Diagnostic {
+        let (severity, note) = match level {
+            LintLevel::Warn => (
+                Severity::Warning,
+                format!("`-W {}` is on by default", self.lint.name()),
+            ),
+            LintLevel::Deny => (
+                Severity::Error,
+                format!("requested on the command line with `-D {}`", self.lint.name()),
+            ),
+        };
+        Diagnostic::new(severity, self.lint.name(), &self.message, self.span.into_range())
+            .with_note(&note)
+    }
+}
+
+/// 関数本体の変数や関数の使用状況
+#[derive(Default)]
+struct Usage<'a> {
+    /// letで宣言された変数とそのlet文の位置
+    lets: Vec<(&'a str, Span)>,
+    /// 値として読まれた名前
+    reads: HashSet<&'a str>,
+    /// 呼び出された関数の名前
+    calls: BTreeSet<&'a str>,
+    /// 生のsedブロックを含むかどうか
+    has_sed: bool,
+}
+
+fn collect_usage<'a>(expr: &Spanned<Expr<'a>>, usage: &mut Usage<'a>) {
+    match &expr.0 {
+        Expr::Error => {}
+        Expr::Value(Value::Func(name)) => {
+            usage.calls.insert(name);
+        }
+        Expr::Value(_) => {}
+        Expr::Local(name) => {
+            usage.reads.insert(name);
+        }
+        Expr::Neg(a) => {
+            usage.calls.extend(op_func_names(&BinaryOp::Sub));
+            collect_usage(a, usage);
+        }
+        Expr::Let(name, rhs) => {
+            usage.lets.push((name, expr.1));
+            collect_usage(rhs, usage);
+        }
+        Expr::Then(a, b) => {
+            collect_usage(a, usage);
+            collect_usage(b, usage);
+        }
+        Expr::If(cond, then, else_) => {
+            collect_usage(cond, usage);
+            collect_usage(then, usage);
+            if let Some(else_) = &**else_ {
+                collect_usage(else_, usage);
+            }
+        }
+        Expr::Sed(_) => {
+            usage.has_sed = true;
+        }
+        Expr::Call(f, args) => {
+            if let Expr::Local(name) = &f.0 {
+                usage.calls.insert(name);
+            } else {
+                collect_usage(f, usage);
+            }
+            for arg in &args.0 {
+                collect_usage(arg, usage);
+            }
+        }
+        Expr::Binary(lhs, op, rhs) => {
+            usage.calls.extend(op_func_names(op));
+            collect_usage(lhs, usage);
+            collect_usage(rhs, usage);
+        }
+        // 左辺は書き込みなので読み込みとして数えない
+        Expr::Assign(_, rhs) => collect_usage(rhs, usage),
+        Expr::Return((values, _)) => {
+            for value in values {
+                collect_usage(value, usage);
+            }
+        }
+    }
+}
+
+/// `_`から始まる名前は意図的に使っていないものとみなす
+fn is_intentionally_unused(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// 関数内の変数と引数の使用状況を調べる
+/// 生のsedブロックはスタックを直接読み書きするため、それを含む関数は対象外
+fn lint_func_locals<'a>(func: &Func<'a>, usage: &Usage<'a>, warnings: &mut Vec<LintWarning>) {
+    if usage.has_sed {
+        return;
+    }
+    for (arg, span) in &func.args {
+        if !usage.reads.contains(arg.name) && !is_intentionally_unused(arg.name) {
+            warnings.push(LintWarning {
+                lint: Lint::UnusedArguments,
+                message: format!("unused argument: `{}`", arg.name),
+                span: *span,
+            });
+        }
+    }
+    let mut seen = HashSet::new();
+    for (name, span) in &usage.lets {
+        // 引数と同名のletは引数への代入になる
+        let is_arg = func.args.iter().any(|(arg, _)| arg.name == *name);
+        if is_arg || !seen.insert(*name) {
+            continue;
+        }
+        if !usage.reads.contains(name) && !is_intentionally_unused(name) {
+            warnings.push(LintWarning {
+                lint: Lint::UnusedVariables,
+                message: format!("variable `{}` is assigned but never used", name),
+                span: *span,
+            });
+        }
+    }
+}
+
+/// entryとpub関数を起点に呼び出しグラフを辿り、到達できない非pub関数を報告する
+/// entryが定義されていない場合は何もしない
+fn lint_dead_code(
+    funcs: &[Spanned<Func<'_>>],
+    usages: &[Usage<'_>],
+    entry: &str,
+    warnings: &mut Vec<LintWarning>,
+) {
+    if !funcs.iter().any(|(func, _)| func.name == entry) {
+        return;
+    }
+    let mut reachable: HashSet<&str> = HashSet::new();
+    let mut stack: Vec<&str> = funcs
+        .iter()
+        .filter(|(func, _)| func.public || func.name == entry)
+        .map(|(func, _)| func.name)
+        .collect();
+    while let Some(name) = stack.pop() {
+        if !reachable.insert(name) {
+            continue;
+        }
+        for (i, (func, _)) in funcs.iter().enumerate() {
+            if func.name == name {
+                stack.extend(usages[i].calls.iter().copied());
+            }
+        }
+    }
+    for (func, span) in funcs {
+        if !reachable.contains(func.name) && !is_intentionally_unused(func.name) {
+            warnings.push(LintWarning {
+                lint: Lint::DeadCode,
+                message: format!("function `{}` is never used", func.name),
+                span: *span,
+            });
+        }
+    }
+}
+
+/// 構文木に対してすべての警告を検査する
+/// `entry`は呼び出しグラフの起点となる関数の名前
+pub fn lint_funcs(funcs: &[Spanned<Func<'_>>], entry: &str) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let usages: Vec<Usage> = funcs
+        .iter()
+        .map(|(func, _)| {
+            let mut usage = Usage::default();
+            collect_usage(&func.body, &mut usage);
+            usage
+        })
+        .collect();
+    for ((func, _), usage) in funcs.iter().zip(&usages) {
+        lint_func_locals(func, usage, &mut warnings);
+    }
+    lint_dead_code(funcs, &usages, entry, &mut warnings);
+    warnings
+}
+
+#[cfg(test)]
+mod lint_test {
+    use super::*;
+
+    fn lint_code(code: &str) -> Vec<LintWarning> {
+        let (tokens, _) = lexer_parse(code);
+        let tokens = tokens.expect("failed to tokenize");
+        let funcs = parser_parse(code, &tokens).expect("failed to parse");
+        lint_funcs(&funcs, "entry")
+    }
+
+    #[test]
+    fn lint_test00() {
+        let code = r#"
+fn helper a:bit32, b:bit32 -> bit32 {
+    let unused = 0;
+    let used = 0;
+    used = a;
+    return used;
+}
+
+fn never_called a:bit32 -> bit32 {
+    return a;
+}
+
+pub fn entry a:bit32 -> bit32 {
+    return helper(a, 0);
+}
+"#;
+        let warnings = lint_code(code);
+        let mut found: Vec<(Lint, &str)> = warnings
+            .iter()
+            .map(|w| (w.lint, &code[w.span.into_range()]))
+            .collect();
+        found.sort();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].0, Lint::UnusedVariables);
+        assert_eq!(found[0].1, "let unused = 0;");
+        assert_eq!(found[1], (Lint::UnusedArguments, "b:bit32"));
+        assert_eq!(found[2].0, Lint::DeadCode);
+        assert!(found[2].1.starts_with("fn never_called"));
+    }
+
+    #[test]
+    fn lint_test01() {
+        // sedブロックを含む関数の引数、pub関数から呼ばれる関数、`_`始まりの名前は報告しない
+        let code = r#"
+fn is_empty a:bit32 -> bool {
+    sed ${
+        "s/~$/T/",
+    }$
+}
+
+pub fn lib_func a:bit32, _b:bit32 -> bool {
+    return is_empty(a);
+}
+
+pub fn entry a:bit32 -> bit32 {
+    return a + 1;
+}
+"#;
+        assert!(lint_code(code).is_empty());
+    }
+
+    #[test]
+    fn lint_config_test00() {
+        let mut config = LintConfig::default();
+        assert_eq!(config.level(Lint::DeadCode), LintLevel::Warn);
+        config.set_level("warnings", LintLevel::Deny).unwrap();
+        config.set_level("dead_code", LintLevel::Warn).unwrap();
+        assert_eq!(config.level(Lint::UnusedVariables), LintLevel::Deny);
+        assert_eq!(config.level(Lint::DeadCode), LintLevel::Warn);
+        assert!(config.set_level("no_such_lint", LintLevel::Deny).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! 分割コンパイルのためのオブジェクトファイル
+//! assemble済みのコンパイル単位を、`.`から始まる見出しとテキスト形式のIR(ir_text.rs)で保存する
+//!
+//! ```text
+//! .object 1
+//! .entry entry
+//! .crate-type library
+//! .io-format binary
+//! .number-repr binary
+//! .export double
+//! .builtin add
+//! .extern twice
+//!
+//! pub fn double args=1 locals=0 rets=1 {
+//!     ...
+//! }
+//! ```
+//!
+//! `.export`は他の単位から呼び出せる関数、`.builtin`は組み込み関数の表からリンクした関数、
+//! `.extern`はこの単位で定義されていない呼び出し先
+
+use crate::code_gen::{CrateType, FuncDef, IoFormat, NumberRepr, collect_called_funcs};
+use crate::ir_text::{IrParseErr, parse_funcs, print_funcs};
+
+/// オブジェクトファイルの形式の版
+pub const OBJECT_VERSION: usize = 2;
+
+/// オブジェクトファイルの内容
+#[derive(Clone, Debug)]
+pub struct ObjectFile {
+    pub entry: String,
+    pub crate_type: CrateType,
+    pub io_format: IoFormat,
+    pub number_repr: NumberRepr,
+    pub exports: Vec<String>,
+    /// 組み込み関数の表からリンクした関数 他の単位の同じ関数とまとめられる
+    pub builtins: Vec<String>,
+    pub externs: Vec<String>,
+    /// 組み込み関数をリンクした後の関数の表 assemble時の順に並ぶ
+    pub funcs: Vec<FuncDef>,
+}
+
+/// 関数の表から呼び出されているが定義されていない関数の名前を集める
+pub fn unresolved_calls(funcs: &[FuncDef]) -> Vec<String> {
+    let mut called = Vec::new();
+    for func in funcs {
+        collect_called_funcs(func.proc_contents(), &mut called);
+    }
+    called.sort();
+    called.dedup();
+    called
+        .into_iter()
+        .filter(|name| !funcs.iter().any(|f| f.name() == *name))
+        .map(str::to_string)
+        .collect()
+}
+
+impl ObjectFile {
+    pub fn to_text(&self) -> String {
+        let mut out = format!(".object {}\n", OBJECT_VERSION);
+        out.push_str(&format!(".entry {}\n", self.entry));
+        out.push_str(&format!(
+            ".crate-type {}\n",
+            match self.crate_type {
+                CrateType::Binary => "binary",
+                CrateType::Library => "library",
+            }
+        ));
+        out.push_str(&format!(
+            ".io-format {}\n",
+            repr_name(self.io_format == IoFormat::Decimal)
+        ));
+        out.push_str(&format!(
+            ".number-repr {}\n",
+            repr_name(self.number_repr == NumberRepr::Decimal)
+        ));
+        for name in &self.exports {
+            out.push_str(&format!(".export {}\n", name));
+        }
+        for name in &self.builtins {
+            out.push_str(&format!(".builtin {}\n", name));
+        }
+        for name in &self.externs {
+            out.push_str(&format!(".extern {}\n", name));
+        }
+        out.push('\n');
+        out.push_str(&print_funcs(&self.funcs));
+        out
+    }
+
+    /// 見出しを読み、残りをテキスト形式のIRとして読む
+    /// `.export`が定義されたpub関数であること、`.builtin`が定義された関数であること、
+    /// `.extern`が実際の呼び出し先と一致することを検査する
+    pub fn parse(text: &str) -> Result<Self, IrParseErr> {
+        let mut version = None;
+        let mut entry = None;
+        let mut crate_type = None;
+        let mut io_format = None;
+        let mut number_repr = None;
+        let mut exports = Vec::new();
+        let mut builtins = Vec::new();
+        let mut externs = Vec::new();
+        // 行番号が変わらないように見出しの行は空行にしてIRを読む
+        let mut body = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let Some(directive) = line.trim().strip_prefix('.') else {
+                body.push_str(line);
+                body.push('\n');
+                continue;
+            };
+            body.push('\n');
+            let (key, value) = directive.split_once(' ').unwrap_or((directive, ""));
+            let value = value.trim();
+            let invalid =
+                || IrParseErr::new(line_no, &format!("invalid `.{}` value `{}`", key, value));
+            match key {
+                "object" => version = Some(value.parse::<usize>().map_err(|_| invalid())?),
+                "entry" => entry = Some(value.to_string()),
+                "crate-type" => {
+                    crate_type = Some(match value {
+                        "binary" => CrateType::Binary,
+                        "library" => CrateType::Library,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "io-format" => {
+                    io_format = Some(match value {
+                        "binary" => IoFormat::Binary,
+                        "decimal" => IoFormat::Decimal,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "number-repr" => {
+                    number_repr = Some(match value {
+                        "binary" => NumberRepr::Binary,
+                        "decimal" => NumberRepr::Decimal,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "export" => exports.push(value.to_string()),
+                "builtin" => builtins.push(value.to_string()),
+                "extern" => externs.push(value.to_string()),
+                _ => {
+                    return Err(IrParseErr::new(
+                        line_no,
+                        &format!("unknown directive `.{}`", key),
+                    ));
+                }
+            }
+        }
+        match version {
+            Some(OBJECT_VERSION) => {}
+            Some(version) => {
+                return Err(IrParseErr::new(
+                    1,
+                    &format!("unsupported object version {}", version),
+                ));
+            }
+            None => return Err(IrParseErr::new(1, "missing `.object`")),
+        }
+        let missing = |key: &str| IrParseErr::new(1, &format!("missing `.{}`", key));
+        let funcs = parse_funcs(&body)?;
+        for name in &exports {
+            if !funcs.iter().any(|f| f.name() == name && f.is_public()) {
+                return Err(IrParseErr::new(
+                    1,
+                    &format!(
+                        "exported function `{}` is not a pub function in the object",
+                        name
+                    ),
+                ));
+            }
+        }
+        if let Some(name) = builtins
+            .iter()
+            .find(|name| !funcs.iter().any(|f| f.name() == name.as_str()))
+        {
+            return Err(IrParseErr::new(
+                1,
+                &format!("builtin function `{}` is not defined in the object", name),
+            ));
+        }
+        if unresolved_calls(&funcs) != externs {
+            return Err(IrParseErr::new(
+                1,
+                "`.extern` does not match the calls in the object",
+            ));
+        }
+        Ok(Self {
+            entry: entry.ok_or_else(|| missing("entry"))?,
+            crate_type: crate_type.ok_or_else(|| missing("crate-type"))?,
+            io_format: io_format.ok_or_else(|| missing("io-format"))?,
+            number_repr: number_repr.ok_or_else(|| missing("number-repr"))?,
+            exports,
+            builtins,
+            externs,
+            funcs,
+        })
+    }
+}
+
+fn repr_name(decimal: bool) -> &'static str {
+    if decimal { "decimal" } else { "binary" }
+}
+
+#[cfg(test)]
+mod object_test {
+    use super::*;
+    use crate::code_gen::{CompilerBuilder, link_compile_builder};
+    use crate::compiler::{FrontendOptions, compiler_frontend_with_options};
+
+    fn library(code: &str) -> CompilerBuilder<crate::code_gen::Assembled> {
+        let options = FrontendOptions {
+            crate_type: CrateType::Library,
+            ..FrontendOptions::default()
+        };
+        compiler_frontend_with_options(code, &options)
+            .unwrap()
+            .assemble()
+    }
+
+    #[test]
+    fn object_test00() {
+        // 書き出したオブジェクトを読み戻してリンクしても結果が変わらない
+        let main = library(
+            r#"
+pub fn entry a:bit32 -> bit32 {
+    return double(a);
+}
+"#,
+        );
+        let lib = library(
+            r#"
+pub fn double a:bit32 -> bit32 {
+    return twice(a);
+}
+
+fn twice a:bit32 -> bit32 {
+    return a + a;
+}
+"#,
+        );
+        let text = lib.to_object().to_text();
+        assert!(text.contains(".export double\n"));
+        let object = ObjectFile::parse(&main.to_object().to_text()).unwrap();
+        assert_eq!(object.externs, vec!["double".to_string()]);
+
+        let units = [main, lib];
+        let direct = link_compile_builder(&units).unwrap().generate().unwrap();
+        let units = units
+            .iter()
+            .map(|unit| {
+                CompilerBuilder::from_object(
+                    ObjectFile::parse(&unit.to_object().to_text()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let relinked = link_compile_builder(&units).unwrap().generate().unwrap();
+        assert_eq!(direct, relinked);
+    }
+
+    #[test]
+    fn object_test01() {
+        let text = library(
+            r#"
+pub fn entry a:bit32 -> bit32 {
+    return double(a);
+}
+"#,
+        )
+        .to_object()
+        .to_text();
+
+        let err = ObjectFile::parse(&text.replace(".object 2", ".object 1")).unwrap_err();
+        assert!(err.to_string().contains("unsupported object version 1"));
+        let err = ObjectFile::parse(&text.replace(".extern double\n", "")).unwrap_err();
+        assert!(err.to_string().contains("`.extern`"));
+        let err = ObjectFile::parse(&text.replace(".export entry", ".export other")).unwrap_err();
+        assert!(err.to_string().contains("`other`"));
+        let err = ObjectFile::parse(&text.replace(".entry entry\n", "")).unwrap_err();
+        assert!(err.to_string().contains("missing `.entry`"));
+    }
+}
@@ -0,0 +1,725 @@
+//! 生成したsedのピープホール最適化
+//! sedのスクリプトを一行ずつコマンドに分けてから、隣り合うコマンドの冗長な組を取り除く
+//!
+//! - フレームの値を並べ替えるだけの置換が続く場合は、一つの置換にまとめる
+//! - 次の行のラベルへのジャンプを取り除く
+//! - どこからも参照されないラベルを取り除く
+//!
+//! 解釈できない行はそのまま残し、その行に名前が現れるラベルは参照されているものとして扱う
+
+use std::fmt;
+
+/// フレームの一つの値に一致する正規表現
+const FIELD: &str = "~[^\\~]*";
+
+/// 置換の後方参照の上限
+const MAX_GROUPS: usize = 9;
+
+/// sedの一行のコマンド
+#[derive(Clone, Debug, PartialEq)]
+enum SedCommand {
+    /// 空行とコメント
+    Blank(String),
+    /// `:label`
+    Label(String),
+    /// `b label`, `t label` 行き先がない場合はスクリプトの終わりに移る
+    /// 最後は元の行
+    Branch(char, Option<String>, String),
+    /// フレームの先頭の値だけを並べ替える置換
+    Shuffle(Shuffle),
+    /// 解釈しないコマンド
+    Other(String),
+}
+
+/// 置換の前後に並ぶ値
+/// フレームは先頭の値、間の任意個の値、末尾の値に分けて扱う
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    /// 置換前の先頭からi番目の値
+    Front(usize),
+    /// 置換前の先頭と末尾の間の値すべて
+    Middle,
+    /// 置換前の末尾の値のうちj番目の値
+    Back(usize),
+    /// `~`で始まる定数の値
+    Const(String),
+}
+
+/// フレームの先頭のfront個と末尾のback個の値、その間の値をitemsに並べ直す置換
+#[derive(Clone, Debug, PartialEq)]
+struct Shuffle {
+    front: usize,
+    back: usize,
+    items: Vec<Item>,
+    /// 元の行 まとめなかった置換はそのまま書き出す
+    text: Option<String>,
+}
+
+impl Shuffle {
+    /// 値を変えない置換かどうか
+    fn is_identity(&self) -> bool {
+        self.front == 0 && self.back == 0 && self.items == [Item::Middle]
+    }
+
+    /// selfの後にnextを実行するのと同じ置換
+    /// フレームの値の数が足りずにnextが一致しなくなる場合はNone
+    fn then(&self, next: &Shuffle) -> Option<Shuffle> {
+        let mut first = self.clone();
+        // nextが読む値が間の値に含まれる場合は、その値を先頭と末尾に移してから合わせる
+        if let Some(m) = first.items.iter().position(|item| *item == Item::Middle) {
+            let extra = next.front.saturating_sub(m);
+            first
+                .items
+                .splice(m..m, (first.front..first.front + extra).map(Item::Front));
+            first.front += extra;
+            let m = m + extra;
+            let extra = next.back.saturating_sub(first.items.len() - m - 1);
+            for item in first.items.iter_mut() {
+                if let Item::Back(j) = item {
+                    *j += extra;
+                }
+            }
+            first
+                .items
+                .splice(m + 1..m + 1, (0..extra).map(Item::Back));
+            first.back += extra;
+        }
+        let produced = first.items;
+        let len = produced.len();
+        if len < next.front + next.back {
+            return None;
+        }
+        let items = next
+            .items
+            .iter()
+            .flat_map(|item| match item {
+                Item::Front(i) => vec![produced[*i].clone()],
+                Item::Middle => produced[next.front..len - next.back].to_vec(),
+                Item::Back(j) => vec![produced[len - next.back + j].clone()],
+                Item::Const(data) => vec![Item::Const(data.clone())],
+            })
+            .collect();
+        let mut shuffle = Shuffle {
+            front: first.front,
+            back: first.back,
+            items,
+            text: None,
+        };
+        shuffle.trim();
+        Some(shuffle)
+    }
+
+    /// 間の値と並んだまま残る先頭と末尾の値を、間の値に含める
+    fn trim(&mut self) {
+        let used_once = |items: &[Item], target: &Item| {
+            items.iter().filter(|item| *item == target).count() == 1
+        };
+        while let Some(m) = self.items.iter().position(|item| *item == Item::Middle) {
+            if 0 < self.front
+                && 0 < m
+                && self.items[m - 1] == Item::Front(self.front - 1)
+                && used_once(&self.items, &Item::Front(self.front - 1))
+            {
+                self.items.remove(m - 1);
+                self.front -= 1;
+            } else if 0 < self.back
+                && self.items.get(m + 1) == Some(&Item::Back(0))
+                && used_once(&self.items, &Item::Back(0))
+            {
+                self.items.remove(m + 1);
+                self.back -= 1;
+                for item in self.items.iter_mut() {
+                    if let Item::Back(j) = item {
+                        *j -= 1;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// 置換のコマンドを書く 後方参照が多すぎる場合はNone
+    fn render(&self) -> Option<String> {
+        if let Some(text) = &self.text {
+            return Some(text.clone());
+        }
+        let fronts: Vec<Item> = (0..self.front).map(Item::Front).collect();
+        let backs: Vec<Item> = (0..self.back).map(Item::Back).collect();
+        let mut whole = fronts.clone();
+        whole.push(Item::Middle);
+        // 間の値が結果の端に残る場合は、一致させずにそのまま残す
+        // 結果が元の値すべてで始まる場合は`&`で書く
+        let (inputs, replaced, anchor, matched): (Vec<Item>, &[Item], _, _) =
+            if self.back == 0 && self.items.last() == Some(&Item::Middle) {
+                (fronts, &self.items[..self.items.len() - 1], ("^", ""), "")
+            } else if self.front == 0 && self.items.first() == Some(&Item::Middle) {
+                (backs, &self.items[1..], ("", "$"), "")
+            } else if self.back == 0 && self.items.starts_with(&whole) {
+                (whole, &self.items[self.front + 1..], ("^", "$"), "&")
+            } else {
+                let mut inputs = whole;
+                inputs.extend(backs);
+                (inputs, &self.items[..], ("^", "$"), "")
+            };
+        let used = |input: &Item| replaced.contains(input);
+        // 結果でも続けて並ぶ値を一つのグループにする
+        let joined = |x: &Item, y: &Item| {
+            *x != Item::Middle
+                && *y != Item::Middle
+                && used(x) == used(y)
+                && replaced.iter().enumerate().all(|(k, item)| {
+                    if item == x {
+                        replaced.get(k + 1) == Some(y)
+                    } else if item == y {
+                        0 < k && replaced[k - 1] == *x
+                    } else {
+                        true
+                    }
+                })
+        };
+        let mut pattern = anchor.0.to_string();
+        let mut group_of: Vec<(Item, usize)> = Vec::new();
+        let mut groups = 0;
+        let mut start = 0;
+        while start < inputs.len() {
+            let mut end = start + 1;
+            while end < inputs.len() && joined(&inputs[end - 1], &inputs[end]) {
+                end += 1;
+            }
+            let is_used = used(&inputs[start]);
+            if is_used {
+                groups += 1;
+                group_of.push((inputs[start].clone(), groups));
+                pattern.push_str("\\(");
+            }
+            if inputs[start] == Item::Middle {
+                pattern.push_str(".*");
+            } else if end - start <= 2 {
+                pattern.push_str(&FIELD.repeat(end - start));
+            } else {
+                groups += 1;
+                pattern.push_str(&format!("\\({}\\)\\{{{}\\}}", FIELD, end - start));
+            }
+            if is_used {
+                pattern.push_str("\\)");
+            }
+            start = end;
+        }
+        pattern.push_str(anchor.1);
+        if groups > MAX_GROUPS {
+            return None;
+        }
+        let mut replacement = matched.to_string();
+        for item in replaced {
+            match item {
+                Item::Const(data) => replacement.push_str(data),
+                // グループの先頭以外の値はグループの先頭と一緒に書かれている
+                item => {
+                    if let Some((_, group)) = group_of.iter().find(|(input, _)| input == item) {
+                        replacement.push_str(&format!("\\{}", group));
+                    }
+                }
+            }
+        }
+        Some(format!("s/{}/{}/", pattern, replacement))
+    }
+}
+
+impl fmt::Display for SedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SedCommand::Blank(text) | SedCommand::Other(text) | SedCommand::Branch(_, _, text) => {
+                write!(f, "{}", text)
+            }
+            SedCommand::Label(label) => write!(f, ":{}", label),
+            SedCommand::Shuffle(shuffle) => match shuffle.render() {
+                Some(text) => write!(f, "{}", text),
+                None => Err(fmt::Error),
+            },
+        }
+    }
+}
+
+/// 生成したsedのスクリプトを最適化する
+pub fn optimize(script: &str) -> String {
+    let mut commands = parse_commands(script);
+    while merge_shuffles(&mut commands)
+        | remove_next_line_jumps(&mut commands)
+        | remove_unused_labels(&mut commands)
+    {}
+    let mut out: String = commands
+        .iter()
+        .map(|command| format!("{}\n", command))
+        .collect();
+    if !script.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+fn parse_commands(script: &str) -> Vec<SedCommand> {
+    let mut commands = Vec::new();
+    let mut continued = false;
+    for line in script.lines() {
+        // `a\`などの続きの行はテキストなので解釈しない
+        let command = if continued {
+            SedCommand::Other(line.to_string())
+        } else {
+            parse_command(line)
+        };
+        continued = (line.len() - line.trim_end_matches('\\').len()) % 2 == 1;
+        commands.push(command);
+    }
+    commands
+}
+
+fn parse_command(line: &str) -> SedCommand {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return SedCommand::Blank(line.to_string());
+    }
+    if let Some(label) = trimmed.strip_prefix(':')
+        && is_label(label)
+    {
+        return SedCommand::Label(label.to_string());
+    }
+    let mut chars = trimmed.chars();
+    if let Some(command @ ('b' | 't')) = chars.next() {
+        let label = chars.as_str().trim();
+        if label.is_empty() {
+            return SedCommand::Branch(command, None, line.to_string());
+        }
+        if is_label(label) {
+            return SedCommand::Branch(command, Some(label.to_string()), line.to_string());
+        }
+    }
+    if let Some(shuffle) = parse_shuffle(trimmed) {
+        return SedCommand::Shuffle(Shuffle {
+            text: Some(line.to_string()),
+            ..shuffle
+        });
+    }
+    SedCommand::Other(line.to_string())
+}
+
+fn is_label(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `s/^\(~[^\~]*\)~[^\~]*/\1~0/`や`s/$/~0/`のような、フレームの値だけを扱う置換を読む
+fn parse_shuffle(line: &str) -> Option<Shuffle> {
+    let body = line.strip_prefix("s/")?;
+    let (pattern, rest) = split_unescaped(body)?;
+    let (replacement, flags) = split_unescaped(rest)?;
+    // 空の正規表現は直前の正規表現を意味する
+    if pattern.is_empty() || !flags.is_empty() {
+        return None;
+    }
+    let (pattern, head) = match pattern.strip_prefix('^') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let (pattern, tail) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    // 一致させる値の並びと、グループごとの値の範囲
+    // 値はひとまずすべてFrontとして数え、`.*`の後ろの値は後でBackに直す
+    let mut slots: Vec<Item> = Vec::new();
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut rest = pattern;
+    while !rest.is_empty() {
+        if let Some(next) = rest.strip_prefix(FIELD) {
+            slots.push(Item::Front(slots.len()));
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix(".*") {
+            if slots.contains(&Item::Middle) {
+                return None;
+            }
+            slots.push(Item::Middle);
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix("\\(") {
+            open.push(groups.len());
+            groups.push((slots.len(), slots.len()));
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix("\\)") {
+            let group = open.pop()?;
+            let start = groups[group].0;
+            groups[group].1 = slots.len();
+            rest = next;
+            // 値だけを含むグループの繰り返し 後方参照は最後の繰り返しを指す
+            if let Some(next) = rest.strip_prefix("\\{") {
+                let (count, next) = next.split_once("\\}")?;
+                let count: usize = count.parse().ok()?;
+                let width = slots.len() - start;
+                if group + 1 != groups.len() || slots[start..].contains(&Item::Middle) {
+                    return None;
+                }
+                slots.truncate(start);
+                slots.extend((start..start + width * count).map(Item::Front));
+                groups[group] = (slots.len().saturating_sub(width).max(start), slots.len());
+                rest = next;
+            }
+        } else {
+            return None;
+        }
+    }
+    if !open.is_empty() {
+        return None;
+    }
+
+    // 一致させない残りの部分も間の値として扱う
+    let implicit = match (slots.contains(&Item::Middle), head, tail) {
+        (true, _, _) => None,
+        (false, _, false) => Some(false),
+        (false, false, true) => Some(true),
+        (false, true, true) => return None,
+    };
+    let middle = match implicit {
+        Some(true) => 0,
+        Some(false) => slots.len(),
+        None => slots.iter().position(|slot| *slot == Item::Middle)?,
+    };
+    let front = middle;
+    let back = slots.len() - front - usize::from(implicit.is_none());
+    for slot in slots.iter_mut().skip(middle) {
+        if let Item::Front(i) = slot {
+            *slot = Item::Back(*i - middle - usize::from(implicit.is_none()));
+        }
+    }
+
+    let mut items = Vec::new();
+    if implicit == Some(true) {
+        items.push(Item::Middle);
+    }
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        let referred = match c {
+            '\\' => match chars.next()? {
+                digit @ '1'..='9' => {
+                    let (start, end) = *groups.get(digit.to_digit(10)? as usize - 1)?;
+                    &slots[start..end]
+                }
+                '~' | '0' | 'n' => return None,
+                escaped => {
+                    match items.last_mut() {
+                        Some(Item::Const(data)) => {
+                            data.push('\\');
+                            data.push(escaped);
+                        }
+                        _ => return None,
+                    }
+                    continue;
+                }
+            },
+            '&' => &slots[..],
+            '~' => {
+                items.push(Item::Const("~".to_string()));
+                continue;
+            }
+            ';' | '|' => return None,
+            c => {
+                match items.last_mut() {
+                    Some(Item::Const(data)) => data.push(c),
+                    _ => return None,
+                }
+                continue;
+            }
+        };
+        items.extend(referred.iter().cloned());
+        // 後方参照の直後は次の値の始まりでなければならない
+        if chars
+            .peek()
+            .is_some_and(|c| *c != '~' && *c != '\\' && *c != '&')
+        {
+            return None;
+        }
+    }
+    if implicit == Some(false) {
+        items.push(Item::Middle);
+    }
+    if 1 < items.iter().filter(|item| **item == Item::Middle).count() {
+        return None;
+    }
+    Some(Shuffle {
+        front,
+        back,
+        items,
+        text: None,
+    })
+}
+
+/// エスケープされていない最初の`/`で分ける
+fn split_unescaped(text: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '/' if !escaped => return Some((&text[..i], &text[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// 次のコマンドの位置 空行とコメントは飛ばす
+fn next_command(commands: &[SedCommand], from: usize) -> Option<usize> {
+    (from..commands.len()).find(|&i| !matches!(commands[i], SedCommand::Blank(_)))
+}
+
+/// 前のコマンドの位置 空行とコメントは飛ばす
+fn prev_command(commands: &[SedCommand], before: usize) -> Option<usize> {
+    (0..before)
+        .rev()
+        .find(|&i| !matches!(commands[i], SedCommand::Blank(_)))
+}
+
+/// 並べ替えの置換が続く場合に一つにまとめる
+fn merge_shuffles(commands: &mut Vec<SedCommand>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < commands.len() {
+        let SedCommand::Shuffle(first) = &commands[i] else {
+            i += 1;
+            continue;
+        };
+        let Some(j) = next_command(commands, i + 1) else {
+            break;
+        };
+        let SedCommand::Shuffle(second) = &commands[j] else {
+            i += 1;
+            continue;
+        };
+        let Some(merged) = first.then(second) else {
+            i += 1;
+            continue;
+        };
+        if merged.is_identity() {
+            // 置換が成功したかどうかを後で読まない場合だけ、何もしない置換を取り除ける
+            if flag_overwritten(commands, j + 1) {
+                commands.remove(j);
+                commands.remove(i);
+                changed = true;
+                continue;
+            }
+        } else if merged.render().is_some() {
+            commands[i] = SedCommand::Shuffle(merged);
+            commands.remove(j);
+            changed = true;
+            continue;
+        }
+        i += 1;
+    }
+    changed
+}
+
+/// tコマンドが読む置換の成否が、読まれる前に必ず上書きされるかどうか
+fn flag_overwritten(commands: &[SedCommand], from: usize) -> bool {
+    match next_command(commands, from).map(|i| (i, &commands[i])) {
+        Some((_, SedCommand::Shuffle(_))) => true,
+        // `t label`と`:label`の組は成否にかかわらず次の行へ進み、成否を消す
+        Some((i, SedCommand::Branch('t', Some(label), _))) => is_label_at(commands, i + 1, label),
+        _ => false,
+    }
+}
+
+/// fromから続くラベルの中にlabelがあるかどうか
+fn is_label_at(commands: &[SedCommand], from: usize, label: &str) -> bool {
+    let mut i = from;
+    while let Some(next) = next_command(commands, i) {
+        match &commands[next] {
+            SedCommand::Label(name) if name == label => return true,
+            SedCommand::Label(_) => i = next + 1,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// 次の行のラベルへのジャンプを取り除く
+/// tは置換の成否を消すので、成否が分かっていない場合は残す
+fn remove_next_line_jumps(commands: &mut Vec<SedCommand>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < commands.len() {
+        let removable = match &commands[i] {
+            SedCommand::Branch('b', Some(label), _) => is_label_at(commands, i + 1, label),
+            SedCommand::Branch('t', Some(label), _) => {
+                is_label_at(commands, i + 1, label) && flag_cleared(commands, i)
+            }
+            _ => false,
+        };
+        if removable {
+            commands.remove(i);
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// 位置iで置換の成否が消えていることが分かっているかどうか
+/// 直前が分岐しなかったtか、他から参照されない`t label`と`:label`の組の場合だけ成否は消えている
+fn flag_cleared(commands: &[SedCommand], i: usize) -> bool {
+    let Some(prev) = prev_command(commands, i) else {
+        return false;
+    };
+    match &commands[prev] {
+        SedCommand::Branch('t', Some(_), _) => true,
+        SedCommand::Label(label) => {
+            references(commands, label) == 1
+                && prev_command(commands, prev).is_some_and(|before| {
+                    matches!(&commands[before], SedCommand::Branch('t', Some(name), _) if name == label)
+                })
+        }
+        _ => false,
+    }
+}
+
+/// ラベルの名前が現れる分岐と解釈しない行の数
+fn references(commands: &[SedCommand], label: &str) -> usize {
+    commands
+        .iter()
+        .filter(|command| match command {
+            SedCommand::Branch(_, Some(name), _) => name == label,
+            SedCommand::Other(text) => text.contains(label),
+            _ => false,
+        })
+        .count()
+}
+
+/// どこからも参照されないラベルを取り除く
+fn remove_unused_labels(commands: &mut Vec<SedCommand>) -> bool {
+    let unused: Vec<String> = commands
+        .iter()
+        .filter_map(|command| match command {
+            SedCommand::Label(label) if references(commands, label) == 0 => Some(label.clone()),
+            _ => None,
+        })
+        .collect();
+    let before = commands.len();
+    commands.retain(|command| match command {
+        SedCommand::Label(label) => !unused.contains(label),
+        _ => true,
+    });
+    commands.len() != before
+}
+
+#[cfg(test)]
+mod peephole_test {
+    use super::*;
+
+    #[test]
+    fn peephole_test00() {
+        // 定数を積んでからローカル変数に代入する置換は一つになる
+        let script = [
+            "s/\\(~[^\\~]*~[^\\~]*\\)/\\1~101/",
+            "# comment",
+            "s/\\(~[^\\~]*\\)~[^\\~]*\\(~[^\\~]*\\)/\\1\\2/",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(
+            optimize(&script),
+            "s/^\\(~[^\\~]*\\)~[^\\~]*/\\1~101/\n# comment\n"
+        );
+
+        // 値を複製してから捨てる置換は、成否が上書きされる場合だけ消える
+        let dup = "s/\\(\\)\\(~[^\\~]*\\)\\(~[^\\~]*\\)/\\1\\2\\3\\2/";
+        let drop = "s/\\(~[^\\~]*~[^\\~]*\\)~[^\\~]*/\\1/";
+        let script = [dup, drop, "s/\\(~[^\\~]*\\)/\\1~1/"].join("\n");
+        assert_eq!(optimize(&script), "s/\\(~[^\\~]*\\)/\\1~1/");
+        let script = [dup, drop, "t done", "s/~/x/", ":done"].join("\n");
+        assert_eq!(optimize(&script), script);
+    }
+
+    #[test]
+    fn peephole_test01() {
+        // 次の行へのジャンプと参照されないラベルは消える
+        let script = [
+            "t else0",
+            "b then0",
+            ":then0",
+            "s/x/y/",
+            "b endif0",
+            ":else0",
+            "s/y/x/",
+            "b endif0",
+            ":endif0",
+            "/a/ b endif0",
+        ]
+        .join("\n");
+        assert_eq!(
+            optimize(&script),
+            [
+                "t else0",
+                "s/x/y/",
+                "b endif0",
+                ":else0",
+                "s/y/x/",
+                ":endif0",
+                "/a/ b endif0"
+            ]
+            .join("\n")
+        );
+
+        // 置換の成否が分からない場合は、次の行へのtも成否を消すために残す
+        let script = "s/x/y/\nt reset\n:reset\ns/y/z/\nt end\n:end\nt reset2\n:reset2";
+        assert_eq!(
+            optimize(script),
+            "s/x/y/\nt reset\n:reset\ns/y/z/\nt end\n:end"
+        );
+    }
+
+    #[test]
+    fn peephole_test02() {
+        // 並べ替え以外の置換と続きの行は解釈しない
+        for line in [
+            "s/\\(~[^\\~]*\\)/\\1;/",
+            "s/\\(~[^\\~]*\\)/:retlabel0\\1|/",
+            "s/\\(~[^\\~]*\\)/\\1x/",
+            "s/\\(~[^\\~]*\\)/\\1~0/g",
+            "s/\\(.*\\)~[0]\\+$/\\1/",
+            "s/.*/&&/",
+        ] {
+            assert_eq!(parse_command(line), SedCommand::Other(line.to_string()));
+        }
+        let script = "a\\\nb next\n:next";
+        assert_eq!(
+            parse_commands(script)[1],
+            SedCommand::Other("b next".to_string())
+        );
+    }
+
+    #[test]
+    fn peephole_test03() {
+        // スタックトップに積んでから取り出して代入する置換は、スタックの深さによらず一つになる
+        let script = [
+            "s/$/~101/",
+            "s/^\\(\\(~[^\\~]*\\)\\{3\\}\\)~[^\\~]*\\(.*\\)\\(~[^\\~]*\\)$/\\1\\4\\3/",
+        ]
+        .join("\n");
+        assert_eq!(
+            optimize(&script),
+            "s/^\\(\\(~[^\\~]*\\)\\{3\\}\\)~[^\\~]*/\\1~101/"
+        );
+
+        // 値を複製してから捨てる置換は何もしない
+        let dup = "s/^\\(\\)\\(~[^\\~]*\\).*$/&\\2/";
+        let drop = "s/^\\(.*\\)~[^\\~]*$/\\1/";
+        let script = [dup, drop, "s/$/~1/"].join("\n");
+        assert_eq!(optimize(&script), "s/$/~1/");
+
+        // 複製した値を二つ取り出して呼び出す置換は、複製を含めて一つになる
+        let call = "s/^\\(.*\\)\\(\\(~[^\\~]*\\)\\{2\\}\\)$/:retlabel0\\2\\1|/";
+        let script = [dup, "s/$/~1/", call].join("\n");
+        assert_eq!(
+            optimize(&script),
+            ["s/^\\(~[^\\~]*\\).*$/&\\1~1/", call].join("\n")
+        );
+    }
+}
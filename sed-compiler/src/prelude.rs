@@ -0,0 +1,85 @@
+use sed_compiler_frontend::parser::*;
+
+use crate::code_gen::FuncDef;
+use crate::compiler::build_func_ir;
+use crate::decimal::lookup_funcs;
+
+/// 演算子から呼び出される組み込み関数のsoilのソースコード
+pub const PRELUDE_SOURCE: &str = include_str!("../soil/prelude.soil");
+
+/// 数を10進数で表す場合のpreludeのsoilのソースコード
+pub const DECIMAL_PRELUDE_SOURCE: &str = include_str!("../soil/prelude_decimal.soil");
+
+/// 文字列の組み込み関数のsoilのソースコード 数の表現によらず共通で使う
+pub const STR_PRELUDE_SOURCE: &str = include_str!("../soil/prelude_str.soil");
+
+/// bigintの組み込み関数のsoilのソースコード 絶対値の計算は数の表現ごとのpreludeが定義する
+pub const BIGINT_PRELUDE_SOURCE: &str = include_str!("../soil/prelude_bigint.soil");
+
+/// preludeのすべての関数のIRを構築する
+pub fn prelude_funcs() -> Vec<FuncDef> {
+    let mut funcs = build_source(PRELUDE_SOURCE);
+    funcs.extend(build_source(STR_PRELUDE_SOURCE));
+    funcs.extend(build_source(BIGINT_PRELUDE_SOURCE));
+    funcs
+}
+
+/// 10進数のpreludeのすべての関数のIRを構築する
+/// 対応表をもつ関数はdecimal.rsで生成される
+pub fn decimal_prelude_funcs() -> Vec<FuncDef> {
+    let mut funcs = build_source(DECIMAL_PRELUDE_SOURCE);
+    funcs.extend(build_source(STR_PRELUDE_SOURCE));
+    funcs.extend(build_source(BIGINT_PRELUDE_SOURCE));
+    funcs.extend(lookup_funcs());
+    funcs
+}
+
+fn build_source(source: &str) -> Vec<FuncDef> {
+    let (tokens, _) = lexer_parse(source);
+    let tokens = tokens.expect("failed to tokenize the prelude");
+    let funcs = parser_parse(source, &tokens).expect("failed to parse the prelude");
+    funcs
+        .iter()
+        .map(|(func, _)| build_func_ir(func).expect("failed to build the prelude"))
+        .collect()
+}
+
+#[cfg(test)]
+mod prelude_test {
+    use super::*;
+    use crate::code_gen::{CallFunc, SedInstruction};
+    use crate::embedded::BuiltinRegistry;
+    use crate::verifier::verify_func_table;
+
+    #[test]
+    fn prelude_test00() {
+        // preludeはそれだけで検査を通る
+        assert_eq!(verify_func_table(&prelude_funcs()), Ok(()));
+        assert_eq!(verify_func_table(&decimal_prelude_funcs()), Ok(()));
+    }
+
+    #[test]
+    fn prelude_test01() {
+        // 使われた関数とそれが呼び出す関数だけが追加される
+        let mut entry = FuncDef::new("entry", 2, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Call(CallFunc::new("eq")),
+            SedInstruction::Ret,
+        ]);
+        let mut func_table = vec![entry];
+        BuiltinRegistry::standard().link(&mut func_table);
+        let mut names: Vec<&str> = func_table.iter().map(|f| f.name()).collect();
+        names.sort();
+        assert_eq!(names, vec!["__eq_raw", "__pad32", "entry", "eq"]);
+
+        // ユーザーが定義した関数は置き換えない
+        let mut entry = FuncDef::new("entry", 2, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Call(CallFunc::new("eq")),
+            SedInstruction::Ret,
+        ]);
+        let mut func_table = vec![entry, FuncDef::new("eq", 2, 0, 1)];
+        BuiltinRegistry::standard().link(&mut func_table);
+        assert_eq!(func_table.len(), 2);
+    }
+}
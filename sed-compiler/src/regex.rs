@@ -0,0 +1,554 @@
+//! 正規表現リテラル`/re/`を使う`matches`と`captures`の関数の生成
+//! 正規表現はsedの基本正規表現(GNUの`\+`, `\?`, `\|`を含む)として検査する
+//!
+//! 文字列はstrings.rsの形式でエスケープされているため、そのままでは正規表現と対応しない
+//! 関数の中では値を一文字ずつ2byteの単位に変換してから照合する
+//! 通常の文字cは`~c`、エスケープされた文字`%d`は`;d`になり、
+//! `~`と`;`は単位の先頭にしか現れないため照合が文字の途中から始まることはない
+//! 正規表現も同じ単位に合わせて書き換える
+
+use crate::code_gen::{FuncDef, SedCode, SedInstruction};
+use crate::strings::ESCAPES;
+
+/// sedの後方参照で使える最大のグループの番号
+const MAX_BACKREF: usize = 9;
+
+/// 正規表現の誤り
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegexErr {
+    pub message: String,
+}
+
+impl RegexErr {
+    fn new(message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// 正規表現を使う組み込み関数の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegexKind {
+    /// 一致するかどうかを`bool`で返す
+    Matches,
+    /// 最初に一致した位置のグループを複数の値として返す
+    Captures,
+}
+
+impl RegexKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "matches" => Some(RegexKind::Matches),
+            "captures" => Some(RegexKind::Captures),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            RegexKind::Matches => "__regex_matches",
+            RegexKind::Captures => "__regex_captures",
+        }
+    }
+}
+
+/// 単位に合わせて書き換えた正規表現
+#[derive(Clone, Debug, PartialEq)]
+pub struct Translated {
+    /// sedの`s`や番地の`/`の間にそのまま書ける正規表現
+    pub pattern: String,
+    /// 元の正規表現のグループの番号(1から)に対応する、書き換え後のグループの番号
+    pub groups: Vec<usize>,
+}
+
+/// 量指定子をつけられる直前の要素
+struct Atom {
+    /// patternの中での開始位置
+    start: usize,
+    /// 一つの要素として扱えるかどうか 単位は2byteのため、多くの場合はグループで囲む必要がある
+    single: bool,
+}
+
+struct Translator<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    pattern: String,
+    /// 書き換え後のグループの数
+    total_groups: usize,
+    groups: Vec<usize>,
+    /// 開いているグループ (元の番号、patternでの開始位置)
+    open: Vec<(usize, usize)>,
+    /// 閉じたグループの元の番号
+    closed: Vec<usize>,
+    last: Option<Atom>,
+    /// 正規表現、`\(`、`\|`の直後かどうか
+    at_start: bool,
+}
+
+/// 正規表現を検査し、単位に合わせて書き換える
+pub fn translate(re: &str) -> Result<Translated, RegexErr> {
+    if re.is_empty() {
+        return Err(RegexErr::new("empty regex"));
+    }
+    let mut t = Translator {
+        chars: re.chars().peekable(),
+        pattern: String::new(),
+        total_groups: 0,
+        groups: Vec::new(),
+        open: Vec::new(),
+        closed: Vec::new(),
+        last: None,
+        at_start: true,
+    };
+    while let Some(c) = t.chars.next() {
+        let at_start = std::mem::replace(&mut t.at_start, false);
+        match c {
+            '\\' => {
+                let Some(e) = t.chars.next() else {
+                    return Err(RegexErr::new("trailing backslash"));
+                };
+                match e {
+                    '(' => t.open_group(),
+                    ')' => t.close_group()?,
+                    '|' => {
+                        t.pattern.push_str("\\|");
+                        t.last = None;
+                        t.at_start = true;
+                    }
+                    '{' => t.interval()?,
+                    '+' | '?' if !at_start && t.last.is_some() => t.quantifier(&format!("\\{}", e)),
+                    '1'..='9' => t.backref(e as usize - '0' as usize)?,
+                    'n' => t.literal('\n'),
+                    't' => t.literal('\t'),
+                    '.' | '*' | '[' | ']' | '\\' | '/' | '^' | '$' | '+' | '?' => t.literal(e),
+                    _ => {
+                        return Err(RegexErr::new(&format!("unsupported escape `\\{}`", e)));
+                    }
+                }
+            }
+            '*' if !at_start && t.last.is_some() => t.quantifier("*"),
+            '^' if at_start => {
+                t.pattern.push('^');
+                t.at_start = true;
+            }
+            '$' if t.at_end() => t.pattern.push('$'),
+            '.' => t.push_atom("[~;].", false),
+            '[' => t.bracket()?,
+            _ => t.literal(c),
+        }
+    }
+    if !t.open.is_empty() {
+        return Err(RegexErr::new("unmatched `\\(`"));
+    }
+    Ok(Translated {
+        pattern: t.pattern,
+        groups: t.groups,
+    })
+}
+
+impl Translator<'_> {
+    /// `$`が正規表現、`\)`、`\|`の直前にあるかどうか
+    fn at_end(&self) -> bool {
+        let mut rest = self.chars.clone();
+        match rest.next() {
+            None => true,
+            Some('\\') => matches!(rest.next(), Some(')' | '|')),
+            Some(_) => false,
+        }
+    }
+
+    fn push_atom(&mut self, code: &str, single: bool) {
+        let start = self.pattern.len();
+        self.pattern.push_str(code);
+        self.last = Some(Atom { start, single });
+    }
+
+    fn literal(&mut self, c: char) {
+        let code = match ESCAPES.iter().find(|(from, _)| *from == c) {
+            Some((_, digit)) => format!(";{}", digit),
+            None => format!("~{}", escape_literal(c)),
+        };
+        self.push_atom(&code, false);
+    }
+
+    fn open_group(&mut self) {
+        self.total_groups += 1;
+        self.groups.push(self.total_groups);
+        self.open.push((self.groups.len(), self.pattern.len()));
+        self.pattern.push_str("\\(");
+        self.last = None;
+        self.at_start = true;
+    }
+
+    fn close_group(&mut self) -> Result<(), RegexErr> {
+        let Some((index, start)) = self.open.pop() else {
+            return Err(RegexErr::new("unmatched `\\)`"));
+        };
+        self.pattern.push_str("\\)");
+        self.closed.push(index);
+        self.last = Some(Atom {
+            start,
+            single: true,
+        });
+        Ok(())
+    }
+
+    fn backref(&mut self, index: usize) -> Result<(), RegexErr> {
+        if !self.closed.contains(&index) {
+            return Err(RegexErr::new(&format!(
+                "back reference `\\{}` does not refer to a closed group",
+                index
+            )));
+        }
+        let mapped = self.groups[index - 1];
+        if MAX_BACKREF < mapped {
+            return Err(RegexErr::new("too many groups before the back reference"));
+        }
+        self.push_atom(&format!("\\{}", mapped), true);
+        Ok(())
+    }
+
+    /// 直前の要素に量指定子をつける 2byte以上の要素はグループで囲む
+    fn quantifier(&mut self, quantifier: &str) {
+        let Some(atom) = self.last.take() else {
+            return;
+        };
+        if !atom.single {
+            self.pattern.insert_str(atom.start, "\\(");
+            self.pattern.push_str("\\)");
+            self.total_groups += 1;
+        }
+        self.pattern.push_str(quantifier);
+    }
+
+    /// `\{m\}`, `\{m,\}`, `\{m,n\}`
+    fn interval(&mut self) -> Result<(), RegexErr> {
+        if self.last.is_none() {
+            return Err(RegexErr::new("`\\{` without an operand"));
+        }
+        let mut body = String::new();
+        loop {
+            match self.chars.next() {
+                Some('\\') if self.chars.peek() == Some(&'}') => {
+                    self.chars.next();
+                    break;
+                }
+                Some(c @ ('0'..='9' | ',')) => body.push(c),
+                _ => return Err(RegexErr::new("invalid interval")),
+            }
+        }
+        let bounds: Vec<&str> = body.split(',').collect();
+        let parse = |s: &str| s.parse::<u32>().ok();
+        let valid = match bounds.as_slice() {
+            [m] => parse(m).is_some(),
+            [m, ""] => parse(m).is_some(),
+            [m, n] => matches!((parse(m), parse(n)), (Some(m), Some(n)) if m <= n),
+            _ => false,
+        };
+        if !valid {
+            return Err(RegexErr::new("invalid interval"));
+        }
+        self.quantifier(&format!("\\{{{}\\}}", body));
+        Ok(())
+    }
+
+    /// `[...]`を通常の文字の単位とエスケープされた文字の単位に分ける
+    fn bracket(&mut self) -> Result<(), RegexErr> {
+        let negated = self.chars.peek() == Some(&'^');
+        if negated {
+            self.chars.next();
+        }
+        let mut items: Vec<String> = Vec::new();
+        let mut digits: Vec<char> = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.chars.next() else {
+                return Err(RegexErr::new("unterminated `[`"));
+            };
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            if c == '[' && matches!(self.chars.peek(), Some(':' | '=' | '.')) {
+                let kind = self.chars.next().unwrap();
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some(c) if c == kind && self.chars.peek() == Some(&']') => {
+                            self.chars.next();
+                            break;
+                        }
+                        Some(c) => name.push(c),
+                        None => return Err(RegexErr::new("unterminated `[`")),
+                    }
+                }
+                if kind != ':' {
+                    return Err(RegexErr::new(&format!(
+                        "unsupported bracket item `[{}{}{}]`",
+                        kind, name, kind
+                    )));
+                }
+                let Some(class) = char_class(&name) else {
+                    return Err(RegexErr::new(&format!(
+                        "unknown character class `{}`",
+                        name
+                    )));
+                };
+                for (special, digit) in ESCAPES {
+                    if class(special) {
+                        digits.push(digit);
+                    }
+                }
+                items.push(format!("[:{}:]", name));
+                continue;
+            }
+            let c = match c {
+                '\\' => match self.chars.peek() {
+                    Some('n') => {
+                        self.chars.next();
+                        '\n'
+                    }
+                    Some('t') => {
+                        self.chars.next();
+                        '\t'
+                    }
+                    Some('\\') | Some(']') => self.chars.next().unwrap(),
+                    _ => '\\',
+                },
+                c => c,
+            };
+            let mut rest = self.chars.clone();
+            if rest.next() == Some('-') && rest.peek().is_some_and(|&end| end != ']') {
+                self.chars.next();
+                let end = self.chars.next().unwrap();
+                if end < c {
+                    return Err(RegexErr::new("invalid range end"));
+                }
+                for (special, digit) in ESCAPES {
+                    if c <= special && special <= end {
+                        digits.push(digit);
+                    }
+                }
+                items.push(format!("{}-{}", bracket_char(c), bracket_char(end)));
+                continue;
+            }
+            match ESCAPES.iter().find(|(from, _)| *from == c) {
+                Some((_, digit)) => digits.push(*digit),
+                None => items.push(bracket_char(c)),
+            }
+        }
+        digits.sort();
+        digits.dedup();
+        let ordinary = bracket_items(items);
+
+        let mut sides = Vec::new();
+        if negated {
+            match ordinary {
+                Some(set) => sides.push(format!("~[^{}]", set)),
+                None => sides.push("~.".to_string()),
+            }
+            if digits.len() < ESCAPES.len() {
+                let set: String = digits.iter().collect();
+                if set.is_empty() {
+                    sides.push(";.".to_string());
+                } else {
+                    sides.push(format!(";[^{}]", set));
+                }
+            }
+        } else {
+            match ordinary.as_deref() {
+                Some("^") => sides.push("~\\^".to_string()),
+                Some(set) => sides.push(format!("~[{}]", set)),
+                None => {}
+            }
+            if !digits.is_empty() {
+                sides.push(format!(";[{}]", digits.iter().collect::<String>()));
+            }
+        }
+        match sides.as_slice() {
+            [side] => self.push_atom(side, false),
+            _ => {
+                self.total_groups += 1;
+                self.push_atom(&format!("\\({}\\)", sides.join("\\|")), true);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 正規表現の中で文字そのものを表す
+fn escape_literal(c: char) -> String {
+    match c {
+        '.' | '*' | '[' | ']' | '\\' | '^' | '$' | '/' => format!("\\{}", c),
+        c => c.to_string(),
+    }
+}
+
+/// `[]`の中で文字そのものを表す `/`はsedの区切りのためエスケープする
+fn bracket_char(c: char) -> String {
+    match c {
+        '/' => "\\/".to_string(),
+        c => c.to_string(),
+    }
+}
+
+/// `]`を先頭、`-`を末尾に置き、`^`が先頭にならないように並べる
+/// `^`だけの場合は呼び出し側で扱う
+fn bracket_items(mut items: Vec<String>) -> Option<String> {
+    if items.is_empty() {
+        return None;
+    }
+    items.sort_by_key(|item| match item.as_str() {
+        "]" => 0,
+        "^" => 2,
+        "-" => 3,
+        _ => 1,
+    });
+    Some(items.concat())
+}
+
+/// 文字クラスに含まれるかどうか
+fn char_class(name: &str) -> Option<fn(char) -> bool> {
+    let class: fn(char) -> bool = match name {
+        "alpha" => |c| c.is_ascii_alphabetic(),
+        "digit" => |c| c.is_ascii_digit(),
+        "alnum" => |c| c.is_ascii_alphanumeric(),
+        "upper" => |c| c.is_ascii_uppercase(),
+        "lower" => |c| c.is_ascii_lowercase(),
+        "space" => |c| c.is_ascii_whitespace() || c == '\x0b',
+        "blank" => |c| c == ' ' || c == '\t',
+        "punct" => |c| c.is_ascii_punctuation(),
+        "print" => |c| c.is_ascii_graphic() || c == ' ',
+        "graph" => |c| c.is_ascii_graphic(),
+        "cntrl" => |c| c.is_ascii_control(),
+        "xdigit" => |c| c.is_ascii_hexdigit(),
+        _ => return None,
+    };
+    Some(class)
+}
+
+/// 関数の名前 同じ正規表現からは同じ名前になる
+pub fn regex_func_name(kind: RegexKind, re: &str) -> String {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in re.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{}_{:016x}", kind.prefix(), hash)
+}
+
+/// 値を単位に変換するsed
+fn encode_lines() -> Vec<String> {
+    vec![
+        "s/^~//".to_string(),
+        "s/./~&/g".to_string(),
+        "s/~%~\\([0-4]\\)/;\\1/g".to_string(),
+    ]
+}
+
+/// 正規表現から関数を生成する
+pub fn regex_func(kind: RegexKind, re: &str) -> Result<FuncDef, RegexErr> {
+    let translated = translate(re)?;
+    let name = regex_func_name(kind, re);
+    let mut lines = encode_lines();
+    let func = match kind {
+        RegexKind::Matches => {
+            lines.extend([
+                format!("/{}/b {}_yes", translated.pattern, name),
+                "s/.*/~0;/".to_string(),
+                format!("b {}_end", name),
+                format!(":{}_yes", name),
+                "s/.*/~1;/".to_string(),
+                format!(":{}_end", name),
+            ]);
+            FuncDef::new(&name, 1, 0, 1)
+        }
+        RegexKind::Captures => {
+            if translated.groups.is_empty() {
+                return Err(RegexErr::new("`captures` needs at least one group"));
+            }
+            if let Some(index) = translated.groups.iter().position(|&g| MAX_BACKREF < g) {
+                return Err(RegexErr::new(&format!(
+                    "group {} cannot be captured: too many groups before it",
+                    index + 1
+                )));
+            }
+            // 一致した部分をグループごとに`|`で区切り、前後を改行で区切る
+            // 単位に変換した値には`|`と改行が含まれない
+            let replacement: String = translated
+                .groups
+                .iter()
+                .map(|g| format!("|\\{}", g))
+                .collect();
+            let retc = translated.groups.len();
+            lines.extend([
+                format!("t {}_reset", name),
+                format!(":{}_reset", name),
+                format!("s/{}/\\n{}\\n/", translated.pattern, replacement),
+                format!("t {}_found", name),
+                format!("s/.*/{};/", "~".repeat(retc)),
+                format!("b {}_end", name),
+                format!(":{}_found", name),
+                "s/.*\\n\\(|.*\\)\\n.*/\\1/".to_string(),
+                "s/;\\([0-4]\\)/%\\1/g".to_string(),
+                "s/~\\(.\\)/\\1/g".to_string(),
+                "s/|/~/g".to_string(),
+                "s/$/;/".to_string(),
+                format!(":{}_end", name),
+            ]);
+            FuncDef::new(&name, 1, 0, retc)
+        }
+    };
+    let mut func = func;
+    func.set_proc_contents(
+        lines
+            .into_iter()
+            .map(|line| SedInstruction::Sed(SedCode(line)))
+            .collect(),
+    );
+    Ok(func)
+}
+
+#[cfg(test)]
+mod regex_test {
+    use super::*;
+
+    #[test]
+    fn regex_test00() {
+        // 文字は単位になり、量指定子のつく単位はグループで囲まれる
+        let t = translate("a.c").unwrap();
+        assert_eq!(t.pattern, "~a[~;].~c");
+        let t = translate("^\\([a-z]*\\)=\\(.*\\)$").unwrap();
+        assert_eq!(t.pattern, "^\\(\\(~[a-z]\\)*\\)~=\\(\\([~;].\\)*\\)$");
+        assert_eq!(t.groups, vec![1, 3]);
+
+        // 区切り文字はエスケープされた単位になる
+        let t = translate("a;b\\n").unwrap();
+        assert_eq!(t.pattern, "~a;2~b;4");
+        let t = translate("[^;a]").unwrap();
+        assert_eq!(t.pattern, "\\(~[^a]\\|;[^2]\\)");
+        let t = translate("x/y\\/").unwrap();
+        assert_eq!(t.pattern, "~x~\\/~y~\\/");
+    }
+
+    #[test]
+    fn regex_test01() {
+        for (re, message) in [
+            ("", "empty regex"),
+            ("\\(a", "unmatched `\\(`"),
+            ("a\\)", "unmatched `\\)`"),
+            ("[a", "unterminated `[`"),
+            (
+                "\\1\\(a\\)",
+                "back reference `\\1` does not refer to a closed group",
+            ),
+            ("a\\{3,1\\}", "invalid interval"),
+            ("\\w", "unsupported escape `\\w`"),
+            ("[[:foo:]]", "unknown character class `foo`"),
+        ] {
+            assert_eq!(translate(re), Err(RegexErr::new(message)), "{}", re);
+        }
+        assert!(regex_func(RegexKind::Captures, "abc").is_err());
+    }
+}
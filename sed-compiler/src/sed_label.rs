@@ -0,0 +1,215 @@
+//! 生のsedのラベルと分岐先
+//! `sed ${ ... }$`のラベルは関数ごとの名前空間に置く
+//!
+//! - 同じ関数の中のsedブロックどうしは、互いのラベルに分岐できる
+//! - 生成するときにすべてのラベルと分岐先に`__f{関数のID}`を付ける
+//!   生成したラベル(`func{n}`, `retlabel{n}`, `then{n}`, `done`など)は`__`を含まないので衝突しない
+//! - ラベルの重複と未定義のラベルへの分岐はverifierが報告する
+
+/// 行の中のラベルの使われ方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LabelUse {
+    /// `:label`
+    Def,
+    /// `b`, `t`, `T`の分岐先
+    Branch,
+}
+
+/// 一行のsedのラベルの定義と分岐先の位置
+/// 行を`;`と`{`, `}`で区切ったコマンドごとに、アドレスと`!`を読み飛ばして見る
+/// - `:label`のラベルは`;`か空白で、分岐先は`;`か`}`で終わる
+/// - `s`と`y`の中と正規表現のアドレスの中の`;`は区切りとみなさない
+/// - `a`, `i`, `c`, `r`, `w`などの行末までを引数にとるコマンドの後ろと、コメントは読まない
+/// - ラベルのない分岐(スクリプトの終わりへの分岐)は含めない
+pub(crate) fn line_labels(line: &str) -> Vec<(LabelUse, usize, usize)> {
+    let bytes = line.as_bytes();
+    let skip_spaces = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        i
+    };
+    // 区切り文字の次の位置 `\`の次の文字は区切りとみなさない
+    let skip_delimited = |mut i: usize, delim: u8| {
+        while i < bytes.len() && bytes[i] != delim {
+            i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+        (i + 1).min(bytes.len())
+    };
+    let skip_address = |i: usize| match bytes.get(i) {
+        Some(b'/') => skip_delimited(i + 1, b'/'),
+        _ => {
+            let mut i = i;
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || matches!(bytes[i], b'$' | b'~'))
+            {
+                i += 1;
+            }
+            i
+        }
+    };
+    let mut labels = Vec::new();
+    let mut i = 0;
+    loop {
+        i = skip_spaces(i);
+        if matches!(bytes.get(i), Some(b';' | b'{' | b'}')) {
+            i += 1;
+            continue;
+        }
+        i = skip_address(i);
+        if bytes.get(i) == Some(&b',') {
+            i = skip_address(skip_spaces(i + 1));
+        }
+        i = skip_spaces(i);
+        if bytes.get(i) == Some(&b'!') {
+            i = skip_spaces(i + 1);
+        }
+        match bytes.get(i) {
+            None => break,
+            Some(b':') => {
+                let start = skip_spaces(i + 1);
+                let end = line[start..]
+                    .find(|c: char| c == ';' || c.is_ascii_whitespace())
+                    .map_or(line.len(), |offset| start + offset);
+                if start < end {
+                    labels.push((LabelUse::Def, start, end));
+                }
+                i = end;
+            }
+            Some(b'b' | b't' | b'T') => {
+                let start = skip_spaces(i + 1);
+                let next = line[start..]
+                    .find([';', '}'])
+                    .map_or(line.len(), |offset| start + offset);
+                let end = start + line[start..next].trim_end().len();
+                if start < end {
+                    labels.push((LabelUse::Branch, start, end));
+                }
+                i = next;
+            }
+            Some(&command @ (b's' | b'y')) => {
+                let Some(&delim) = bytes.get(i + 1) else {
+                    break;
+                };
+                i = skip_delimited(skip_delimited(i + 2, delim), delim);
+                // `w`フラグは行末までがファイル名
+                while i < bytes.len() && !matches!(bytes[i], b';' | b'}') {
+                    if command == b's' && bytes[i] == b'w' {
+                        return labels;
+                    }
+                    i += 1;
+                }
+            }
+            Some(b'a' | b'i' | b'c' | b'r' | b'R' | b'w' | b'W' | b'e' | b'#') => break,
+            Some(_) => i += 1,
+        }
+    }
+    labels
+}
+
+/// ラベルの後ろにsuffixを付ける
+/// labelsは一つの行のline_labelsの結果のうち、名前を変えるもの
+fn add_suffix(line: &str, labels: &[(LabelUse, usize, usize)], suffix: &str) -> String {
+    let mut renamed = String::with_capacity(line.len());
+    let mut last = 0;
+    for (_, _, end) in labels {
+        renamed.push_str(&line[last..*end]);
+        renamed.push_str(suffix);
+        last = *end;
+    }
+    renamed.push_str(&line[last..]);
+    renamed
+}
+
+/// 本体で定義されているラベルと、それへの分岐先にsuffixを付ける
+pub(crate) fn rename_labels(lines: &[&str], suffix: &str) -> Vec<String> {
+    let defined: Vec<&str> = lines
+        .iter()
+        .flat_map(|line| {
+            line_labels(line)
+                .into_iter()
+                .filter(|(kind, _, _)| *kind == LabelUse::Def)
+                .map(|(_, start, end)| &line[start..end])
+        })
+        .collect();
+    lines
+        .iter()
+        .map(|line| {
+            let labels: Vec<_> = line_labels(line)
+                .into_iter()
+                .filter(|(_, start, end)| defined.contains(&&line[*start..*end]))
+                .collect();
+            add_suffix(line, &labels, suffix)
+        })
+        .collect()
+}
+
+/// すべてのラベルと分岐先にsuffixを付ける
+/// 分岐先がすべて同じ関数の中で定義されていることはverifierで確かめておく
+pub(crate) fn namespace_labels(code: &str, suffix: &str) -> String {
+    code.lines()
+        .map(|line| add_suffix(line, &line_labels(line), suffix))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod sed_label_test {
+    use super::*;
+
+    fn labels(line: &str, kind: LabelUse) -> Vec<&str> {
+        line_labels(line)
+            .into_iter()
+            .filter(|(k, _, _)| *k == kind)
+            .map(|(_, start, end)| &line[start..end])
+            .collect()
+    }
+
+    #[test]
+    fn sed_label_test00() {
+        let defs = |line| labels(line, LabelUse::Def);
+        assert_eq!(defs(":loop"), vec!["loop"]);
+        assert_eq!(defs("  : loop  "), vec!["loop"]);
+        assert_eq!(defs(":a;N"), vec!["a"]);
+        assert_eq!(defs("N;:a;s/x/y/;:b"), vec!["a", "b"]);
+        assert!(defs(":").is_empty());
+        assert!(defs("s/:a/b/").is_empty());
+
+        let targets = |line| labels(line, LabelUse::Branch);
+        assert_eq!(targets("b loop"), vec!["loop"]);
+        assert_eq!(targets("/x/ !{ tother }"), vec!["other"]);
+        assert_eq!(targets("$Tend;n"), vec!["end"]);
+        assert_eq!(targets("s/^~0/~/;t loop"), vec!["loop"]);
+        assert_eq!(
+            targets("/a/{s/x/y/;b one};1,/b;c/ t two;n"),
+            vec!["one", "two"]
+        );
+        assert!(targets("b").is_empty());
+        assert!(targets("s/b x/y/").is_empty());
+        assert!(targets("s/a;t x/b/g;y/;/b/").is_empty());
+        assert!(targets("a text; b x").is_empty());
+    }
+
+    #[test]
+    fn sed_label_test01() {
+        // ラベルのない分岐はそのまま
+        let code = ":loop\ns/~1/~0/\nt loop\n/x/b done\nb\n:done";
+        assert_eq!(
+            namespace_labels(code, "__f3"),
+            ":loop__f3\ns/~1/~0/\nt loop__f3\n/x/b done__f3\nb\n:done__f3"
+        );
+    }
+
+    #[test]
+    fn sed_label_test02() {
+        // `;`で区切った後ろのコマンドのラベルも名前を変える
+        let code = ":loop;s/^~0/~/;t loop\n/x/{s/a/b/;b done};:done";
+        assert_eq!(
+            namespace_labels(code, "__f3"),
+            ":loop__f3;s/^~0/~/;t loop__f3\n/x/{s/a/b/;b done__f3};:done__f3"
+        );
+        assert_eq!(
+            rename_labels(&["s/x/y/;:a", "t a;b other"], "_1"),
+            vec!["s/x/y/;:a_1", "t a_1;b other"]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! 文字列の値の表現
+//! `~`と`;`はスタックの区切り、`|`は組み込み関数の作業用の区切りに使うため、
+//! 文字列の中ではそれぞれを`%`と数字の組に置き換えて保持する
+//! 開始地点の`str`の引数と返り値もこの形になる
+
+/// 置き換える文字と、置き換えた後の数字
+pub(crate) const ESCAPES: [(char, char); 5] = [('%', '0'), ('~', '1'), (';', '2'), ('|', '3'), ('\n', '4')];
+
+/// 文字列をsedの中で扱う形に変換する
+pub fn escape_str(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match ESCAPES.iter().find(|(from, _)| *from == c) {
+            Some((_, digit)) => {
+                escaped.push('%');
+                escaped.push(*digit);
+            }
+            None => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// escape_strで変換した文字列を元に戻す
+/// 対応しない`%`の組がある場合はNone
+pub fn unescape_str(escaped: &str) -> Option<String> {
+    let mut text = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            let digit = chars.next()?;
+            let (from, _) = ESCAPES.iter().find(|(_, d)| *d == digit)?;
+            text.push(*from);
+        } else {
+            text.push(c);
+        }
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod strings_test {
+    use super::*;
+
+    #[test]
+    fn strings_test00() {
+        let text = "100% ~a;b|c\nd";
+        let escaped = escape_str(text);
+        assert_eq!(escaped, "100%0 %1a%2b%3c%4d");
+        assert_eq!(unescape_str(&escaped), Some(text.to_string()));
+        assert_eq!(unescape_str("%9"), None);
+        assert_eq!(unescape_str("%"), None);
+    }
+}
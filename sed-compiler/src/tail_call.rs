@@ -0,0 +1,147 @@
+//! 末尾呼び出しの最適化
+//! `return f(...)`のように呼び出しの直後に`Ret`が続き、スタックに呼び出しの引数しかない呼び出しを
+//! TailCallに置き換える
+//! TailCallはホールドスペースにフレームを積まず、現在のフレームを呼び出し先のものに置き換えて移る
+//! 再帰呼び出しのループでホールドスペースが伸びず、戻るときも一度のreturn dispatcherで済む
+
+use std::collections::HashMap;
+
+use crate::code_gen::{FuncDef, IfProc, SedInstruction};
+
+/// すべての関数の末尾呼び出しを置き換え、置き換えた呼び出しの数を返す
+/// 定義の見つからない関数の呼び出しは置き換えない
+pub(crate) fn lower_tail_calls(func_table: &mut [FuncDef]) -> usize {
+    let signatures: HashMap<String, (usize, usize)> = func_table
+        .iter()
+        .map(|f| (f.name().to_string(), (f.argc(), f.retc())))
+        .collect();
+    let mut count = 0;
+    for func_def in func_table.iter_mut() {
+        let mut lowering = Lowering {
+            retc: func_def.retc(),
+            signatures: &signatures,
+            count: 0,
+        };
+        let Some(proc_contents) = lowering.block(func_def.proc_contents(), 0) else {
+            continue;
+        };
+        if lowering.count == 0 {
+            continue;
+        }
+        count += lowering.count;
+        let mut lowered = FuncDef::new(
+            func_def.name(),
+            func_def.argc(),
+            func_def.localc(),
+            func_def.retc(),
+        );
+        lowered.set_public(func_def.is_public());
+        lowered.set_inline(func_def.is_inline());
+        lowered.set_const(func_def.is_const());
+        lowered.set_span(func_def.span().cloned());
+        lowered.set_proc_contents(proc_contents);
+        *func_def = lowered;
+    }
+    count
+}
+
+struct Lowering<'a> {
+    /// 置き換える関数の返り値の個数
+    retc: usize,
+    signatures: &'a HashMap<String, (usize, usize)>,
+    count: usize,
+}
+
+impl Lowering<'_> {
+    /// 命令列を置き換える
+    /// スタックの深さを追えない命令(生のsed)を含む場合はNone
+    fn block(
+        &mut self,
+        proc_contents: &[SedInstruction],
+        mut depth: usize,
+    ) -> Option<Vec<SedInstruction>> {
+        let mut out = Vec::new();
+        let mut instructions = proc_contents.iter().peekable();
+        while let Some(instruction) = instructions.next() {
+            match instruction {
+                SedInstruction::Sed(_) => return None,
+                SedInstruction::Call(call_func) => {
+                    let &(argc, retc) = self.signatures.get(call_func.func_name())?;
+                    if depth == argc
+                        && retc == self.retc
+                        && matches!(instructions.peek(), Some(SedInstruction::Ret))
+                    {
+                        instructions.next();
+                        out.push(SedInstruction::TailCall(call_func.clone()));
+                        self.count += 1;
+                        continue;
+                    }
+                    depth = (depth + retc).checked_sub(argc)?;
+                }
+                SedInstruction::InlineSed(inline) => {
+                    depth = (depth + inline.retc()).checked_sub(inline.argc())?;
+                }
+                SedInstruction::Val(_) | SedInstruction::ConstVal(_) => depth += 1,
+                SedInstruction::Set(_) => depth = depth.checked_sub(1)?,
+                SedInstruction::IfProc(if_proc) => {
+                    depth = depth.checked_sub(1)?;
+                    let then_proc = self.block(if_proc.then_proc(), depth)?;
+                    let else_proc = self.block(if_proc.else_proc(), depth)?;
+                    out.push(SedInstruction::IfProc(IfProc::new(then_proc, else_proc)));
+                    continue;
+                }
+                SedInstruction::Ret | SedInstruction::TailCall(_) => {}
+            }
+            out.push(instruction.clone());
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tail_call_test {
+    use super::*;
+    use crate::code_gen::{CallFunc, ConstVal, Value};
+
+    fn call(name: &str) -> SedInstruction {
+        SedInstruction::Call(CallFunc::new(name))
+    }
+
+    #[test]
+    fn tail_call_test00() {
+        // 他の値がスタックに残っている呼び出しと返り値の個数が異なる呼び出しは置き換えない
+        let mut entry = FuncDef::new("entry", 1, 0, 1);
+        entry.set_proc_contents(vec![
+            SedInstruction::Val(Value::Arg(0)),
+            SedInstruction::IfProc(IfProc::new(
+                vec![
+                    SedInstruction::Val(Value::Arg(0)),
+                    call("f"),
+                    SedInstruction::Ret,
+                ],
+                vec![
+                    SedInstruction::ConstVal(ConstVal::new("1")),
+                    SedInstruction::Val(Value::Arg(0)),
+                    call("g"),
+                    SedInstruction::Ret,
+                ],
+            )),
+        ]);
+        let mut f = FuncDef::new("f", 1, 0, 1);
+        f.set_proc_contents(vec![
+            SedInstruction::ConstVal(ConstVal::new("1")),
+            SedInstruction::Val(Value::Arg(0)),
+            call("g"),
+            call("f"),
+            SedInstruction::Ret,
+        ]);
+        let mut g = FuncDef::new("g", 1, 0, 0);
+        g.set_proc_contents(vec![SedInstruction::Ret]);
+        let mut func_table = vec![entry, f, g];
+        assert_eq!(lower_tail_calls(&mut func_table), 2);
+        let text = crate::ir_text::print_funcs(&func_table);
+        assert_eq!(text.matches("call.tail f").count(), 2);
+        assert!(text.contains("    call g\n"));
+        assert_eq!(crate::verifier::verify_func_table(&func_table), Ok(()));
+    }
+}
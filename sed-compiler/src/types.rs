@@ -0,0 +1,430 @@
+//! 式の型の推論と、演算子が呼び出す組み込み関数の選択
+//! 型は宣言された名前のまま扱い、整数型だけを区別する
+
+use std::collections::HashMap;
+
+use sed_compiler_frontend::parser::*;
+
+/// 演算子の計算に使う整数型
+/// `bit32`は`u32`、`bit64`は`u64`として扱う
+/// `bigint`は幅の決まらない符号付きの整数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntType {
+    U32,
+    I32,
+    U64,
+    I64,
+    Big,
+}
+
+impl IntType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bit32" | "u32" => Some(IntType::U32),
+            "i32" => Some(IntType::I32),
+            "bit64" | "u64" => Some(IntType::U64),
+            "i64" => Some(IntType::I64),
+            "bigint" => Some(IntType::Big),
+            _ => None,
+        }
+    }
+
+    /// 幅 `bigint`の場合はNone
+    pub fn bits(self) -> Option<usize> {
+        match self {
+            IntType::U32 | IntType::I32 => Some(32),
+            IntType::U64 | IntType::I64 => Some(64),
+            IntType::Big => None,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, IntType::I32 | IntType::I64 | IntType::Big)
+    }
+}
+
+/// 演算子が呼び出す関数の名前
+/// checkedの場合、桁あふれや0による除算でスクリプトを中断する関数を呼び出す
+/// `bigint`は桁あふれせず、0による除算は常に中断するためcheckedによらない
+pub fn op_func_name(op: &BinaryOp, ty: IntType, checked: bool) -> &'static str {
+    use BinaryOp::*;
+    use IntType::*;
+    match (op, ty, checked) {
+        (Add, Big, _) => "big_add",
+        (Sub, Big, _) => "big_sub",
+        (Mul, Big, _) => "big_mul",
+        (Div, Big, _) => "big_div",
+        (Mod, Big, _) => "big_mod",
+        (Eq, Big, _) => "big_eq",
+        (NotEq, Big, _) => "big_neq",
+        (Lt, Big, _) => "big_lt",
+        (LtEq, Big, _) => "big_le",
+        (Gt, Big, _) => "big_gt",
+        (GtEq, Big, _) => "big_ge",
+        (Add, U32 | I32, false) => "add",
+        (Add, U64 | I64, false) => "add64",
+        (Sub, U32 | I32, false) => "sub32",
+        (Sub, U64 | I64, false) => "sub64",
+        (Mul, U32 | I32, false) => "mul32",
+        (Mul, U64 | I64, false) => "mul64",
+        (Div, U32, false) => "div",
+        (Div, I32, false) => "div_i32",
+        (Div, U64, false) => "div_u64",
+        (Div, I64, false) => "div_i64",
+        (Mod, U32, false) => "mod",
+        (Mod, I32, false) => "mod_i32",
+        (Mod, U64, false) => "mod_u64",
+        (Mod, I64, false) => "mod_i64",
+        (Add, U32, true) => "add_checked_u32",
+        (Add, I32, true) => "add_checked_i32",
+        (Add, U64, true) => "add_checked_u64",
+        (Add, I64, true) => "add_checked_i64",
+        (Sub, U32, true) => "sub_checked_u32",
+        (Sub, I32, true) => "sub_checked_i32",
+        (Sub, U64, true) => "sub_checked_u64",
+        (Sub, I64, true) => "sub_checked_i64",
+        (Mul, U32, true) => "mul_checked_u32",
+        (Mul, I32, true) => "mul_checked_i32",
+        (Mul, U64, true) => "mul_checked_u64",
+        (Mul, I64, true) => "mul_checked_i64",
+        (Div, U32, true) => "div_checked_u32",
+        (Div, I32, true) => "div_checked_i32",
+        (Div, U64, true) => "div_checked_u64",
+        (Div, I64, true) => "div_checked_i64",
+        (Mod, U32, true) => "mod_checked_u32",
+        (Mod, I32, true) => "mod_checked_i32",
+        (Mod, U64, true) => "mod_checked_u64",
+        (Mod, I64, true) => "mod_checked_i64",
+        (Eq, U32 | I32, _) => "eq",
+        (Eq, U64 | I64, _) => "eq64",
+        (NotEq, U32 | I32, _) => "neq",
+        (NotEq, U64 | I64, _) => "neq64",
+        (Lt, U32, _) => "lt_u32",
+        (Lt, I32, _) => "lt_i32",
+        (Lt, U64, _) => "lt_u64",
+        (Lt, I64, _) => "lt_i64",
+        (LtEq, U32, _) => "le_u32",
+        (LtEq, I32, _) => "le_i32",
+        (LtEq, U64, _) => "le_u64",
+        (LtEq, I64, _) => "le_i64",
+        (Gt, U32, _) => "gt_u32",
+        (Gt, I32, _) => "gt_i32",
+        (Gt, U64, _) => "gt_u64",
+        (Gt, I64, _) => "gt_i64",
+        (GtEq, U32, _) => "ge_u32",
+        (GtEq, I32, _) => "ge_i32",
+        (GtEq, U64, _) => "ge_u64",
+        (GtEq, I64, _) => "ge_i64",
+    }
+}
+
+/// 演算子が呼び出す可能性のあるすべての関数の名前
+pub fn op_func_names(op: &BinaryOp) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    for ty in [
+        IntType::U32,
+        IntType::I32,
+        IntType::U64,
+        IntType::I64,
+        IntType::Big,
+    ] {
+        for checked in [false, true] {
+            let name = op_func_name(op, ty, checked);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// 二つの型をまとめる
+/// 型の分からない値(整数リテラルなど)はもう一方の型になる
+/// `bit32`と`bit64`はそれぞれ同じ幅の整数型になる
+/// 異なる整数型の場合はErr
+fn unify<'a>(lhs: Option<&'a str>, rhs: Option<&'a str>) -> Result<Option<&'a str>, ()> {
+    match (lhs, rhs) {
+        (None, ty) | (ty, None) => Ok(ty),
+        (Some(lhs), Some(rhs)) if lhs == rhs => Ok(Some(lhs)),
+        (Some(lhs), Some(rhs)) => match (IntType::from_name(lhs), IntType::from_name(rhs)) {
+            (Some(a), Some(b)) if a.bits() == b.bits() && lhs.starts_with("bit") => Ok(Some(rhs)),
+            (Some(a), Some(b)) if a.bits() == b.bits() && rhs.starts_with("bit") => Ok(Some(lhs)),
+            (Some(_), Some(_)) => Err(()),
+            // 整数型以外は区別しない
+            _ => Ok(Some(lhs)),
+        },
+    }
+}
+
+/// 型の推論に使う関数の引数と返り値の型
+#[derive(Debug, Default)]
+pub struct Signatures<'a> {
+    funcs: HashMap<&'a str, (Vec<&'a str>, Vec<&'a str>)>,
+}
+
+impl<'a> Signatures<'a> {
+    pub fn new<'f>(funcs: impl IntoIterator<Item = &'f Func<'a>>) -> Self
+    where
+        'a: 'f,
+    {
+        let funcs = funcs
+            .into_iter()
+            .map(|func| {
+                let args = func.args.iter().map(|(arg, _)| arg.type_).collect();
+                let rtype = func.rtype.iter().map(|(type_, _)| *type_).collect();
+                (func.name, (args, rtype))
+            })
+            .collect();
+        Self { funcs }
+    }
+
+    fn args(&self, name: &str) -> Option<&[&'a str]> {
+        self.funcs.get(name).map(|(args, _)| args.as_slice())
+    }
+
+    fn rtype(&self, name: &str) -> Option<&[&'a str]> {
+        self.funcs.get(name).map(|(_, rtype)| rtype.as_slice())
+    }
+}
+
+/// 関数の中で使う型の情報
+pub struct TypeEnv<'s, 'a> {
+    signatures: &'s Signatures<'a>,
+    /// 引数とローカル変数の型
+    vars: HashMap<&'a str, &'a str>,
+    /// この関数の返り値の型
+    rtype: Vec<&'a str>,
+    /// 桁あふれを検査する演算子を使うかどうか
+    pub checked: bool,
+}
+
+impl<'s, 'a> TypeEnv<'s, 'a> {
+    /// ローカル変数の型は最初に代入された値から決める
+    pub fn new(func: &Func<'a>, signatures: &'s Signatures<'a>, checked: bool) -> Self {
+        let mut env = Self {
+            signatures,
+            vars: func.args.iter().map(|(arg, _)| (arg.name, arg.type_)).collect(),
+            rtype: func.rtype.iter().map(|(type_, _)| *type_).collect(),
+            checked,
+        };
+        env.collect_local_types(&func.body.0);
+        env
+    }
+
+    fn collect_local_types(&mut self, expr: &Expr<'a>) {
+        match expr {
+            Expr::Let(name, rhs) => {
+                if !self.vars.contains_key(name)
+                    && let Some(ty) = self.infer(&rhs.0)
+                {
+                    self.vars.insert(name, ty);
+                }
+            }
+            Expr::Assign((lhs, _), rhs) => {
+                let types: Vec<Option<&'a str>> = match &rhs.0 {
+                    Expr::Call(f, _) if 1 < lhs.len() => match &f.0 {
+                        Expr::Local(name) => self
+                            .signatures
+                            .rtype(name)
+                            .map(|rtype| rtype.iter().map(|ty| Some(*ty)).collect())
+                            .unwrap_or_default(),
+                        _ => vec![],
+                    },
+                    _ => vec![self.infer(&rhs.0)],
+                };
+                for ((value, _), ty) in lhs.iter().zip(types) {
+                    if let (Expr::Local(name), Some(ty)) = (value, ty) {
+                        self.vars.entry(name).or_insert(ty);
+                    }
+                }
+            }
+            Expr::Then(a, b) => {
+                self.collect_local_types(&a.0);
+                self.collect_local_types(&b.0);
+            }
+            Expr::If(_, then, else_) => {
+                self.collect_local_types(&then.0);
+                if let Some(else_) = &**else_ {
+                    self.collect_local_types(&else_.0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 式の型 整数リテラルなど型の決まらない値はNone
+    pub fn infer(&self, expr: &Expr<'a>) -> Option<&'a str> {
+        match expr {
+            Expr::Value(Value::Bool(_)) => Some("bool"),
+            Expr::Value(Value::Str(_)) => Some("str"),
+            Expr::Value(_) => None,
+            Expr::Local(name) => self.vars.get(name).copied(),
+            Expr::Neg(a) => self.infer(&a.0),
+            Expr::Call(f, _) => match &f.0 {
+                Expr::Local(name) => self.signatures.rtype(name)?.first().copied(),
+                _ => None,
+            },
+            Expr::Binary(lhs, op, rhs) => match op {
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
+                    unify(self.infer(&lhs.0), self.infer(&rhs.0)).unwrap_or(None)
+                }
+                _ => Some("bool"),
+            },
+            _ => None,
+        }
+    }
+
+    /// 二項演算子の計算に使う整数型
+    /// 整数型でない場合や決まらない場合は`u32`
+    pub fn operand_type(&self, lhs: &Expr<'a>, rhs: &Expr<'a>) -> IntType {
+        unify(self.infer(lhs), self.infer(rhs))
+            .ok()
+            .flatten()
+            .or_else(|| self.infer(lhs))
+            .and_then(IntType::from_name)
+            .unwrap_or(IntType::U32)
+    }
+
+    /// 二項演算子が呼び出す関数の名前
+    pub fn op_func(&self, op: &BinaryOp, lhs: &Expr<'a>, rhs: &Expr<'a>) -> &'static str {
+        op_func_name(op, self.operand_type(lhs, rhs), self.checked)
+    }
+
+    /// 変数の型
+    pub fn var_type(&self, name: &str) -> Option<IntType> {
+        self.vars.get(name).copied().and_then(IntType::from_name)
+    }
+
+    /// index番目の返り値の型
+    pub fn return_type(&self, index: usize) -> Option<IntType> {
+        self.rtype.get(index).copied().and_then(IntType::from_name)
+    }
+
+    /// 関数のindex番目の引数の型
+    pub fn arg_type(&self, func: &str, index: usize) -> Option<IntType> {
+        self.signatures
+            .args(func)?
+            .get(index)
+            .copied()
+            .and_then(IntType::from_name)
+    }
+}
+
+/// 型の誤り
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeErr {
+    pub message: String,
+    pub span: Span,
+}
+
+/// 異なる整数型どうしの演算を探す
+pub fn check_func_types<'a>(func: &Func<'a>, signatures: &Signatures<'a>) -> Vec<TypeErr> {
+    let env = TypeEnv::new(func, signatures, false);
+    let mut errs = Vec::new();
+    check_expr(&func.body, &env, &mut errs);
+    errs
+}
+
+fn check_expr<'a>(expr: &Spanned<Expr<'a>>, env: &TypeEnv<'_, 'a>, errs: &mut Vec<TypeErr>) {
+    match &expr.0 {
+        Expr::Binary(lhs, op, rhs) => {
+            check_expr(lhs, env, errs);
+            check_expr(rhs, env, errs);
+            let (lty, rty) = (env.infer(&lhs.0), env.infer(&rhs.0));
+            if let (Some(l), Some(r)) = (lty, rty)
+                && unify(lty, rty).is_err()
+            {
+                errs.push(TypeErr {
+                    message: format!("mismatched types: `{}` {} `{}`", l, op, r),
+                    span: expr.1,
+                });
+            }
+        }
+        Expr::Neg(a) | Expr::Let(_, a) | Expr::Assign(_, a) => check_expr(a, env, errs),
+        Expr::Then(a, b) => {
+            check_expr(a, env, errs);
+            check_expr(b, env, errs);
+        }
+        Expr::If(cond, then, else_) => {
+            check_expr(cond, env, errs);
+            check_expr(then, env, errs);
+            if let Some(else_) = &**else_ {
+                check_expr(else_, env, errs);
+            }
+        }
+        Expr::Call(_, args) => {
+            for arg in &args.0 {
+                check_expr(arg, env, errs);
+            }
+        }
+        Expr::Return((values, _)) => {
+            for value in values {
+                check_expr(value, env, errs);
+            }
+        }
+        Expr::Error | Expr::Value(_) | Expr::Local(_) | Expr::Sed(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod types_test {
+    use super::*;
+
+    #[test]
+    fn types_test00() {
+        // 両辺の型で呼び出す関数が変わる
+        let code = r#"
+pub fn entry a:i32, b:u64, c:bit32 -> i32, u64, bit32, bool {
+    let d = b;
+    return a / 2, d % b, c * 3, a < -1;
+}
+"#;
+        let (tokens, _) = lexer_parse(code);
+        let tokens = tokens.expect("failed to tokenize");
+        let funcs: Vec<Func> = parser_parse(code, &tokens)
+            .expect("failed to parse")
+            .into_iter()
+            .map(|(func, _)| func)
+            .collect();
+        let signatures = Signatures::new(&funcs);
+        assert_eq!(check_func_types(&funcs[0], &signatures), vec![]);
+
+        let env = TypeEnv::new(&funcs[0], &signatures, false);
+        assert_eq!(env.var_type("d"), Some(IntType::U64));
+        assert_eq!(op_func_name(&BinaryOp::Div, IntType::I32, false), "div_i32");
+        assert_eq!(op_func_name(&BinaryOp::Mod, IntType::U64, false), "mod_u64");
+        assert_eq!(op_func_name(&BinaryOp::Mul, IntType::U32, false), "mul32");
+        assert_eq!(op_func_name(&BinaryOp::Lt, IntType::I32, false), "lt_i32");
+        assert_eq!(op_func_name(&BinaryOp::Add, IntType::I64, true), "add_checked_i64");
+        assert_eq!(op_func_name(&BinaryOp::Eq, IntType::I32, true), "eq");
+
+        // bit32とu32は同じ型として扱う
+        assert_eq!(unify(Some("bit32"), Some("u32")), Ok(Some("u32")));
+        assert!(unify(Some("u32"), Some("i32")).is_err());
+
+        // bigintは他の整数型と混ぜられない
+        assert_eq!(op_func_name(&BinaryOp::Mul, IntType::Big, true), "big_mul");
+        assert!(unify(Some("bigint"), Some("bit32")).is_err());
+    }
+
+    #[test]
+    fn types_test01() {
+        let code = r#"
+pub fn entry a:u32, b:i32 -> i32 {
+    let c = a;
+    return c + b;
+}
+"#;
+        let (tokens, _) = lexer_parse(code);
+        let tokens = tokens.expect("failed to tokenize");
+        let funcs: Vec<Func> = parser_parse(code, &tokens)
+            .expect("failed to parse")
+            .into_iter()
+            .map(|(func, _)| func)
+            .collect();
+        let signatures = Signatures::new(&funcs);
+        let errs = check_func_types(&funcs[0], &signatures);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message, "mismatched types: `u32` + `i32`");
+    }
+}
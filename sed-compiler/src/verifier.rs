@@ -0,0 +1,652 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::code_gen::{FuncDef, SedInstruction, Value};
+use crate::diagnostic::SourceSpan;
+use crate::sed_label::{LabelUse, line_labels};
+
+/// スタックの深さと生のsedのラベルの検査で見つかった問題の種類
+/// 深さは引数とローカル変数を除いたスタック領域に積まれている値の個数
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyErrKind {
+    /// 命令が消費する値がスタックに足りない
+    StackUnderflow {
+        needed: usize,
+        available: usize,
+    },
+    /// then節とelse節の終了時のスタックの深さが異なる
+    BranchMismatch {
+        then_depth: usize,
+        else_depth: usize,
+    },
+    /// `Ret`の時点でスタックにある値の個数が返り値の個数と一致しない
+    ReturnCountMismatch {
+        expected: usize,
+        available: usize,
+    },
+    /// `Ret`を通らずに関数の終わりに到達する
+    MissingReturn,
+    /// 存在しない引数またはローカル変数を参照している
+    UndefinedValue(String),
+    UndefinedFunction(String),
+    /// `Ret`の後に命令を追加しようとした (ir_builderだけが報告する)
+    Unreachable,
+    /// 末尾呼び出しの時点でスタックに引数以外の値が残っている
+    TailCallStack {
+        expected: usize,
+        available: usize,
+    },
+    /// 同じ関数の生のsedで同じラベルを二度定義している
+    DuplicateLabel(String),
+    /// 同じ関数の生のsedで定義されていないラベルに分岐している
+    UndefinedLabel(String),
+}
+
+/// スタックの深さの検査に失敗した位置と理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyErr {
+    pub func_name: String,
+    /// 問題のある命令の位置
+    /// IfProcの中では`[ifの位置, 0(then)または1(else), 命令の位置, ...]`のように続く
+    pub path: Vec<usize>,
+    /// 命令を実行する直前のスタックの深さ
+    pub depth: usize,
+    pub kind: VerifyErrKind,
+    /// 関数を定義しているソース上の位置
+    pub span: Option<Box<SourceSpan>>,
+}
+
+impl fmt::Display for VerifyErrKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyErrKind::StackUnderflow { needed, available } => write!(
+                f,
+                "stack underflow: {} value(s) needed but {} available",
+                needed, available
+            ),
+            VerifyErrKind::BranchMismatch {
+                then_depth,
+                else_depth,
+            } => write!(
+                f,
+                "branches end with different stack depths: then {}, else {}",
+                then_depth, else_depth
+            ),
+            VerifyErrKind::ReturnCountMismatch {
+                expected,
+                available,
+            } => write!(
+                f,
+                "expected {} return value(s) but {} available",
+                expected, available
+            ),
+            VerifyErrKind::MissingReturn => write!(f, "reaches the end without return"),
+            VerifyErrKind::UndefinedValue(name) => write!(f, "undefined {}", name),
+            VerifyErrKind::UndefinedFunction(name) => write!(f, "undefined function `{}`", name),
+            VerifyErrKind::Unreachable => write!(f, "instruction after return is unreachable"),
+            VerifyErrKind::TailCallStack {
+                expected,
+                available,
+            } => write!(
+                f,
+                "tail call expects exactly {} value(s) on the stack but {} available",
+                expected, available
+            ),
+            VerifyErrKind::DuplicateLabel(label) => write!(f, "duplicate label `{}`", label),
+            VerifyErrKind::UndefinedLabel(label) => {
+                write!(f, "branch to undefined label `{}`", label)
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerifyErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "in function `{}` at instruction {:?}: {}",
+            self.func_name, self.path, self.kind
+        )
+    }
+}
+
+/// 呼び出し先の引数と返り値の個数
+struct Signature {
+    argc: usize,
+    retc: usize,
+}
+
+struct Verifier<'a> {
+    func_def: &'a FuncDef,
+    signatures: &'a HashMap<&'a str, Signature>,
+    /// 関数の生のsedで定義されているすべてのラベル
+    labels: HashSet<String>,
+    /// 検査済みの命令で定義されていたラベル
+    seen_labels: HashSet<String>,
+    errs: Vec<VerifyErr>,
+}
+
+impl Verifier<'_> {
+    fn report(&mut self, path: &[usize], depth: usize, kind: VerifyErrKind) {
+        self.errs.push(VerifyErr {
+            func_name: self.func_def.name().to_string(),
+            path: path.to_vec(),
+            depth,
+            kind,
+            span: self.func_def.span().cloned().map(Box::new),
+        });
+    }
+
+    /// 値を消費できるかを確認する
+    /// 足りない場合は報告してfalseを返す
+    fn consume(&mut self, path: &[usize], depth: usize, needed: usize) -> bool {
+        if depth < needed {
+            self.report(
+                path,
+                depth,
+                VerifyErrKind::StackUnderflow {
+                    needed,
+                    available: depth,
+                },
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    fn check_value(&mut self, path: &[usize], depth: usize, value: &Value) {
+        let (index, count, kind) = match *value {
+            Value::Arg(index) => (index, self.func_def.argc(), "argument"),
+            Value::Local(index) => (index, self.func_def.localc(), "local"),
+        };
+        if count <= index {
+            self.report(
+                path,
+                depth,
+                VerifyErrKind::UndefinedValue(format!("{} {}", kind, index)),
+            );
+        }
+    }
+
+    /// 命令列を検査し、終了時のスタックの深さを返す
+    /// 必ず`Ret`を通る(終わりに到達しない)場合はNone
+    /// 問題が見つかった場合はその命令列の検査を打ち切る
+    fn verify_block(
+        &mut self,
+        block: &[SedInstruction],
+        path: &mut Vec<usize>,
+        mut depth: usize,
+    ) -> Result<Option<usize>, ()> {
+        for (i, instruction) in block.iter().enumerate() {
+            path.push(i);
+            let next = self.verify_instruction(instruction, path, depth);
+            path.pop();
+            match next? {
+                Some(next) => depth = next,
+                // 以降の命令には到達しない
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(depth))
+    }
+
+    fn verify_instruction(
+        &mut self,
+        instruction: &SedInstruction,
+        path: &mut Vec<usize>,
+        depth: usize,
+    ) -> Result<Option<usize>, ()> {
+        match instruction {
+            // 生のsedプログラムはスタックの深さを変えないものとして扱う
+            SedInstruction::Sed(sed) => {
+                for kind in label_errs(&sed.0, &self.labels, &mut self.seen_labels) {
+                    self.report(path, depth, kind);
+                }
+                Ok(Some(depth))
+            }
+            SedInstruction::Val(value) => {
+                self.check_value(path, depth, value);
+                Ok(Some(depth + 1))
+            }
+            SedInstruction::ConstVal(_) => Ok(Some(depth + 1)),
+            SedInstruction::Call(call_func) => {
+                let Some(signature) = self.signatures.get(call_func.func_name()) else {
+                    let name = call_func.func_name().to_string();
+                    self.report(path, depth, VerifyErrKind::UndefinedFunction(name));
+                    return Err(());
+                };
+                let (argc, retc) = (signature.argc, signature.retc);
+                if !self.consume(path, depth, argc) {
+                    return Err(());
+                }
+                Ok(Some(depth - argc + retc))
+            }
+            SedInstruction::Set(value) => {
+                self.check_value(path, depth, value);
+                if !self.consume(path, depth, 1) {
+                    return Err(());
+                }
+                Ok(Some(depth - 1))
+            }
+            SedInstruction::InlineSed(inline) => {
+                // インライン展開したsedは展開元の関数のラベルだけを参照する
+                let labels = defined_labels([inline.code()]);
+                for kind in label_errs(inline.code(), &labels, &mut HashSet::new()) {
+                    self.report(path, depth, kind);
+                }
+                if !self.consume(path, depth, inline.argc()) {
+                    return Err(());
+                }
+                Ok(Some(depth - inline.argc() + inline.retc()))
+            }
+            SedInstruction::TailCall(call_func) => {
+                let Some(signature) = self.signatures.get(call_func.func_name()) else {
+                    let name = call_func.func_name().to_string();
+                    self.report(path, depth, VerifyErrKind::UndefinedFunction(name));
+                    return Err(());
+                };
+                let (argc, retc) = (signature.argc, signature.retc);
+                if depth != argc {
+                    self.report(
+                        path,
+                        depth,
+                        VerifyErrKind::TailCallStack {
+                            expected: argc,
+                            available: depth,
+                        },
+                    );
+                } else if retc != self.func_def.retc() {
+                    self.report(
+                        path,
+                        depth,
+                        VerifyErrKind::ReturnCountMismatch {
+                            expected: self.func_def.retc(),
+                            available: retc,
+                        },
+                    );
+                }
+                Ok(None)
+            }
+            SedInstruction::Ret => {
+                let retc = self.func_def.retc();
+                if depth != retc {
+                    self.report(
+                        path,
+                        depth,
+                        VerifyErrKind::ReturnCountMismatch {
+                            expected: retc,
+                            available: depth,
+                        },
+                    );
+                }
+                Ok(None)
+            }
+            SedInstruction::IfProc(if_proc) => {
+                // 条件の値を消費する
+                if !self.consume(path, depth, 1) {
+                    return Err(());
+                }
+                let depth = depth - 1;
+                path.push(0);
+                let then_depth = self.verify_block(if_proc.then_proc(), path, depth);
+                path.pop();
+                path.push(1);
+                let else_depth = self.verify_block(if_proc.else_proc(), path, depth);
+                path.pop();
+                match (then_depth?, else_depth?) {
+                    (Some(then_depth), Some(else_depth)) if then_depth != else_depth => {
+                        self.report(
+                            path,
+                            depth,
+                            VerifyErrKind::BranchMismatch {
+                                then_depth,
+                                else_depth,
+                            },
+                        );
+                        Err(())
+                    }
+                    (Some(then_depth), _) => Ok(Some(then_depth)),
+                    (None, else_depth) => Ok(else_depth),
+                }
+            }
+        }
+    }
+}
+
+/// 生のsedで定義されているラベル
+fn defined_labels<'a>(codes: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+    codes
+        .into_iter()
+        .flat_map(str::lines)
+        .flat_map(|line| {
+            line_labels(line)
+                .into_iter()
+                .filter(|(kind, _, _)| *kind == LabelUse::Def)
+                .map(|(_, start, end)| line[start..end].to_string())
+        })
+        .collect()
+}
+
+/// 関数の中の生のsedをすべて集める
+fn sed_codes<'a>(block: &'a [SedInstruction], codes: &mut Vec<&'a str>) {
+    for instruction in block {
+        match instruction {
+            SedInstruction::Sed(sed) => codes.push(&sed.0),
+            SedInstruction::IfProc(if_proc) => {
+                sed_codes(if_proc.then_proc(), codes);
+                sed_codes(if_proc.else_proc(), codes);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// ラベルの重複と、定義されていないラベルへの分岐を探す
+/// ラベルは同じ関数の中のsedブロックで共有する
+fn label_errs(
+    code: &str,
+    labels: &HashSet<String>,
+    seen_labels: &mut HashSet<String>,
+) -> Vec<VerifyErrKind> {
+    let mut errs = Vec::new();
+    for line in code.lines() {
+        for (kind, start, end) in line_labels(line) {
+            let label = &line[start..end];
+            match kind {
+                LabelUse::Def if !seen_labels.insert(label.to_string()) => {
+                    errs.push(VerifyErrKind::DuplicateLabel(label.to_string()));
+                }
+                LabelUse::Branch if !labels.contains(label) => {
+                    errs.push(VerifyErrKind::UndefinedLabel(label.to_string()));
+                }
+                _ => {}
+            }
+        }
+    }
+    errs
+}
+
+fn contains_sed(block: &[SedInstruction]) -> bool {
+    block.iter().any(|instruction| match instruction {
+        SedInstruction::Sed(_) => true,
+        SedInstruction::IfProc(if_proc) => {
+            contains_sed(if_proc.then_proc()) || contains_sed(if_proc.else_proc())
+        }
+        _ => false,
+    })
+}
+
+fn verify_func_def_with(
+    func_def: &FuncDef,
+    signatures: &HashMap<&str, Signature>,
+) -> Vec<VerifyErr> {
+    let mut codes = Vec::new();
+    sed_codes(func_def.proc_contents(), &mut codes);
+    let mut verifier = Verifier {
+        func_def,
+        signatures,
+        labels: defined_labels(codes),
+        seen_labels: HashSet::new(),
+        errs: Vec::new(),
+    };
+    let mut path = Vec::new();
+    let end = verifier.verify_block(func_def.proc_contents(), &mut path, 0);
+    // 生のsedプログラムを含む関数はsed側で返り値を作るのでRetがなくてもよい
+    if let Ok(Some(depth)) = end
+        && !contains_sed(func_def.proc_contents())
+    {
+        path.push(func_def.proc_contents().len());
+        verifier.report(&path, depth, VerifyErrKind::MissingReturn);
+    }
+    verifier.errs
+}
+
+fn collect_signatures(func_table: &[FuncDef]) -> HashMap<&str, Signature> {
+    func_table
+        .iter()
+        .map(|f| {
+            (
+                f.name(),
+                Signature {
+                    argc: f.argc(),
+                    retc: f.retc(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// 一つの関数の命令列について、すべての命令の位置でのスタックの深さを検査する
+/// 呼び出し先の関数の引数と返り値の個数はfunc_tableから探す
+pub fn verify_func_def(func_def: &FuncDef, func_table: &[FuncDef]) -> Result<(), Vec<VerifyErr>> {
+    let errs = verify_func_def_with(func_def, &collect_signatures(func_table));
+    if errs.is_empty() { Ok(()) } else { Err(errs) }
+}
+
+/// テーブル内のすべての関数を検査する
+pub fn verify_func_table(func_table: &[FuncDef]) -> Result<(), Vec<VerifyErr>> {
+    let signatures = collect_signatures(func_table);
+    let errs: Vec<VerifyErr> = func_table
+        .iter()
+        .flat_map(|func_def| verify_func_def_with(func_def, &signatures))
+        .collect();
+    if errs.is_empty() { Ok(()) } else { Err(errs) }
+}
+
+#[cfg(test)]
+mod verifier_test {
+    use super::*;
+    use crate::code_gen::{CallFunc, ConstVal, IfProc, SedCode};
+    use crate::embedded::*;
+
+    fn func(
+        name: &str,
+        argc: usize,
+        localc: usize,
+        retc: usize,
+        proc: Vec<SedInstruction>,
+    ) -> FuncDef {
+        let mut func_def = FuncDef::new(name, argc, localc, retc);
+        func_def.set_proc_contents(proc);
+        func_def
+    }
+
+    #[test]
+    fn verifier_test00() {
+        // 正しい関数は検査を通る
+        let func_table = vec![
+            em_shift_left1(),
+            em_shift_right1(),
+            em_is_empty(),
+            em_ends_with_zero(),
+            em_mul(),
+            em_add(),
+            em_sub32(),
+            em_twos_complement(),
+            em_zero_padding32(),
+        ];
+        assert_eq!(verify_func_table(&func_table), Ok(()));
+    }
+
+    #[test]
+    fn verifier_test01() {
+        // 引数の足りない呼び出しとSet
+        let func_table = vec![
+            func(
+                "f",
+                0,
+                1,
+                1,
+                vec![
+                    SedInstruction::ConstVal(ConstVal::new("1")),
+                    SedInstruction::Call(CallFunc::new("g")),
+                    SedInstruction::Ret,
+                ],
+            ),
+            func(
+                "g",
+                2,
+                0,
+                1,
+                vec![SedInstruction::Sed(SedCode("".to_string()))],
+            ),
+            func("h", 0, 1, 0, vec![SedInstruction::Set(Value::Local(0))]),
+        ];
+        let errs = verify_func_table(&func_table).unwrap_err();
+        assert_eq!(errs.len(), 2);
+        assert_eq!(errs[0].func_name, "f");
+        assert_eq!(errs[0].path, vec![1]);
+        assert_eq!(
+            errs[0].kind,
+            VerifyErrKind::StackUnderflow {
+                needed: 2,
+                available: 1
+            }
+        );
+        assert_eq!(errs[1].func_name, "h");
+        assert_eq!(
+            errs[1].kind,
+            VerifyErrKind::StackUnderflow {
+                needed: 1,
+                available: 0
+            }
+        );
+    }
+
+    #[test]
+    fn verifier_test02() {
+        // then節とelse節で深さが異なる
+        let f = func(
+            "f",
+            1,
+            0,
+            1,
+            vec![
+                SedInstruction::Val(Value::Arg(0)),
+                SedInstruction::IfProc(IfProc::new(
+                    vec![SedInstruction::ConstVal(ConstVal::new("1"))],
+                    vec![],
+                )),
+                SedInstruction::Ret,
+            ],
+        );
+        let errs = verify_func_def(&f, &[]).unwrap_err();
+        assert_eq!(errs[0].path, vec![1]);
+        assert_eq!(
+            errs[0].kind,
+            VerifyErrKind::BranchMismatch {
+                then_depth: 1,
+                else_depth: 0
+            }
+        );
+
+        // 片方の節がreturnする場合は残りの節の深さが使われる
+        let f = func(
+            "f",
+            1,
+            0,
+            1,
+            vec![
+                SedInstruction::Val(Value::Arg(0)),
+                SedInstruction::IfProc(IfProc::new(
+                    vec![
+                        SedInstruction::ConstVal(ConstVal::new("1")),
+                        SedInstruction::Ret,
+                    ],
+                    vec![SedInstruction::ConstVal(ConstVal::new("0"))],
+                )),
+                SedInstruction::Ret,
+            ],
+        );
+        assert_eq!(verify_func_def(&f, &[]), Ok(()));
+    }
+
+    #[test]
+    fn verifier_test03() {
+        // 返り値の個数、returnの欠落、存在しない変数
+        let f = func(
+            "f",
+            0,
+            0,
+            2,
+            vec![
+                SedInstruction::ConstVal(ConstVal::new("1")),
+                SedInstruction::Ret,
+            ],
+        );
+        let errs = verify_func_def(&f, &[]).unwrap_err();
+        assert_eq!(
+            errs[0].kind,
+            VerifyErrKind::ReturnCountMismatch {
+                expected: 2,
+                available: 1
+            }
+        );
+
+        let f = func("f", 0, 0, 1, vec![SedInstruction::Val(Value::Local(0))]);
+        let errs = verify_func_def(&f, &[]).unwrap_err();
+        assert_eq!(
+            errs[0].kind,
+            VerifyErrKind::UndefinedValue("local 0".to_string())
+        );
+        assert_eq!(errs[1].path, vec![1]);
+        assert_eq!(errs[1].kind, VerifyErrKind::MissingReturn);
+    }
+
+    #[test]
+    fn verifier_test04() {
+        // 同じ関数の別のsedブロックのラベルには分岐できる
+        let sed = |code: &str| SedInstruction::Sed(SedCode(code.to_string()));
+        let f = func(
+            "f",
+            0,
+            0,
+            0,
+            vec![sed(":loop\ns/~1/~0/\n/~0/b done"), sed("t loop\n:done\nb")],
+        );
+        assert_eq!(verify_func_def(&f, &[]), Ok(()));
+
+        // ラベルの重複と、ほかの関数や生成したラベルへの分岐
+        let f = func(
+            "f",
+            0,
+            0,
+            0,
+            vec![sed(":loop\nt loop"), sed(":loop\nb done\nb retlabel0")],
+        );
+        let errs = verify_func_def(&f, &[]).unwrap_err();
+        let kinds: Vec<_> = errs
+            .iter()
+            .map(|err| (err.path.clone(), err.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (vec![1], VerifyErrKind::DuplicateLabel("loop".to_string())),
+                (vec![1], VerifyErrKind::UndefinedLabel("done".to_string())),
+                (
+                    vec![1],
+                    VerifyErrKind::UndefinedLabel("retlabel0".to_string())
+                ),
+            ]
+        );
+
+        // `;`で区切った後ろのコマンドのラベルと分岐先も検査する
+        let f = func("f", 0, 0, 0, vec![sed(":loop;s/^~0/~/;t loop")]);
+        assert_eq!(verify_func_def(&f, &[]), Ok(()));
+        let f = func("f", 0, 0, 0, vec![sed(":a;s/x/y/;:a;t b")]);
+        let kinds: Vec<_> = verify_func_def(&f, &[])
+            .unwrap_err()
+            .into_iter()
+            .map(|err| err.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                VerifyErrKind::DuplicateLabel("a".to_string()),
+                VerifyErrKind::UndefinedLabel("b".to_string()),
+            ]
+        );
+    }
+}
@@ -4,17 +4,24 @@ mod gen_test;
 
 #[cfg(test)]
 mod command_test {
-    use std::fmt::format;
     use std::process::{Command, Stdio};
     use std::io::{Read, Write};
-    use sed_compiler::compiler::compiler_frontend;
+    use sed_compiler::code_gen::{
+        CompilerBuilder, CrateType, IoFormat, LinkErr, Linked, NumberRepr, link_compile_builder,
+    };
+    use sed_compiler::compiler::{FrontendOptions, compiler_frontend, compiler_frontend_with_options};
+    use sed_compiler::strings::escape_str;
 
     struct DebugCompilerError {
         error_msg: String
     }
 
     fn sed_operation_test(code: &str, input:&str) -> Result<String, DebugCompilerError>{
-        match compiler_frontend(code) {
+        sed_operation_test_with_options(code, input, &FrontendOptions::default())
+    }
+
+    fn sed_operation_test_with_options(code: &str, input:&str, options: &FrontendOptions) -> Result<String, DebugCompilerError>{
+        match compiler_frontend_with_options(code, options) {
             Ok(compiler_builder) => {
                 let assembled = compiler_builder.assemble();
                 // assembled.resolved_show_table();
@@ -39,7 +46,6 @@ mod command_test {
                         let mut output = String::new();
                         if let Some (a) = child.stdout.as_mut() {
                             a.read_to_string(&mut output).expect("標準出力に失敗しました");
-                        } else {
                         }
 
                         // 4. コマンド終了を待つ
@@ -50,16 +56,16 @@ mod command_test {
                         println!("output = {:?}", output);
                         // assert_eq!(output, expected_output);
                         // assert_eq!(status., expected_status_code);
-                        return Ok(output);
+                        Ok(output)
                     }
                     Err(err) => {
-                        return Err(DebugCompilerError { error_msg: format!("{:?}", err)})
+                        Err(DebugCompilerError { error_msg: format!("{:?}", err)})
                     }
                 }
             }
             Err(err) => {
                 println!("{:?}", err);
-                return Err(DebugCompilerError { error_msg: format!("{:?}", err)});
+                Err(DebugCompilerError { error_msg: format!("{:?}", err)})
             }
         }
     }
@@ -117,6 +123,7 @@ mod command_test {
         let expected_status_code = 0;
 
         let mut child = Command::new("sed")
+            .arg("-f")
             .arg("sed/basic_operations.sed")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -131,7 +138,6 @@ mod command_test {
         let mut output = String::new();
         if let Some (a) = child.stdout.as_mut() {
             a.read_to_string(&mut output).expect("標準出力に失敗しました");
-        } else {
         }
 
         // 4. コマンド終了を待つ
@@ -141,7 +147,7 @@ mod command_test {
         // 5. Rust 側で出力を利用可能
         println!("output = {:?}", output);
         assert_eq!(output, expected_output);
-        // assert_eq!(status., expected_status_code);
+        assert_eq!(status.code(), Some(expected_status_code));
     }
 
     #[test]
@@ -160,4 +166,1117 @@ mod command_test {
             }
         }
     }
+
+    #[test]
+    fn test_entry_option00() {
+        let code = r#"
+fn double a:bit32 -> bit32 {
+    sed ${
+        "s/~\\([01]*\\)/~\\10;/",
+    }$
+}
+
+pub fn entry a:bit32 -> bit32 {
+    return double(a);
+}
+
+pub fn entry_twice a:bit32 -> bit32 {
+    return double(double(a));
+}
+"#;
+        let result = sed_operation_test(code, "~101").map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("~1010;".to_string()));
+
+        let options = FrontendOptions {
+            entry: "entry_twice".to_string(),
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "~101", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("~10100;".to_string()));
+
+        let options = FrontendOptions {
+            entry: "no_such_entry".to_string(),
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "~101", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Err("MissingEntry(\"no_such_entry\")".to_string()));
+    }
+
+    #[test]
+    fn test_entry_option01() {
+        // 再帰する関数もentry pointにできる
+        use std::fs;
+        let code = fs::read_to_string("soil/basic_operations.soil").expect("ファイルの読み込みに失敗しました");
+        let options = FrontendOptions {
+            entry: "gcd".to_string(),
+            ..FrontendOptions::default()
+        };
+        let input_args = &format!("~{:032b}~{:032b}", 12, 18);
+        let result = sed_operation_test_with_options(&code, input_args, &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok(format!("~{:032b};", 6)));
+    }
+
+    #[test]
+    fn test_prelude_operators00() {
+        // 演算子はpreludeの関数で計算され、結果は32bitに収まる
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bit32, bit32, bit32, bit32, bool, bool {
+    return a + b, a - b, a * b, a / b, a % b, a == b, a != b;
+}
+"#;
+        let cases: [(u32, u32); 6] = [
+            (12, 18),
+            (18, 12),
+            (0xFFFF_FFFF, 1),
+            (0x1234_5678, 0x9ABC),
+            (7, 0),
+            (0x8000_0000, 0x8000_0000),
+        ];
+        for (a, b) in cases {
+            let input_args = &format!("~{:032b}~{:032b}", a, b);
+            let result = sed_operation_test(code, input_args).map_err(|e| e.error_msg);
+            // 0で割った場合、商はすべてのbitが1、余りは割られる数
+            let q = a.checked_div(b).unwrap_or(u32::MAX);
+            let r = a.checked_rem(b).unwrap_or(a);
+            let expected = format!(
+                "~{:032b}~{:032b}~{:032b}~{:032b}~{:032b}~{}~{};",
+                a.wrapping_add(b),
+                a.wrapping_sub(b),
+                a.wrapping_mul(b),
+                q,
+                r,
+                (a == b) as u8,
+                (a != b) as u8,
+            );
+            assert_eq!(result, Ok(expected), "a = {}, b = {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_prelude_operators01() {
+        // 32bitより短い入力も32bitとして扱う
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bool {
+    return a * b + 1, a == 3;
+}
+"#;
+        let result = sed_operation_test(code, "~11~101").map_err(|e| e.error_msg);
+        assert_eq!(result, Ok(format!("~{:032b}~1;", 16)));
+    }
+
+    #[test]
+    fn test_io_format_decimal00() {
+        // 10進数の引数を受け取り、返り値を10進数で出力する
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bit32, bool, bit32 {
+    return a + b, a - b, a == b, 0;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "  1000   24\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("1024 976 0 0\n".to_string()));
+        let result = sed_operation_test_with_options(code, "4294967295 4294967297\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("0 4294967294 0 0\n".to_string()));
+        // 行ごとに独立して実行される
+        let result = sed_operation_test_with_options(code, "3 1\n5 5\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("4 2 0 0\n10 0 1 0\n".to_string()));
+    }
+
+    #[test]
+    fn test_number_repr_decimal00() {
+        // 10進数の表現でも2進数と同じ結果になる
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bit32, bit32, bit32, bit32, bool, bool {
+    return a + b, a - b, a * b, a / b, a % b, a == b, a != b;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        let cases: [(u32, u32); 6] = [
+            (12, 18),
+            (18, 12),
+            (0xFFFF_FFFF, 1),
+            (123_456_789, 987_654_321),
+            (7, 0),
+            (0x8000_0000, 0x8000_0000),
+        ];
+        for (a, b) in cases {
+            let input_args = &format!("{} {}\n", a, b);
+            let result = sed_operation_test_with_options(code, input_args, &options).map_err(|e| e.error_msg);
+            let q = a.checked_div(b).unwrap_or(u32::MAX);
+            let r = a.checked_rem(b).unwrap_or(a);
+            let expected = format!(
+                "{} {} {} {} {} {} {}\n",
+                a.wrapping_add(b),
+                a.wrapping_sub(b),
+                a.wrapping_mul(b),
+                q,
+                r,
+                (a == b) as u8,
+                (a != b) as u8,
+            );
+            assert_eq!(result, Ok(expected), "a = {}, b = {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_number_repr_decimal01() {
+        // 2進数の入出力の場合は境界で変換する
+        // 32bitを超える入力は演算子で2^32で割った余りになる
+        let code = r#"
+fn gcd a:bit32, b:bit32 -> bit32 {
+    if b == 0 {
+        return a;
+    }
+    return gcd(b, a % b);
+}
+
+pub fn entry a:bit32, b:bit32 -> bit32, bit32 {
+    return gcd(a, b), b + 0;
+}
+"#;
+        let options = FrontendOptions {
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "~111010110111100110100010101~101110000000000000000000000000000001011\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok(format!("~{:032b}~{:032b};\n", 1, 11)));
+    }
+
+    #[test]
+    fn test_number_repr_decimal02() {
+        // 10進数の表現では符号付きと64bitの型、checkedはエラーになる
+        let options = FrontendOptions {
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        for ty in ["i32", "u64", "bit64", "i64"] {
+            let code = format!("pub fn entry a:{0}, b:{0} -> {0} {{\n    return a - b;\n}}\n", ty);
+            let result = compiler_frontend_with_options(&code, &options).map(|_| ()).map_err(|e| e.note);
+            assert_eq!(result, Err("aborting due to 3 number representation error(s)".to_string()), "{}", ty);
+        }
+
+        let code = "pub fn entry a:u32, b:u32 -> u32 {\n    return a + b;\n}\n";
+        let checked = FrontendOptions { checked: true, ..options };
+        let result = compiler_frontend_with_options(code, &checked).map(|_| ()).map_err(|e| e.note);
+        assert_eq!(result, Err("aborting due to 1 number representation error(s)".to_string()));
+    }
+
+    #[test]
+    fn test_signed_i32_00() {
+        // 符号付きの除算と比較はRustのwrappingな演算と同じ結果になる
+        let code = r#"
+pub fn entry a:i32, b:i32 -> i32, i32, i32, i32, bool, bool, bool, bool {
+    return a + b, a * b, a / b, a % b, a < b, a <= b, a > b, a >= b;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            ..FrontendOptions::default()
+        };
+        let cases: [(i32, i32); 7] = [
+            (7, 2),
+            (-7, 2),
+            (7, -2),
+            (-7, -2),
+            (i32::MIN, -1),
+            (-5, 0),
+            (i32::MAX, i32::MIN),
+        ];
+        for (a, b) in cases {
+            let input_args = &format!("{} {}\n", a as u32, b as u32);
+            let result = sed_operation_test_with_options(code, input_args, &options).map_err(|e| e.error_msg);
+            let (q, r) = if b == 0 { (-1, a) } else { (a.wrapping_div(b), a.wrapping_rem(b)) };
+            let expected = format!(
+                "{} {} {} {} {} {} {} {}\n",
+                a.wrapping_add(b) as u32,
+                a.wrapping_mul(b) as u32,
+                q as u32,
+                r as u32,
+                (a < b) as u8,
+                (a <= b) as u8,
+                (a > b) as u8,
+                (a >= b) as u8,
+            );
+            assert_eq!(result, Ok(expected), "a = {}, b = {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_signed_i32_01() {
+        // 負のリテラルと単項の-
+        let code = r#"
+pub fn entry a:i32 -> i32, i32, bool {
+    let b = -3;
+    return -a, a / b, a < -100;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            ..FrontendOptions::default()
+        };
+        for a in [0i32, 10, -200] {
+            let result = sed_operation_test_with_options(code, &format!("{}\n", a as u32), &options).map_err(|e| e.error_msg);
+            let expected = format!("{} {} {}\n", a.wrapping_neg() as u32, (a / -3) as u32, (a < -100) as u8);
+            assert_eq!(result, Ok(expected), "a = {}", a);
+        }
+    }
+
+    #[test]
+    fn test_u64_00() {
+        // 64bitの演算
+        let code = r#"
+pub fn entry a:u64, b:u64 -> u64, u64, u64, u64, bool {
+    return a + b, a * b, a / b, a % b, a < b;
+}
+"#;
+        let a: u64 = 0x1234_5678_9ABC_DEF0;
+        let b: u64 = 0x0FED_CBA9;
+        let result = sed_operation_test(code, &format!("~{:064b}~{:064b}\n", a, b)).map_err(|e| e.error_msg);
+        let expected = format!(
+            "~{:064b}~{:064b}~{:064b}~{:064b}~{};\n",
+            a.wrapping_add(b),
+            a.wrapping_mul(b),
+            a / b,
+            a % b,
+            (a < b) as u8,
+        );
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn test_mismatched_int_types00() {
+        // 型の異なる整数どうしの演算はエラーになる
+        let code = r#"
+pub fn entry a:u32, b:i32 -> i32 {
+    return a + b;
+}
+"#;
+        let result = sed_operation_test(code, "").map_err(|e| e.error_msg);
+        assert!(result.is_err());
+    }
+
+    fn sed_status_test(code: &str, input: &str, options: &FrontendOptions) -> (String, String, Option<i32>) {
+        let generated = compiler_frontend_with_options(code, options)
+            .map_err(|e| format!("{:?}", e))
+            .unwrap()
+            .assemble()
+            .generate()
+            .unwrap();
+        let mut child = Command::new("sed")
+            .arg(generated)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn().expect("子プロセスの生成に失敗しました");
+        child.stdin.take().unwrap().write_all(input.as_bytes()).expect("stdinの書き込みに失敗しました");
+        let output = child.wait_with_output().expect("コマンドの終了前にエラーが発生しました");
+        (
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            output.status.code(),
+        )
+    }
+
+    #[test]
+    fn test_checked00() {
+        // checkedでは桁あふれと0による除算で中断する
+        let code = r#"
+pub fn entry a:i32, b:i32 -> i32, i32 {
+    return a / b, a + b;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            checked: true,
+            ..FrontendOptions::default()
+        };
+        let (stdout, _, status) = sed_status_test(code, &format!("{} {}\n", -7i32 as u32, 2), &options);
+        assert_eq!((stdout.as_str(), status), (format!("{} {}\n", -3i32 as u32, -5i32 as u32).as_str(), Some(0)));
+
+        let (_, stderr, status) = sed_status_test(code, &format!("{} {}\n", i32::MAX, 1), &options);
+        assert_eq!((stderr.as_str(), status), ("attempt to add with overflow\n", Some(1)));
+
+        let (_, stderr, status) = sed_status_test(code, "1 0\n", &options);
+        assert_eq!((stderr.as_str(), status), ("attempt to divide by zero\n", Some(1)));
+
+        let (_, stderr, status) = sed_status_test(code, &format!("{} {}\n", i32::MIN as u32, -1i32 as u32), &options);
+        assert_eq!((stderr.as_str(), status), ("attempt to divide with overflow\n", Some(1)));
+    }
+
+    #[test]
+    fn test_checked01() {
+        // 符号なしの減算と乗算
+        let code = r#"
+pub fn entry a:u32, b:u32 -> u32, u32 {
+    return a - b, a * b;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            checked: true,
+            ..FrontendOptions::default()
+        };
+        let (stdout, _, status) = sed_status_test(code, "70000 60000\n", &options);
+        assert_eq!((stdout.as_str(), status), ("10000 4200000000\n", Some(0)));
+
+        let (_, stderr, status) = sed_status_test(code, "1 2\n", &options);
+        assert_eq!((stderr.as_str(), status), ("attempt to subtract with overflow\n", Some(1)));
+
+        let (_, stderr, status) = sed_status_test(code, "70000 70000\n", &options);
+        assert_eq!((stderr.as_str(), status), ("attempt to multiply with overflow\n", Some(1)));
+    }
+
+    #[test]
+    fn test_str00() {
+        // 区切り文字を含む文字列でも一文字として扱う
+        let code = r#"
+pub fn entry s:str, t:str -> bit32, str, bool, bool, str, str, str {
+    return str_len(s), str_concat(s, t), str_starts_with(s, t), str_starts_with(t, s), str_to_upper(s), str_to_lower(t), str_replace_all(s, t, "<&/>");
+}
+"#;
+        let s = "a~b;c|d%e";
+        let t = "a~";
+        let input_args = format!("~{}~{}\n", escape_str(s), escape_str(t));
+        let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+        let expected = format!(
+            "~{:032b}~{}~1~0~{}~{}~{};\n",
+            s.chars().count(),
+            escape_str(&format!("{}{}", s, t)),
+            escape_str(&s.to_uppercase()),
+            escape_str(&t.to_lowercase()),
+            escape_str(&s.replace(t, "<&/>")),
+        );
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn test_str01() {
+        // 位置は文字の単位で数え、範囲を超える部分は切り詰める
+        let code = r#"
+pub fn entry s:str, needle:str, start:bit32, n:bit32 -> bit32, str {
+    return str_find(s, needle), str_substr(s, start, n);
+}
+"#;
+        let cases: [(&str, &str, u32, u32); 5] = [
+            ("hello world", "o", 2, 3),
+            ("~;%1", "%1", 1, 10),
+            ("%1", "1", 0, 1),
+            ("abc", "", 5, 1),
+            ("abc", "abcd", 0, 0),
+        ];
+        for (s, needle, start, n) in cases {
+            let input_args = format!("~{}~{}~{:032b}~{:032b}\n", escape_str(s), escape_str(needle), start, n);
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            let chars: Vec<char> = s.chars().collect();
+            let pos = s.find(needle).map(|byte| s[..byte].chars().count() as u32).unwrap_or(u32::MAX);
+            let from = (start as usize).min(chars.len());
+            let to = (from + n as usize).min(chars.len());
+            let sub: String = chars[from..to].iter().collect();
+            let expected = format!("~{:032b}~{};\n", pos, escape_str(&sub));
+            assert_eq!(result, Ok(expected), "s = {:?}, needle = {:?}", s, needle);
+        }
+    }
+
+    #[test]
+    fn test_str02() {
+        // 10進数の表現でも同じ関数を使える
+        let code = r#"
+pub fn entry a:bit32 -> bit32, bit32, bit32 {
+    let s = "a~b;c|d";
+    return str_len(s) + a, str_find(s, "c|"), str_len(str_substr(s, a, 3));
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "5\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("12 4 2\n".to_string()));
+    }
+
+    #[test]
+    fn test_str03() {
+        // 組み込み関数と同じ名前の関数は利用者の定義が使われ、他の組み込み関数には影響しない
+        // `len`や`find`のような接頭辞のない名前は自由に定義できる
+        let code = r#"
+fn len a:bit32 -> bit32 {
+    return a + 1;
+}
+
+fn str_len _s:str -> bit32 {
+    return 100;
+}
+
+pub fn entry a:bit32 -> bit32, bit32, bit32 {
+    let s = "abcdef";
+    return len(a), str_len(s), str_find(str_substr(s, a, 3), "f");
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "4\n", &options).map_err(|e| e.error_msg);
+        assert_eq!(result, Ok("5 100 1\n".to_string()));
+    }
+
+    #[test]
+    fn test_str04() {
+        // 演算子の関数を置き換えても、それを使う組み込み関数の結果は変わらない
+        let code = r#"
+fn sub32 a:bit32, _b:bit32 -> bit32 {
+    return a;
+}
+
+fn ge_u32 a:u32, _b:u32 -> bool {
+    return a == a;
+}
+
+fn diff a:bigint, b:bigint -> bit32 {
+    return bigint_to_bit32(a - b);
+}
+
+pub fn entry a:bit32 -> bit32, bool, bit32, bit32, bit32, bit32 {
+    let s = "abcdef";
+    let t = str_substr(s, a, 3);
+    return a - 1, a >= 9, str_len(t), str_find(t, "c"), str_find(s, "zz"), diff(bigint_from_bit32(a), bigint_from_bit32(3));
+}
+"#;
+        for number_repr in [NumberRepr::Binary, NumberRepr::Decimal] {
+            let options = FrontendOptions {
+                io_format: IoFormat::Decimal,
+                number_repr,
+                ..FrontendOptions::default()
+            };
+            let result = sed_operation_test_with_options(code, "1\n", &options).map_err(|e| e.error_msg);
+            assert_eq!(result, Ok("1 1 3 1 4294967295 4294967294\n".to_string()), "{:?}", number_repr);
+        }
+    }
+
+    fn bigint_binary(value: i128) -> String {
+        let sign = if value < 0 { "-" } else { "" };
+        format!("{}{:b}", sign, value.unsigned_abs())
+    }
+
+    #[test]
+    fn test_bigint00() {
+        // bigintは桁数の制限なく計算する
+        let code = r#"
+pub fn entry a:bigint, b:bigint -> bigint, bigint, bigint, bigint, bigint, bool, bool, bool {
+    return a + b, a - b, a * b, a / b, a % b, a < b, a == b, a >= b;
+}
+"#;
+        let cases: [(i128, i128); 6] = [
+            (12, 18),
+            (-7, 2),
+            (7, -2),
+            (-7, -7),
+            (0xFFFF_FFFF_FFFF, 0x1_0000_0001),
+            (-(1 << 70), 3),
+        ];
+        for (a, b) in cases {
+            let input_args = format!("~{}~{}\n", bigint_binary(a), bigint_binary(b));
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            let expected = format!(
+                "~{}~{}~{}~{}~{}~{}~{}~{};\n",
+                bigint_binary(a + b),
+                bigint_binary(a - b),
+                bigint_binary(a * b),
+                bigint_binary(a / b),
+                bigint_binary(a % b),
+                (a < b) as u8,
+                (a == b) as u8,
+                (a >= b) as u8,
+            );
+            assert_eq!(result, Ok(expected), "a = {}, b = {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_bigint01() {
+        // 10進数の表現で階乗を計算する
+        let code = r#"
+fn fact n:bigint -> bigint {
+    if n <= 1 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+pub fn entry n:bit32 -> bigint, bit32 {
+    let f = fact(bigint_from_bit32(n));
+    return f, bigint_to_bit32(f);
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        let result = sed_operation_test_with_options(code, "25\n", &options).map_err(|e| e.error_msg);
+        let fact: u128 = (1..=25).product();
+        assert_eq!(result, Ok(format!("{} {}\n", fact, fact as u32)));
+    }
+
+    #[test]
+    fn test_bigint02() {
+        // 負の値の入出力と32bitへの変換
+        let code = r#"
+pub fn entry a:bigint -> bigint, bit32, bigint {
+    return -a, bigint_to_bit32(a), a - 100;
+}
+"#;
+        let options = FrontendOptions {
+            io_format: IoFormat::Decimal,
+            number_repr: NumberRepr::Decimal,
+            ..FrontendOptions::default()
+        };
+        for a in [5i128, -5, 0, -123_456_789_012_345_678] {
+            let result = sed_operation_test_with_options(code, &format!("{}\n", a), &options).map_err(|e| e.error_msg);
+            let expected = format!("{} {} {}\n", -a, a as u32, a - 100);
+            assert_eq!(result, Ok(expected), "a = {}", a);
+        }
+    }
+
+    #[test]
+    fn test_regex00() {
+        // 区切り文字も一文字として照合し、文字の途中からは一致しない
+        let code = r#"
+pub fn entry s:str -> bool, bool, bool, bool {
+    return matches(s, /^[a-z]*;[^a-z]\+$/), matches(s, /~\|%1/), matches(s, /1/), matches(s, /a\/b/);
+}
+"#;
+        let cases: [(&str, &str); 4] = [
+            ("abc;~|", "~1~1~0~0"),
+            ("%1", "~0~1~1~0"),
+            ("xa/b;", "~0~0~0~1"),
+            ("", "~0~0~0~0"),
+        ];
+        for (s, expected) in cases {
+            let input_args = format!("~{}\n", escape_str(s));
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            assert_eq!(result, Ok(format!("{};\n", expected)), "s = {:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_regex01() {
+        // グループを複数の値として返し、一致しない場合は空の文字列になる
+        let code = r#"
+pub fn entry s:str -> str, str, bool {
+    let key = "";
+    let value = "";
+    key, value = captures(s, /\([a-z]*\)=\(.*\)$/);
+    return value, key, matches(key, /^k.y$/);
+}
+"#;
+        let cases: [(&str, &str, &str); 3] = [
+            ("key=a~b;c", "a~b;c", "key"),
+            ("x;k=%1=", "%1=", "k"),
+            ("no value", "", ""),
+        ];
+        for (s, value, key) in cases {
+            let input_args = format!("~{}\n", escape_str(s));
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            let found = if key == "key" { 1 } else { 0 };
+            let expected = format!("~{}~{}~{};\n", escape_str(value), escape_str(key), found);
+            assert_eq!(result, Ok(expected), "s = {:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_regex02() {
+        // 不正な正規表現と、matchesとcaptures以外で使われた正規表現はエラーになる
+        for body in [
+            "return matches(s, /\\(a/);",
+            "return captures(s, /abc/);",
+            "return f(s, /a/);",
+        ] {
+            let code = format!("pub fn entry s:str -> bool {{\n    {}\n}}\n", body);
+            let result = sed_operation_test(&code, "~\n").map_err(|e| e.error_msg);
+            assert!(result.is_err(), "{}", body);
+        }
+    }
+
+    fn link_units(codes: &[(&str, CrateType)]) -> Result<CompilerBuilder<Linked>, Vec<LinkErr>> {
+        let units: Vec<_> = codes
+            .iter()
+            .map(|(code, crate_type)| {
+                let options = FrontendOptions {
+                    crate_type: *crate_type,
+                    ..FrontendOptions::default()
+                };
+                compiler_frontend_with_options(code, &options).map_err(|e| e.note).unwrap().assemble()
+            })
+            .collect();
+        link_compile_builder(&units)
+    }
+
+    #[test]
+    fn test_link00() {
+        // 別々にassembleした単位のラベルは重複せず、同じ組み込み関数は一つにまとめられる
+        let lib = r#"
+pub fn double a:bit32 -> bit32 {
+    if a == 0 {
+        return 0;
+    }
+    return twice(a);
+}
+
+fn twice a:bit32 -> bit32 {
+    return a + a;
+}
+"#;
+        let main = r#"
+pub fn entry a:bit32 -> bit32 {
+    if a == 1 {
+        return double(a) + 1;
+    }
+    return double(a + 1);
+}
+"#;
+        let linked = link_units(&[(main, CrateType::Binary), (lib, CrateType::Library)]).unwrap();
+        assert_eq!(linked.dump_ir().matches("fn add ").count(), 1);
+        let script = linked.generate().unwrap();
+        for (a, expected) in [(1u32, 3u32), (5, 12), (0, 2)] {
+            let output = Command::new("sed")
+                .arg(&script)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .and_then(|mut child| {
+                    child.stdin.take().unwrap().write_all(format!("~{:032b}\n", a).as_bytes())?;
+                    child.wait_with_output()
+                })
+                .expect("sedの実行に失敗しました");
+            assert_eq!(String::from_utf8_lossy(&output.stdout), format!("~{:032b};\n", expected));
+        }
+    }
+
+    #[test]
+    fn test_link01() {
+        let main = "pub fn entry a:bit32 -> bit32 {\n    return helper(a);\n}\n";
+        let private = "fn helper a:bit32 -> bit32 {\n    return a;\n}\npub fn other a:bit32 -> bit32 {\n    return helper(a);\n}\n";
+        let errs = link_units(&[(main, CrateType::Binary), (private, CrateType::Library)]).err();
+        assert_eq!(
+            errs,
+            Some(vec![LinkErr::PrivateSymbol { name: "helper".to_string(), unit: 0, defined_in: 1 }])
+        );
+
+        // 非公開の関数は他の単位の同じ名前の関数と衝突しない
+        let public = "pub fn helper a:bit32 -> bit32 {\n    return a + 1;\n}\n";
+        let linked = link_units(&[(main, CrateType::Binary), (public, CrateType::Library), (private, CrateType::Library)]);
+        assert!(linked.is_ok());
+
+        let other = "pub fn helper a:bit32 -> bit32 {\n    return a + 2;\n}\n";
+        let errs = link_units(&[(main, CrateType::Binary), (public, CrateType::Library), (other, CrateType::Library)]).err();
+        assert_eq!(
+            errs,
+            Some(vec![LinkErr::DuplicateSymbol { name: "helper".to_string(), first: 1, second: 2 }])
+        );
+
+        let errs = link_units(&[(main, CrateType::Binary), (main, CrateType::Binary)]).err().unwrap();
+        assert!(errs.contains(&LinkErr::MultipleEntries { name: "entry".to_string(), units: vec![0, 1] }));
+        assert!(errs.contains(&LinkErr::UndefinedSymbol { name: "helper".to_string(), unit: 0 }));
+
+        let errs = link_units(&[(public, CrateType::Library)]).err();
+        assert_eq!(errs, Some(vec![LinkErr::MissingEntry("entry".to_string())]));
+    }
+
+    #[test]
+    fn test_link02() {
+        // 単位ごとの非公開の関数は名前を変えてリンクされ、それぞれの単位から呼び出される
+        let main = r#"
+pub fn entry a:bit32 -> bit32, bit32, bit32 {
+    return helper(a), inc(a), dbl(a);
+}
+
+// 展開されないように命令を増やす
+fn helper a:bit32 -> bit32 {
+    let b = a + 0;
+    let c = b + 0;
+    return c;
+}
+"#;
+        let inc = "pub fn inc a:bit32 -> bit32 {\n    return helper(a);\n}\n\nfn helper a:bit32 -> bit32 {\n    let b = a + 1;\n    let c = b + 0;\n    return c;\n}\n";
+        let dbl = "pub fn dbl a:bit32 -> bit32 {\n    return helper(a);\n}\n\nfn helper a:bit32 -> bit32 {\n    let b = a * 2;\n    let c = b + 0;\n    return c;\n}\n";
+        let linked = link_units(&[(main, CrateType::Binary), (inc, CrateType::Library), (dbl, CrateType::Library)]).unwrap();
+        let ir = linked.dump_ir();
+        for name in ["helper__u0", "helper__u1", "helper__u2"] {
+            assert!(ir.contains(&format!("fn {} ", name)), "{}", ir);
+        }
+        let script = linked.generate().unwrap();
+        let output = Command::new("sed")
+            .arg(&script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(format!("~{:032b}\n", 5).as_bytes())?;
+                child.wait_with_output()
+            })
+            .expect("sedの実行に失敗しました");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("~{:032b}~{:032b}~{:032b};\n", 5, 6, 10));
+    }
+
+    #[test]
+    fn test_inline00() {
+        // 同じラベルを定義する生のsedを二回展開しても衝突しない
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bit32 {
+    let x = pick(a, b);
+    return shl(shl(x)), pick(b, a);
+}
+
+#[inline]
+fn pick a:bit32, b:bit32 -> bit32 {
+    let t = a + b;
+    if a == 0 {
+        return b;
+    } else {
+        return t;
+    }
+}
+
+fn shl a:bit32 -> bit32 {
+    sed ${
+        "b start",
+        ":start",
+        "s/\\(~[01]*\\)/\\10;/",
+    }$
+}
+"#;
+        let generated = compiler_frontend(code).unwrap().assemble().generate().unwrap();
+        assert!(!generated.contains("# function call: shl"));
+        assert!(!generated.contains("# function call: pick"));
+        let cases = [((0, 5), (20, 5)), ((3, 5), (32, 8))];
+        for ((a, b), (x, y)) in cases {
+            let input_args = format!("~{:032b}~{:032b}\n", a, b);
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            assert_eq!(result, Ok(format!("~{:034b}~{:032b};\n", x, y)));
+        }
+    }
+
+    #[test]
+    fn test_const_fold00() {
+        // 定数の引数による呼び出しは評価され、実行時には呼び出されない
+        let code = r#"
+pub fn entry a:u32 -> u32, u32 {
+    return add(3, 4) + fib(10), fib(a);
+}
+
+const fn fib n:u32 -> u32 {
+    if n < 2 {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+"#;
+        let generated = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .tail_calls(false)
+            .assemble()
+            .generate()
+            .unwrap();
+        assert!(generated.contains(&format!("~{:032b}", 62)));
+        assert_eq!(generated.matches("# function call: fib").count(), 3);
+        for (a, fib) in [(0, 0), (6, 8)] {
+            let input_args = format!("~{:032b}\n", a);
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            assert_eq!(result, Ok(format!("~{:032b}~{:032b};\n", 62, fib)));
+        }
+    }
+
+    #[test]
+    fn test_peephole00() {
+        // ピープホール最適化の前後で結果は変わらない
+        let code = std::fs::read_to_string("soil/basic_operations.soil").unwrap();
+        let generate = |peephole: bool| {
+            compiler_frontend(&code)
+                .unwrap()
+                .peephole(peephole)
+                .assemble()
+                .generate()
+                .unwrap()
+        };
+        let optimized = generate(true);
+        let plain = generate(false);
+        assert!(optimized.lines().count() < plain.lines().count());
+        assert!(!optimized.contains("b then"));
+        let input_args = format!("~{:032b}~{:032b}\n", 1234567, 89);
+        let run = |script: &str| {
+            let mut child = Command::new("sed")
+                .arg("-e")
+                .arg(script)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input_args.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap().stdout
+        };
+        assert_eq!(run(&optimized), run(&plain));
+    }
+
+    #[test]
+    fn test_tail_call00() {
+        // 相互再帰の末尾呼び出しはホールドスペースにフレームを積まずに移る
+        let code = r#"
+pub fn entry a:bit32 -> bit32, bit32 {
+    let e = is_even(a);
+    return e, gcd(a, 6);
+}
+
+fn is_even a:bit32 -> bit32 {
+    if a == 0 {
+        return 1;
+    }
+    return is_odd(a - 1);
+}
+
+fn is_odd a:bit32 -> bit32 {
+    if a == 0 {
+        return 0;
+    }
+    return is_even(a - 1);
+}
+
+fn gcd a:bit32, b:bit32 -> bit32 {
+    if b == 0 {
+        return a;
+    }
+    return gcd(b, a % b);
+}
+"#;
+        let generated = compiler_frontend(code).unwrap().assemble().generate().unwrap();
+        assert!(generated.contains("# tail call: is_odd"));
+        assert!(!generated.contains("# function call: is_odd"));
+        // gcdの再帰は末尾呼び出しになり、entryからの呼び出しだけが残る
+        assert_eq!(generated.matches("# function call: gcd").count(), 1);
+        let cases = [(0, 1, 6), (7, 0, 1), (12, 1, 6), (301, 0, 1)];
+        for (a, even, gcd) in cases {
+            let input_args = format!("~{:032b}\n", a);
+            let result = sed_operation_test(code, &input_args).map_err(|e| e.error_msg);
+            assert_eq!(result, Ok(format!("~{:032b}~{:032b};\n", even, gcd)), "a = {}", a);
+        }
+    }
+
+    #[test]
+    fn test_return_jump00() {
+        // 多くの場所から呼ばれる関数も、引数の個数が違う関数への末尾呼び出しも呼び出し元に戻る
+        let code = r#"
+pub fn entry a:bit32 -> bit32, bit32 {
+    let s = inc(a);
+    s = inc(inc(inc(s)));
+    s = inc(inc(inc(s)));
+    s = inc(inc(inc(s)));
+    s = inc(inc(s));
+    return s, twice(a, 1);
+}
+
+fn inc a:bit32 -> bit32 {
+    return a + 1;
+}
+
+fn twice a:bit32, b:bit32 -> bit32 {
+    return add3(a, a, b);
+}
+
+fn add3 a:bit32, b:bit32, c:bit32 -> bit32 {
+    return a + b + c;
+}
+"#;
+        let generated = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .const_fold(false)
+            .assemble()
+            .generate()
+            .unwrap();
+        assert_eq!(generated.matches("# function call: inc").count(), 12);
+        assert!(generated.contains("# tail call: add3"));
+        for (a, s, t) in [(0, 12, 1), (100, 112, 201)] {
+            let mut child = Command::new("sed")
+                .arg("-e")
+                .arg(&generated)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(format!("~{:032b}\n", a).as_bytes())
+                .unwrap();
+            let output = String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap();
+            assert_eq!(output, format!("~{:032b}~{:032b};\n", s, t), "a = {}", a);
+        }
+    }
+
+    #[test]
+    fn test_return_jump01() {
+        // 戻りアドレスがどの呼び出し元とも一致しない場合は、次の関数に進まずに終了する
+        // corruptはholdスペースの自分の戻りアドレスの番号の末尾に9を付ける
+        let code = r#"
+pub fn entry a:bit32 -> bit32 {
+    let s = corrupt(a);
+    s = corrupt(corrupt(corrupt(s)));
+    s = corrupt(corrupt(corrupt(s)));
+    s = corrupt(corrupt(corrupt(s)));
+    s = corrupt(corrupt(s));
+    return s;
+}
+
+fn corrupt a:bit32 -> bit32 {
+    sed ${
+        "x",
+        "s/:retlabel\\([0-9]*\\)\\([^\\n]*\\)$/:retlabel\\19\\2/",
+        "x",
+        "s/$/;/",
+    }$
+}
+"#;
+        let generated = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .assemble()
+            .generate()
+            .unwrap();
+        assert_eq!(generated.matches("# function call: corrupt").count(), 12);
+        let mut child = Command::new("sed")
+            .arg("-e")
+            .arg(&generated)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"~0\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "invalid return address\n");
+    }
+
+    #[test]
+    fn test_sed_label00() {
+        // 別の関数の生のsedで同じラベルを定義しても衝突しない
+        let code = r#"
+pub fn entry a:bit32 -> bit32, bit32 {
+    return shl(a), shl2(a);
+}
+
+fn shl a:bit32 -> bit32 {
+    sed ${
+        "b start",
+        ":start",
+        "s/\\(~[01]*\\)/\\10;/",
+    }$
+}
+
+fn shl2 a:bit32 -> bit32 {
+    sed ${
+        "b start",
+        ":start",
+        "s/\\(~[01]*\\)/\\100;/",
+    }$
+}
+"#;
+        let generated = compiler_frontend(code)
+            .unwrap()
+            .inline(false)
+            .peephole(false)
+            .assemble()
+            .generate()
+            .unwrap();
+        assert!(!generated.contains(":start\n"));
+        assert_eq!(generated.matches(":start__f").count(), 2);
+        let mut child = Command::new("sed")
+            .arg("-e")
+            .arg(&generated)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(format!("~{:032b}\n", 3).as_bytes()).unwrap();
+        let output = String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap();
+        assert_eq!(output, format!("~{:033b}~{:034b};\n", 6, 12));
+
+        // 同じ関数の中でのラベルの重複と、生成したラベルへの分岐はエラーになる
+        let code = r#"
+pub fn entry a:bit32 -> bit32 {
+    sed ${
+        ":loop",
+        "s/~0\\([01]\\)/~\\1/",
+        "t loop",
+        ":loop",
+        "b done",
+        "s/$/;/",
+    }$
+}
+"#;
+        let err = compiler_frontend(code).unwrap().assemble().generate().unwrap_err();
+        let messages: Vec<String> = err.to_diagnostics().iter().map(|d| d.message.clone()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "in function `entry` at instruction [3]: duplicate label `loop`",
+                "in function `entry` at instruction [4]: branch to undefined label `done`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sed_label01() {
+        // `;`で区切った後ろのコマンドの分岐先も名前を変える
+        // 展開する場合も呼び出しごとに名前を変える
+        let code = r#"
+pub fn entry a:bit32, b:bit32 -> bit32, bit32 {
+    return strip(a), strip(b);
+}
+
+fn strip a:bit32 -> bit32 {
+    sed ${
+        ":loop",
+        "s/^~0\\([01]\\)/~\\1/;t loop",
+        "s/$/;/",
+    }$
+}
+"#;
+        for inline in [false, true] {
+            let generated = compiler_frontend(code)
+                .unwrap()
+                .inline(inline)
+                .peephole(false)
+                .assemble()
+                .generate()
+                .unwrap();
+            assert!(!generated.contains("t loop\n"), "{}", generated);
+            let mut child = Command::new("sed")
+                .arg("-e")
+                .arg(&generated)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(format!("~{:032b}~{:032b}\n", 5, 0).as_bytes()).unwrap();
+            let output = String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap();
+            assert_eq!(output, "~101~0;\n", "inline: {}", inline);
+        }
+    }
 }
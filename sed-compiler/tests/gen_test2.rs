@@ -1,41 +1,8 @@
 use sed_compiler::{
     code_gen::*,
-    embedded::{
-        em_add, em_ends_with_zero, em_mul, em_sub32, em_twos_complement, em_zero_padding32,
-    },
+    embedded::{em_add, em_sub32, em_twos_complement, em_zero_padding32},
 };
 
-#[cfg(test)]
-mod gen_test2 {
-    use super::*;
-    use std::fs::File;
-    use std::io::Write;
-
-    #[test]
-    fn gen_test00() {
-        let mut file = File::create("./sed/mul.sed").expect("ファイルが開けませんでした");
-        let a = gen_test_proc00();
-        file.write_all(a.as_bytes())
-            .expect("書き込みに失敗しました");
-    }
-
-    #[test]
-    fn gen_test01() {
-        let mut file = File::create("./sed/sub.sed").expect("ファイルが開けませんでした");
-        let a = gen_test_proc01();
-        file.write_all(a.as_bytes())
-            .expect("書き込みに失敗しました");
-    }
-
-    #[test]
-    fn gen_test02() {
-        let mut file = File::create("./sed/sub.sed").expect("ファイルが開けませんでした");
-        let a = gen_test_proc02();
-        file.write_all(a.as_bytes())
-            .expect("書き込みに失敗しました");
-    }
-}
-
 fn gen_test_proc00() -> String {
     use sed_compiler::embedded::*;
     // それぞれの関数のローカル変数の個数は後で適当なものに置き換える
@@ -130,7 +97,7 @@ fn gen_test_proc01() -> String {
 }
 
 fn gen_test_proc02() -> String {
-    let mut entry = FuncDef::new("entry", 0, 2, 1);
+    let entry = FuncDef::new("entry", 0, 2, 1);
 
     let compile_result = CompilerBuilder::new().add_func(entry).assemble().generate();
 
@@ -142,3 +109,34 @@ fn gen_test_proc02() -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod gen_test2 {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn gen_test00() {
+        let mut file = File::create("./sed/mul.sed").expect("ファイルが開けませんでした");
+        let a = gen_test_proc00();
+        file.write_all(a.as_bytes())
+            .expect("書き込みに失敗しました");
+    }
+
+    #[test]
+    fn gen_test01() {
+        let mut file = File::create("./sed/sub.sed").expect("ファイルが開けませんでした");
+        let a = gen_test_proc01();
+        file.write_all(a.as_bytes())
+            .expect("書き込みに失敗しました");
+    }
+
+    #[test]
+    fn gen_test02() {
+        let mut file = File::create("./sed/sub.sed").expect("ファイルが開けませんでした");
+        let a = gen_test_proc02();
+        file.write_all(a.as_bytes())
+            .expect("書き込みに失敗しました");
+    }
+}
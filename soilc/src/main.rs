@@ -1,39 +1,286 @@
-use sed_compiler::compiler::compiler_frontend;
+use sed_compiler::code_gen::{CompilerBuilder, CrateType, IoFormat, NumberRepr, link_compile_builder};
+use sed_compiler::compiler::{BuildIRErr, FrontendOptions, SourceFile, compiler_frontend_files};
+use sed_compiler::diagnostic::{Diagnostic, ErrorFormat, Severity, SourceSpan};
+use sed_compiler::lint::LintLevel;
+use sed_compiler::object::ObjectFile;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 
 /// soilcはsedコンパイラを制御するためのUIです。
 /// soilプログラムをsedにトランスパイルすることができます
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)] // バージョン情報や説明文を自動設定
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// コンパイルしたいsoilファイル
-    #[arg(short, long)]
-    input: String,
+    /// 複数指定した場合はファイルごとに別のモジュールになる
+    /// 拡張子が`.ir`のファイルはテキスト形式のIRとして読み込む
+    #[arg(short, long, num_args = 1.., required = true)]
+    input: Vec<String>,
 
     /// 出力されるsedファイルの名前
     #[arg(short, long, default_value_t = String::from("out.sed"))]
     output: String,
 
+    /// プログラムの開始地点となる関数の名前
+    #[arg(long, default_value_t = String::from("entry"))]
+    entry: String,
+
     /// 詳細表示モードかどうか (フラグ)
     /// 値を取らず、存在するだけで true になる bool 型
     #[arg(short, long)]
     verbose: bool,
+
+    /// 警告として表示するlint (例: -W dead_code, すべての場合は -W warnings)
+    #[arg(short = 'W', value_name = "LINT")]
+    warn: Vec<String>,
+
+    /// エラーに昇格するlint (例: -D unused_variables, すべての場合は -D warnings)
+    #[arg(short = 'D', value_name = "LINT")]
+    deny: Vec<String>,
+
+    /// 診断の出力形式
+    /// jsonの場合は一つの診断ごとに一行のJSONを標準エラー出力に書き出す
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "human",
+        value_parser = ["human", "json"]
+    )]
+    error_format: String,
+
+    /// 生成するsedスクリプトの入出力の形式
+    /// decimalの場合は空白で区切った10進数を受け取り、返り値を10進数で表示する
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "binary",
+        value_parser = ["binary", "decimal"]
+    )]
+    io_format: String,
+
+    /// 生成するsedスクリプトでの数の表現
+    /// decimalの場合は10進数の文字列のまま計算する
+    #[arg(
+        long,
+        value_name = "REPR",
+        default_value = "binary",
+        value_parser = ["binary", "decimal"]
+    )]
+    number_repr: String,
+
+    /// 算術演算子で桁あふれや0による除算が起きた場合に実行を中断する
+    #[arg(long)]
+    checked: bool,
+
+    /// 出力する形式
+    /// irの場合は組み込み関数をリンクする前のIRをテキスト形式で書き出す
+    #[arg(
+        long,
+        value_name = "KIND",
+        default_value = "sed",
+        value_parser = ["sed", "ir"]
+    )]
+    emit: String,
+
+    /// ライブラリとしてコンパイルし、sedの代わりにオブジェクトファイルを出力する
+    /// pub関数が他のオブジェクトファイルから呼び出せるようになる
+    #[arg(short = 'c')]
+    compile_only: bool,
+
+    /// 最適化のレベル
+    /// 0は最適化しない、1はIRの最適化だけ、2は生成したsedのピープホール最適化もする
+    #[arg(
+        short = 'O',
+        value_name = "LEVEL",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    opt_level: u8,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// `-c`で作ったオブジェクトファイルをリンクしてsedスクリプトを生成する
+    Link {
+        /// リンクするオブジェクトファイル
+        /// 開始地点の関数の名前は最初のファイルのものが使われる
+        #[arg(required = true)]
+        objects: Vec<String>,
+
+        /// 出力されるsedファイルの名前
+        #[arg(short, long, default_value_t = String::from("out.sed"))]
+        output: String,
+
+        /// 診断の出力形式
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            default_value = "human",
+            value_parser = ["human", "json"]
+        )]
+        error_format: String,
+
+        /// 最適化のレベル 2の場合は生成したsedをピープホール最適化する
+        #[arg(
+            short = 'O',
+            value_name = "LEVEL",
+            default_value_t = 2,
+            value_parser = clap::value_parser!(u8).range(0..=2)
+        )]
+        opt_level: u8,
+    },
+}
+
+/// コンパイルの失敗を診断として標準エラー出力に報告して終了する
+/// 位置と行と列は`sources`から求める
+fn exit_with(
+    error_format: ErrorFormat,
+    sources: &[(&str, &str)],
+    diagnostics: Vec<Diagnostic>,
+) -> ! {
+    for diagnostic in diagnostics {
+        diagnostic.emit(sources, error_format);
+    }
+    std::process::exit(1);
+}
+
+fn error_format_of(name: &str) -> ErrorFormat {
+    match name {
+        "json" => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    }
+}
+
+/// オブジェクトファイルを読み込んでリンクする
+fn link(objects: &[String], output: &str, error_format: ErrorFormat, opt_level: u8) {
+    let mut units = Vec::new();
+    for path in objects {
+        let text = std::fs::read_to_string(path).expect("Failed to Open File"); // TODO: ファイルオープンの失敗を処理
+        match ObjectFile::parse(&text) {
+            Ok(object) => units.push(CompilerBuilder::from_object(object)),
+            Err(err) => {
+                let message = format!("{}: {}", path, err);
+                exit_with(
+                    error_format,
+                    &[],
+                    vec![Diagnostic::without_span(Severity::Error, "10", &message)],
+                )
+            }
+        }
+    }
+    match link_compile_builder(&units) {
+        Ok(linked) => match linked.peephole(opt_level >= 2).generate() {
+            Ok(generated_sed_code) => {
+                std::fs::write(output, generated_sed_code).expect("Failed to write file");
+            }
+            Err(err) => exit_with(error_format, &[], err.to_diagnostics()),
+        },
+        Err(errs) => exit_with(
+            error_format,
+            &[],
+            errs.iter().map(|err| err.to_diagnostic()).collect(),
+        ),
+    }
 }
 
 fn main() {
     // 引数を解析して構造体に変換
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
-    let code = 
-        std::fs::read_to_string(args.input)
-        .expect("Failed to Open File"); // TODO: ファイルオープンの失敗を処理
+    if let Some(Command::Link {
+        objects,
+        output,
+        error_format,
+        opt_level,
+    }) = &args.command
+    {
+        link(objects, output, error_format_of(error_format), *opt_level);
+        return;
+    }
+    let opt_level = args.opt_level;
+
+    let error_format = error_format_of(&args.error_format);
+
+    // -W と -D はコマンドラインで後に書かれたものを優先する
+    let mut options = FrontendOptions {
+        entry: args.entry.clone(),
+        error_format,
+        io_format: match args.io_format.as_str() {
+            "decimal" => IoFormat::Decimal,
+            _ => IoFormat::Binary,
+        },
+        number_repr: match args.number_repr.as_str() {
+            "decimal" => NumberRepr::Decimal,
+            _ => NumberRepr::Binary,
+        },
+        checked: args.checked,
+        crate_type: if args.compile_only {
+            CrateType::Library
+        } else {
+            CrateType::Binary
+        },
+        ..FrontendOptions::default()
+    };
+    let mut levels: Vec<(usize, &String, LintLevel)> = Vec::new();
+    for (id, names, level) in [
+        ("warn", &args.warn, LintLevel::Warn),
+        ("deny", &args.deny, LintLevel::Deny),
+    ] {
+        if let Some(indices) = matches.indices_of(id) {
+            levels.extend(indices.zip(names).map(|(index, name)| (index, name, level)));
+        }
+    }
+    levels.sort_by_key(|(index, _, _)| *index);
+    for (_, name, level) in levels {
+        if let Err(err) = options.lint.set_level(name, level) {
+            Diagnostic::without_span(Severity::Error, "1", &err.note).emit(&[], error_format);
+            std::process::exit(1);
+        }
+    }
+
+    let codes: Vec<String> = args
+        .input
+        .iter()
+        .map(|path| std::fs::read_to_string(path).expect("Failed to Open File")) // TODO: ファイルオープンの失敗を処理
+        .collect();
+    let (ir_files, files): (Vec<SourceFile>, Vec<SourceFile>) = args
+        .input
+        .iter()
+        .zip(&codes)
+        .map(|(name, code)| SourceFile { name, code })
+        .partition(|file| file.name.ends_with(".ir"));
+    let sources: Vec<(&str, &str)> = files
+        .iter()
+        .chain(&ir_files)
+        .map(|file| (file.name, file.code))
+        .collect();
 
     // ソースに基づいてIRを生成する
     // CompileBuilderの中に中間表現IRの情報を含む
-    let r_ir = compiler_frontend(&code);
+    let r_ir = compiler_frontend_files(&files, &options).and_then(|mut builder| {
+        for file in &ir_files {
+            builder = builder.add_ir(file.code).map_err(|err| BuildIRErr {
+                note: format!("{}: {}", file.name, err),
+                span: Some(SourceSpan::new(err.span(file.code)).in_file(file.name)),
+            })?;
+        }
+        // 最適化のレベルに合わせてassembleとsedの生成を設定する
+        Ok(builder
+            .const_fold(opt_level >= 1)
+            .inline(opt_level >= 1)
+            .tail_calls(opt_level >= 1)
+            .peephole(opt_level >= 2))
+    });
 
     match r_ir {
+        Ok(compiler_builder) if args.emit == "ir" => {
+            std::fs::write(args.output, compiler_builder.dump_ir()).expect("Failed to write file");
+        }
         Ok(compiler_builder) => {
             // リターンアドレス、ラベルの解決をする
             let assembled = compiler_builder.assemble();
@@ -41,6 +288,11 @@ fn main() {
             if args.verbose {
                 assembled.resolved_show_table();
             }
+            if args.compile_only {
+                std::fs::write(args.output, assembled.to_object().to_text())
+                    .expect("Failed to write file");
+                return;
+            }
             // 解決の終わったIRからsedスクリプトを生成する
             let generated = assembled.generate();
             match generated {
@@ -48,13 +300,9 @@ fn main() {
                     std::fs::write(args.output, generated_sed_code)
                         .expect("Failed to write file");
                 }
-                Err(err) => {
-                    println!("{:?}", err);
-                }
+                Err(err) => exit_with(error_format, &sources, err.to_diagnostics()),
             }
         }
-        Err(err) => {
-            println!("{:?}", err);
-        }
+        Err(err) => exit_with(error_format, &sources, vec![err.to_diagnostic()]),
     }
 }
@@ -0,0 +1,120 @@
+// soilcを実行して、診断の出力を確かめる
+#[cfg(test)]
+mod cli_test {
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Output};
+
+    /// テストごとの一時ディレクトリにファイルを書く
+    fn temp_dir(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("soilc_cli_test_{}_{}", test_name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file_name, code) in files {
+            std::fs::write(dir.join(file_name), code).unwrap();
+        }
+        dir
+    }
+
+    fn soilc(dir: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_soilc"))
+            .current_dir(dir)
+            .args(args)
+            .output()
+            .expect("soilcの実行に失敗しました")
+    }
+
+    /// 一時ディレクトリにファイルを書いて、そこでsoilcを実行したstderrを返す
+    fn run_json(test_name: &str, file_name: &str, code: &str) -> String {
+        let dir = temp_dir(test_name, &[(file_name, code)]);
+        let output = soilc(&dir, &["-i", file_name, "-o", "out.sed", "--error-format=json"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    /// codeのエラーの行
+    fn error_line<'a>(stderr: &'a str, code: &str) -> &'a str {
+        let field = format!(r#""severity":"error","code":"{}""#, code);
+        stderr
+            .lines()
+            .find(|line| line.contains(&field))
+            .unwrap_or_else(|| panic!("no error with code {} in {}", code, stderr))
+    }
+
+    #[test]
+    fn cli_test00() {
+        // 字句解析のエラー
+        let code = "pub fn entry a:bit32 -> bit32 {\n    return a @ 1;\n}\n";
+        let stderr = run_json("00", "lex.soil", code);
+        let line = error_line(&stderr, "2");
+        assert!(line.starts_with(r#"{"file":"lex.soil","span":{"start":45,"end":46,"line_start":2,"column_start":14,"line_end":2,"column_end":15}"#), "{}", line);
+        assert!(line.contains(r#""message":"unexpected `@`""#), "{}", line);
+    }
+
+    #[test]
+    fn cli_test01() {
+        // 構文解析のエラー
+        let code = "pub fn entry a:bit32 -> bit32 {\n    return a +;\n}\n";
+        let stderr = run_json("01", "parse.soil", code);
+        let line = error_line(&stderr, "3");
+        assert!(line.starts_with(r#"{"file":"parse.soil","span":{"start":46,"end":47,"line_start":2,"column_start":15,"line_end":2,"column_end":16}"#), "{}", line);
+        assert!(line.contains(r#""message":"unexpected `;`""#), "{}", line);
+    }
+
+    #[test]
+    fn cli_test02() {
+        // IRを作るときのエラーは式の位置
+        let code = "pub fn entry a:bit32 -> bit32 {\n    return x;\n}\n";
+        let stderr = run_json("02", "build.soil", code);
+        let line = error_line(&stderr, "1");
+        assert!(line.starts_with(r#"{"file":"build.soil","span":{"start":43,"end":44,"line_start":2,"column_start":12,"line_end":2,"column_end":13}"#), "{}", line);
+        assert!(line.contains(r#""message":"context error""#), "{}", line);
+    }
+
+    #[test]
+    fn cli_test03() {
+        // 生成するときのエラーは関数の位置
+        let code = "pub fn entry a:bit32 -> bit32 {\n    return g(a);\n}\n";
+        let stderr = run_json("03", "verify.soil", code);
+        let line = error_line(&stderr, "7");
+        assert!(line.starts_with(r#"{"file":"verify.soil","span":{"start":0,"end":50,"line_start":1,"column_start":1,"line_end":3,"column_end":2}"#), "{}", line);
+        assert!(line.contains("undefined function `g`"), "{}", line);
+    }
+
+    #[test]
+    fn cli_test04() {
+        // リンクのエラーはhumanでも診断として表示する
+        let main = "pub fn entry a:bit32 -> bit32 {\n    return helper(a);\n}\n";
+        let lib1 = "pub fn helper a:bit32 -> bit32 {\n    return a;\n}\n";
+        let lib2 = "pub fn helper a:bit32 -> bit32 {\n    return a + 1;\n}\n";
+        let dir = temp_dir("04", &[("main.soil", main), ("lib1.soil", lib1), ("lib2.soil", lib2)]);
+        for name in ["main", "lib1", "lib2"] {
+            let output = soilc(&dir, &["-i", &format!("{}.soil", name), "-o", &format!("{}.o", name), "-c"]);
+            assert_eq!(output.status.code(), Some(0));
+        }
+        let output = soilc(&dir, &["link", "main.o", "lib1.o", "lib2.o", "-o", "out.sed"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "error[10]: function `helper` is defined differently in units 1 and 2\n"
+        );
+    }
+
+    #[test]
+    fn cli_test05() {
+        // 範囲外の最適化のレベルは引数のエラーになる
+        let main = "pub fn entry a:bit32 -> bit32 {\n    return a;\n}\n";
+        let dir = temp_dir("05", &[("main.soil", main)]);
+        let compile = soilc(&dir, &["-i", "main.soil", "-o", "out.sed", "-O", "3"]);
+        let link = soilc(&dir, &["link", "main.o", "-O", "3"]);
+        let ok = soilc(&dir, &["-i", "main.soil", "-o", "out.sed", "-O", "1"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        for output in [compile, link] {
+            assert_eq!(output.status.code(), Some(2));
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.contains("'3'"), "{}", stderr);
+        }
+        assert_eq!(ok.status.code(), Some(0));
+    }
+}